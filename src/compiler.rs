@@ -1,400 +1,431 @@
-use std::{
-    collections::{HashMap, HashSet},
-    fs::File,
-};
+use std::fs::File;
 
 use crate::{
-    parser::{Expr, LetExpr, Node},
+    frame::Frame,
+    ir::{Function, Inst, Lit, Module, Terminator, VReg},
+    parser::Node,
+    passes::PassManager,
+    regalloc::Loc,
     writer::Writer,
 };
 
-use lazy_static::lazy_static;
-
-#[derive(Eq, Hash, PartialEq, Debug, Clone, Copy)]
-enum Reg {
-    RAX,
+/// The registers the allocator hands out. RAX, R10 and R11 are kept free for the code of each
+/// instruction to use.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Reg {
     RBX,
     RCX,
     RDX,
-    // RBP,
     RSI,
     RDI,
     R8,
     R9,
-    R10,
-    R11,
     R12,
     R13,
     R14,
     R15,
 }
 
+impl Reg {
+    /// The number of the register in the encoding of instructions, which is how root maps
+    /// refer to it
+    pub fn number(self) -> i64 {
+        match self {
+            Reg::RCX => 1,
+            Reg::RDX => 2,
+            Reg::RBX => 3,
+            Reg::RSI => 6,
+            Reg::RDI => 7,
+            Reg::R8 => 8,
+            Reg::R9 => 9,
+            Reg::R12 => 12,
+            Reg::R13 => 13,
+            Reg::R14 => 14,
+            Reg::R15 => 15,
+        }
+    }
+}
+
 const PARAM_REGS: [Reg; 6] = [Reg::RDI, Reg::RSI, Reg::RDX, Reg::RCX, Reg::R8, Reg::R9];
 
-lazy_static! {
-    static ref USABLE_REGS: HashSet<Reg> = {
-        let mut s = HashSet::new();
-        // s.insert(Reg::RBX);
-        s.insert(Reg::RCX);
-        s.insert(Reg::RDX);
-        s.insert(Reg::R8);
-        s.insert(Reg::R9);
-        s.insert(Reg::R10);
-        s.insert(Reg::R11);
-        s.insert(Reg::R12);
-        s.insert(Reg::R13);
-        s.insert(Reg::R14);
-        s.insert(Reg::R15);
-        s
-    };
-    static ref CALLER_SAVED_REGS: HashSet<Reg> = {
-        let mut s = HashSet::new();
-        s.insert(Reg::RAX);
-        s.insert(Reg::RCX);
-        s.insert(Reg::RDX);
-        s.insert(Reg::RSI);
-        s.insert(Reg::RDI);
-        s.insert(Reg::R8);
-        s.insert(Reg::R9);
-        s.insert(Reg::R10);
-        s.insert(Reg::R11);
-        s
-    };
+/// Data the compiled code refers to by label
+#[derive(Debug, Clone, PartialEq)]
+pub enum Const {
+    Float(f64),
+    /// The name of a quoted symbol, stored as a null-terminated string
+    Symbol(String),
+    /// The fields of a string literal's box: its length, then its text, null-terminated and
+    /// padded with zeros to a whole number of words
+    Str(String),
+    /// The words of a function's frame that hold values at a call or allocation. See
+    /// `Frame::roots`.
+    Roots(Vec<i64>),
+    /// A jump target; reserves the name but emits no data
+    Label,
+}
+
+impl Const {
+    /// Returns the line that declares this constant in the data section, if any
+    pub fn to_asm(&self, name: &str) -> Option<String> {
+        match self {
+            Const::Float(val) => Some(format!("{name}: dq {val:?}")),
+            Const::Symbol(s) => {
+                let bytes: Vec<_> = s.bytes().chain([0]).map(|b| b.to_string()).collect();
+                Some(format!("{name}: db {}", bytes.join(", ")))
+            }
+            Const::Str(s) => {
+                let mut bytes: Vec<_> = s.bytes().chain([0]).map(|b| b.to_string()).collect();
+                bytes.resize(bytes.len().next_multiple_of(8), "0".into());
+                Some(format!("{name}: dq {}\ndb {}", s.len(), bytes.join(", ")))
+            }
+            Const::Roots(roots) => {
+                let words: Vec<_> = [roots.len() as i64]
+                    .iter()
+                    .chain(roots)
+                    .map(i64::to_string)
+                    .collect();
+                Some(format!("{name}: dq {}", words.join(", ")))
+            }
+            Const::Label => None,
+        }
+    }
 }
 
-fn assert_params(e: &Expr, n: usize) {
-    assert_eq!(e.params.len(), n);
+/// Settings for how a program is compiled
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// How much to optimize, from 0 to 2. See `PassManager` for what each level does.
+    pub opt_level: u8,
+    /// Largest number of instructions a function can have and still be inlined
+    pub inline_threshold: usize,
+    /// The name of a pass to print the IR after, for debugging
+    pub print_after: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            opt_level: 2,
+            inline_threshold: 20,
+            print_after: None,
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct Compiler {
     pub lines: Vec<String>,
-    preserve: HashSet<Reg>,
-    pub bindings: HashMap<String, Reg>,
-    pub consts: Vec<(String, f64)>,
+    pub consts: Vec<(String, Const)>,
     pub fns: Vec<String>,
-
-    /// Number of pushes to stack. If even, pointer will not be aligned after making a call and a
-    /// push must be made
-    rsp_parity: usize,
+    pub options: Options,
 }
 
 impl Compiler {
-    pub fn with_consts(consts: Vec<(String, f64)>) -> Self {
+    pub fn with_consts(consts: Vec<(String, Const)>) -> Self {
         Self {
-            lines: Vec::new(),
-            preserve: HashSet::new(),
-            bindings: HashMap::new(),
             consts,
-            fns: Vec::new(),
-            rsp_parity: 0,
+            ..Default::default()
         }
     }
 
-    pub fn compile(mut self, t: &Node) -> (Vec<(String, f64)>, Vec<String>) {
-        self.compile_tok(&t, Some(Reg::RAX));
-        assert_eq!(self.preserve.len(), 0);
-        // assert_eq!(self.bindings.len(), 0);
-        assert_eq!(self.rsp_parity, 0);
-        (self.consts, self.lines)
+    /// Compiles a program. The code runs the program and returns its value, and is followed by
+    /// the code of its lambdas.
+    ///
+    /// The output only depends on the program and the constants the compiler started with, so
+    /// compiling the same program twice gives the same assembly.
+    pub fn compile(mut self, t: &Node) -> (Vec<(String, Const)>, Vec<String>) {
+        self.compile_module(&self.optimize(t));
+        let mut lines = self.lines;
+        lines.extend(self.fns);
+        (self.consts, lines)
     }
 
     pub fn compile_to_file(&mut self, t: Node, file: &mut File) {
-        self.compile_tok(&t, Some(Reg::RAX));
-        assert_eq!(self.preserve.len(), 0);
-        assert_eq!(self.bindings.len(), 0);
-        assert_eq!(self.rsp_parity, 0);
-
+        self.compile_module(&self.optimize(&t));
         self.to_file(file);
     }
 
+    /// Lowers a program to IR and optimizes it
+    pub fn optimize(&self, t: &Node) -> Module {
+        let mut module = Module::new(t);
+        PassManager::new(&self.options).run(&mut module);
+        module
+    }
+
     fn l(&mut self, line: impl ToString) {
         self.lines.push(line.to_string());
     }
 
-    fn compile_tok(&mut self, t: &Node, target: Option<Reg>) -> Reg {
-        let out = match t {
-            Node::Expr(e) => match &e.op[..] {
-                // Arithmetic operations
-                "+" => {
-                    // TODO: can add an arbitrary amount of numbers
-                    assert_params(e, 2);
-                    self.arith("madd", &e.params[0], &e.params[1])
-                }
-                "-" => {
-                    assert_params(e, 2);
-                    self.arith("msub", &e.params[0], &e.params[1])
-                }
-                "*" => {
-                    assert_params(e, 2);
-                    self.arith("mmul", &e.params[0], &e.params[1])
-                }
-                "/" => {
-                    assert_params(e, 2);
-                    self.arith("mdiv", &e.params[0], &e.params[1])
-                }
-                "mod" => {
-                    assert_params(e, 2);
-                    self.arith("mmod", &e.params[0], &e.params[1])
-                }
-                "=" => {
-                    assert_params(e, 2);
-                    self.arith("eq", &e.params[0], &e.params[1])
-                }
+    fn compile_module(&mut self, module: &Module) {
+        let labels: Vec<_> = module.functions.iter().map(|_| self.label()).collect();
+        for (i, func) in module.functions.iter().enumerate() {
+            let outer = std::mem::take(&mut self.lines);
+            let f = if i == 0 {
+                Frame::entry(func)
+            } else {
+                Frame::new(func)
+            };
+            self.compile_function(func, &f, &labels[i], &labels);
+            let lines = std::mem::replace(&mut self.lines, outer);
+            if i == 0 {
+                self.lines.extend(lines);
+            } else {
+                self.fns.extend(lines);
+            }
+        }
+    }
 
-                // List operations
-                "empty" => {
-                    assert_params(e, 0);
-                    self.call_function("empty")
-                }
-                f @ ("first" | "rest") => {
-                    assert_params(e, 1);
-                    self.call_one_param(f, &e.params[0])
-                }
-                "empty?" => {
-                    assert_params(e, 1);
-                    self.call_one_param("isempty", &e.params[0])
-                }
-                f @ ("cons" | "append") => {
-                    assert_params(e, 2);
-                    self.call_two_param(f, &e.params[0], &e.params[1])
-                }
-                "list" => self.call_on_stack("list", &e.params[..]),
-
-                // Internals
-                f @ ("_getint" | "_getfloat") => self.call_one_param(&f[1..], &e.params[0]),
-
-                // Conditionals
-                "if" => self.compile_if(&e.params[0], &e.params[1], &e.params[2], target),
-
-                op => {
-                    if let Some(ip) = self.bindings.get(op) {
-                        self.l(format!("mov rdi, {ip:?}"));
-                        let out = self.call_function("getip");
-                        let old_preserve = self.preserve.clone();
-                        self.preserve.insert(out);
-                        for (param, reg) in e.params.iter().zip(PARAM_REGS) {
-                            self.compile_tok(param, Some(reg));
-                            self.preserve.insert(reg);
-                        }
-                        let out = self.call_function(&format!("{out:?}")[..]);
-                        self.preserve = old_preserve;
-                        out
-                    } else {
-                        unimplemented!("{op}")
+    fn compile_function(&mut self, func: &Function, f: &Frame, label: &str, functions: &[String]) {
+        assert!(func.params.len() <= PARAM_REGS.len(), "too many parameters");
+        self.lines.extend(f.prologue(label));
+        let params: Vec<_> = func
+            .params
+            .iter()
+            .zip(PARAM_REGS)
+            .filter_map(|(p, reg)| Some((f.operand(*p)?, format!("{reg:?}"))))
+            .collect();
+        self.parallel_move(&params);
+
+        let blocks: Vec<_> = func.blocks.iter().map(|_| self.label()).collect();
+        for (i, block) in func.blocks.iter().enumerate() {
+            self.l(format!("{}:", blocks[i]));
+            for (j, inst) in block.insts.iter().enumerate() {
+                self.compile_inst(inst, f, f.roots(i, j), functions);
+            }
+            match &block.term {
+                Terminator::Jump(b) if b.0 == i + 1 => {}
+                Terminator::Jump(b) => self.l(format!("jmp {}", blocks[b.0])),
+                Terminator::Branch(cond, t, e) => {
+                    // Everything but #f counts as true
+                    self.l(format!("cmp {}, 0", f.get(*cond)));
+                    self.l(format!("jne {}", blocks[t.0]));
+                    if e.0 != i + 1 {
+                        self.l(format!("jmp {}", blocks[e.0]));
                     }
                 }
-            },
-            Node::LetExpr(e) => self.compile_let_expr(&e.bindings, &e.body),
-            Node::LambdaExpr(e) => self.compile_lambda_expr(&e.params, &e.body),
-            Node::String(_) | Node::Float(_) | Node::Integer(_) => self.compile_constant(t, target),
-        };
-        if let Some(target) = target {
-            if out != target {
-                self.l(format!("mov {target:?}, {out:?}"));
+                Terminator::Return(v) => {
+                    self.l(format!("mov rax, {}", f.get(*v)));
+                    self.lines.extend(f.epilogue());
+                }
             }
-            target
-        } else {
-            out
         }
     }
 
-    fn compile_constant(&mut self, c: &Node, target: Option<Reg>) -> Reg {
-        match c {
-            Node::String(s) => {
-                let val = match &s[..] {
-                    "#t" => 1,
-                    "#f" => 0,
-                    _ => {
-                        let reg = *self.bindings.get(s).unwrap();
-                        if let Some(target) = target {
-                            self.l(format!("mov {target:?}, {reg:?}"));
+    /// Compiles an instruction, given the root map entries for the values it has to keep
+    fn compile_inst(&mut self, inst: &Inst, f: &Frame, roots: Vec<i64>, functions: &[String]) {
+        match inst {
+            Inst::Lit(d, lit) => {
+                match lit {
+                    Lit::Int(i) => self.l(format!("mov rax, {}", fixnum(*i))),
+                    Lit::Float(x) => {
+                        let name = self.intern(Const::Float(*x));
+                        self.alloc(FLOAT, 1, 1, roots);
+                        self.l(format!("mov r11, [{name}]"));
+                        self.l("mov [rax+8], r11");
+                    }
+                    Lit::Symbol(s) => {
+                        let name = self.intern(Const::Symbol(s.clone()));
+                        self.alloc(SYMBOL, 1, 1, roots);
+                        self.l(format!("mov r11, {name}"));
+                        self.l("mov [rax+8], r11");
+                    }
+                    Lit::Str(s) => {
+                        // A string holds its text, so the literal's box is copied from the data
+                        // section a word at a time
+                        let words = 1 + (s.len() + 8) / 8;
+                        let name = self.intern(Const::Str(s.clone()));
+                        self.alloc(STRING, 255, words, roots);
+                        for i in 0..words {
+                            self.l(format!("mov r11, [{name}+{}]", 8 * i));
+                            self.l(format!("mov [rax+{}], r11", 8 * (i + 1)));
                         }
-                        return reg;
                     }
-                };
-                let out = target.unwrap_or_else(|| self.next_reg());
-                self.l(format!("mov {out:?}, {val}"));
-                out
+                    Lit::Bool(b) => self.l(format!("mov rax, {}", *b as u8)),
+                    Lit::Empty => self.l(format!("mov rax, {EMPTY}")),
+                    Lit::Void => self.l(format!("mov rax, {VOID}")),
+                }
+                self.store(f, *d);
             }
-            Node::Float(f) => {
-                let name = if let Some((name, _)) = self.consts.iter().find(|(_, val)| val == f) {
-                    name.clone()
-                } else {
-                    let name = self.next_label_name();
-                    self.consts.push((name.clone(), *f));
-                    name
-                };
-                self.l(format!("movss XMM0, [{name}]"));
-                self.call_function("newfloat")
+            Inst::Move(d, s) => {
+                if let Some(dst) = f.operand(*d) {
+                    self.parallel_move(&[(dst, f.get(*s))]);
+                }
+            }
+            Inst::Call(d, name, args) if name == "cons" => {
+                self.alloc(CONS, 0, 2, roots);
+                for (i, arg) in args.iter().enumerate() {
+                    self.set_field(f, i, *arg);
+                }
+                self.store(f, *d);
             }
-            Node::Integer(i) => {
-                self.l(format!("mov RDI, {i}"));
-                self.call_function("newint")
+            Inst::Call(d, name, args) => {
+                self.set_roots(roots);
+                self.load_params(f, args);
+                self.l(format!("call {name}"));
+                self.store(f, *d);
+            }
+            Inst::CallStack(d, name, args) => {
+                // The pushes mustn't leave the stack misaligned for the call. The arguments are
+                // values the callee may keep while it allocates, so the root map has them too.
+                let pad = args.len() % 2;
+                let pushed = (f.size() + 8 * (args.len() + pad)) as i64;
+                let args_roots = (0..args.len()).map(|i| 8 * i as i64 - pushed);
+                self.set_roots(roots.into_iter().chain(args_roots).collect());
+                if pad == 1 {
+                    self.l("push 0");
+                }
+                for arg in args.iter().rev() {
+                    self.l(format!("push {}", f.get(*arg)));
+                }
+                self.l(format!("mov rdi, {}", args.len()));
+                self.l(format!("call {name}"));
+                self.l(format!("add rsp, {}", 8 * (args.len() + pad)));
+                self.store(f, *d);
+            }
+            Inst::Closure(d, func, values) => {
+                // The first field is the address of the code, and the captured values follow
+                self.alloc(CLOSURE, 1, values.len() + 1, roots);
+                self.l(format!("mov r11, {}", functions[func.0]));
+                self.l("mov [rax+8], r11");
+                for (i, value) in values.iter().enumerate() {
+                    self.set_field(f, i + 1, *value);
+                }
+                self.store(f, *d);
+            }
+            Inst::Apply(d, func, args) => {
+                self.set_roots(roots);
+                self.l(format!("mov rax, {}", f.get(*func)));
+                self.l("call checkprocedure");
+                self.load_params(f, args);
+                self.l("mov r10, rax");
+                self.l("call qword [r10+8]");
+                self.store(f, *d);
+            }
+            Inst::Captured(d, i) => {
+                self.l(format!("mov rax, {}", f.env_slot()));
+                self.l(format!("mov rax, [rax+{}]", 8 * (i + 2)));
+                self.store(f, *d);
+            }
+            Inst::NewCell(d, v) => {
+                self.alloc(CELL, 0, 1, roots);
+                self.set_field(f, 0, *v);
+                self.store(f, *d);
+            }
+            Inst::CellGet(d, cell) => {
+                self.l(format!("mov rax, {}", f.get(*cell)));
+                self.l("mov rax, [rax+8]");
+                self.store(f, *d);
+            }
+            Inst::CellSet(cell, v) => {
+                self.l(format!("mov rax, {}", f.get(*cell)));
+                self.l(format!("mov r11, {}", f.get(*v)));
+                self.l("mov [rax+8], r11");
             }
-            _ => unreachable!(),
         }
     }
 
-    fn compile_let_expr(&mut self, bindings: &[(String, Node)], body: &Node) -> Reg {
-        for (name, val) in bindings {
-            let reg = self.next_reg();
-            self.compile_tok(val, Some(reg));
-            self.bindings.insert(name.to_string(), reg);
-            self.preserve.insert(reg);
-        }
-        let out = self.compile_tok(body, None);
-        for (name, _) in bindings {
-            let reg = self.bindings.remove(name).unwrap();
-            self.preserve.remove(&reg);
-        }
-        out
+    /// Allocates a box with `fields` fields after its header, the first `raw` of which aren't
+    /// values, and leaves its address in RAX
+    ///
+    /// This bumps the heap pointer inline, and only calls into the runtime to collect garbage
+    /// when the heap is full. That preserves every register, so it doesn't count as a call, but
+    /// the root map has to say which of them hold values.
+    fn alloc(&mut self, ty: u64, raw: usize, fields: usize, roots: Vec<i64>) {
+        let bytes = 8 * (fields + 1);
+        let fits = self.label();
+        self.l("mov rax, [heap_next]");
+        self.l(format!("add rax, {bytes}"));
+        self.l("cmp rax, [heap_end]");
+        self.l(format!("jbe {fits}"));
+        self.set_roots(roots);
+        self.l(format!("mov rax, {bytes}"));
+        self.l("call heapfull");
+        self.l(format!("{fits}:"));
+        self.l("mov [heap_next], rax");
+        self.l(format!("sub rax, {bytes}"));
+        let header = (fields as u64) << 16 | (raw as u64) << 8 | ty;
+        self.l(format!("mov qword [rax], {header:#x}"));
     }
 
-    fn compile_lambda_expr(&mut self, params: &[String], body: &Node) -> Reg {
-        let mut compiler = Compiler::with_consts(self.consts.clone());
-        // TODO: closure
-        for (name, reg) in params.iter().zip(PARAM_REGS) {
-            compiler.bindings.insert(name.to_string(), reg);
-        }
-        let (consts, mut lines) = compiler.compile(body);
-        self.consts = consts;
-        let offset = lines.len() + 1;
-        lines.push("ret".to_string());
-        lines.extend(self.fns.clone());
-        self.fns = lines;
-        if self.preserve.contains(&Reg::RDI) {
-            self.l("push RDI");
-            self.rsp_parity += 1;
-        }
-        self.l(format!("mov RDI, rbx"));
-        self.l(format!("sub RDI, {}", offset * 3));
-        let out = self.call_function("newip");
-        if self.preserve.contains(&Reg::RDI) {
-            self.l("pop RDI");
-            self.rsp_parity -= 1;
-        }
-        out
+    /// Points the frame's root map at `roots`
+    fn set_roots(&mut self, roots: Vec<i64>) {
+        let name = self.intern(Const::Roots(roots));
+        self.l(format!("mov qword [rbp-8], {name}"));
     }
 
-    fn arith(&mut self, op: &str, p1: &Node, p2: &Node) -> Reg {
-        let save_rax = self.preserve.contains(&Reg::RAX);
-        if save_rax {
-            self.l("push rax");
-            self.rsp_parity += 1;
-            self.preserve.remove(&Reg::RAX);
-        }
+    /// Stores `v` in field `i` of the box RAX points to
+    fn set_field(&mut self, f: &Frame, i: usize, v: VReg) {
+        let src = match f.loc(v) {
+            Some(Loc::Reg(reg)) => format!("{reg:?}"),
+            _ => {
+                self.l(format!("mov r11, {}", f.get(v)));
+                "r11".into()
+            }
+        };
+        self.l(format!("mov [rax+{}], {src}", 8 * (i + 1)));
+    }
 
-        let r1 = self.compile_tok(p1, None);
-        self.preserve.insert(r1);
-        let r2 = self.compile_tok(p2, None);
-        self.preserve.remove(&r1);
-        self.l(format!("mov rdi, {r1:?}"));
-        self.l(format!("mov rsi, {r2:?}"));
-        let mut out = self.call_function(op);
-
-        if save_rax {
-            out = self.next_reg();
-            self.l(format!("mov {out:?}, RAX"));
-            self.l("pop rax");
-            self.rsp_parity -= 1;
-            self.preserve.insert(Reg::RAX);
+    /// Stores RAX in the location of `v`, unless `v` is never used
+    fn store(&mut self, f: &Frame, v: VReg) {
+        if let Some(dst) = f.operand(v) {
+            self.l(format!("mov {dst}, rax"));
         }
-        out
     }
 
-    fn compile_if(&mut self, cond: &Node, p1: &Node, p2: &Node, target: Option<Reg>) -> Reg {
-        let cond_reg = self.compile_tok(cond, None);
-        let truelabel = self.next_label_name();
-        self.consts.push((truelabel.clone(), f64::NAN));
-        let falselabel = self.next_label_name();
-        self.consts.push((falselabel.clone(), f64::NAN));
-        let donelabel = self.next_label_name();
-        self.consts.push((donelabel.clone(), f64::NAN));
-        self.l(format!("cmp {cond_reg:?}, 1"));
-        self.l(format!("je {truelabel}"));
-        self.l(format!("jmp {falselabel}"));
-        self.l(format!("{truelabel}:"));
-        let out = self.compile_tok(p1, target);
-        self.l(format!("jmp {donelabel}"));
-        self.l(format!("{falselabel}:"));
-        let out2 = self.compile_tok(p2, target);
-        assert_eq!(out, out2);
-        self.l(format!("{donelabel}:"));
-        out
+    fn load_params(&mut self, f: &Frame, args: &[VReg]) {
+        assert!(args.len() <= PARAM_REGS.len(), "too many arguments");
+        let moves: Vec<_> = args
+            .iter()
+            .zip(PARAM_REGS)
+            .map(|(arg, reg)| (format!("{reg:?}"), f.get(*arg)))
+            .collect();
+        self.parallel_move(&moves);
     }
 
-    fn call_function(&mut self, name: &str) -> Reg {
-        let mut saved_regs = Vec::new();
-        for reg in self.preserve.intersection(&CALLER_SAVED_REGS) {
-            saved_regs.push(*reg);
-        }
-        for reg in &saved_regs {
-            self.l(format!("push {reg:?}"));
-            self.rsp_parity += 1;
-            self.preserve.remove(reg);
-        }
-        if self.rsp_parity % 2 == 0 {
-            self.l("sub rsp, 8");
-        }
-
-        self.l(format!("call {name}"));
-
-        if self.rsp_parity % 2 == 0 {
-            self.l("add rsp, 8");
-        }
-        let mut out = Reg::RAX;
-        for reg in saved_regs {
-            if reg == Reg::RAX {
-                out = self.next_reg();
-                self.l(format!("mov {out:?}, rax"));
+    /// Copies each source to its destination as if all the copies happened at once. The
+    /// copies are made in order if none overwrites a later one's source, and through the stack
+    /// otherwise.
+    fn parallel_move(&mut self, moves: &[(String, String)]) {
+        let moves: Vec<_> = moves.iter().filter(|(dst, src)| dst != src).collect();
+        let in_order = moves
+            .iter()
+            .enumerate()
+            .all(|(i, (dst, _))| moves[i + 1..].iter().all(|(_, src)| src != dst));
+        if in_order {
+            for (dst, src) in moves {
+                if dst.starts_with("qword") && src.starts_with("qword") {
+                    self.l(format!("mov rax, {src}"));
+                    self.l(format!("mov {dst}, rax"));
+                } else {
+                    self.l(format!("mov {dst}, {src}"));
+                }
+            }
+        } else {
+            for (_, src) in &moves {
+                self.l(format!("push {src}"));
+            }
+            for (dst, _) in moves.iter().rev() {
+                self.l(format!("pop {dst}"));
             }
-            self.l(format!("pop {reg:?}"));
-            self.preserve.insert(reg);
-            self.rsp_parity -= 1;
         }
-        out
-    }
-
-    fn call_one_param(&mut self, name: &str, p1: &Node) -> Reg {
-        self.compile_tok(p1, Some(Reg::RDI));
-
-        self.call_function(name)
     }
 
-    fn call_two_param(&mut self, name: &str, p1: &Node, p2: &Node) -> Reg {
-        self.compile_tok(p1, Some(Reg::RDI));
-        self.preserve.insert(Reg::RDI);
-        self.compile_tok(p2, Some(Reg::RSI));
-        self.preserve.remove(&Reg::RDI);
-
-        self.call_function(name)
+    fn label(&mut self) -> String {
+        let name = self.next_label_name();
+        self.consts.push((name.clone(), Const::Label));
+        name
     }
 
-    fn call_on_stack(&mut self, name: &str, params: &[Node]) -> Reg {
-        let orig_parity = self.rsp_parity;
-        let stack_misaligned = (self.rsp_parity + params.len()) % 2 == 0;
-        if stack_misaligned {
-            self.l("sub rsp, 8");
-            self.rsp_parity += 1;
-        }
-
-        for param in params.iter().rev() {
-            let reg = self.compile_tok(param, None);
-            self.l(format!("push {reg:?}"));
-            self.rsp_parity += 1;
+    /// Returns the label of `c` in the data section, adding it if it is not already there
+    fn intern(&mut self, c: Const) -> String {
+        if let Some((name, _)) = self.consts.iter().find(|(_, val)| val == &c) {
+            name.clone()
+        } else {
+            let name = self.next_label_name();
+            self.consts.push((name.clone(), c));
+            name
         }
-
-        self.l(format!("mov rdi, {}", params.len()));
-        self.l(format!("call {name}"));
-
-        self.l(format!("add rsp, {}", (self.rsp_parity - orig_parity) * 8));
-        self.rsp_parity = orig_parity;
-        Reg::RAX
-    }
-
-    fn next_reg(&self) -> Reg {
-        *USABLE_REGS.difference(&self.preserve).next().unwrap()
     }
 
     fn next_label_name(&self) -> String {
@@ -418,3 +449,64 @@ impl Compiler {
         }
     }
 }
+
+/// The words for `'()` and `#<void>`. See `mem.asm` for how values are represented.
+const EMPTY: u64 = 5;
+const VOID: u64 = 9;
+
+/// The types of boxes, which are the low byte of their header
+const FLOAT: u64 = 1;
+const CLOSURE: u64 = 2;
+const SYMBOL: u64 = 3;
+const CONS: u64 = 4;
+const CELL: u64 = 5;
+const STRING: u64 = 8;
+
+/// The word for an integer, which is stored in the upper 62 bits
+fn fixnum(i: i64) -> i64 {
+    assert!(
+        (i << 2) >> 2 == i,
+        "{i}: integer literal doesn't fit in 62 bits"
+    );
+    i << 2 | 2
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lexer::Lexer, parser::Parser};
+
+    use super::*;
+
+    #[test]
+    fn deterministic() {
+        let programs = [
+            "(define-syntax swap! (syntax-rules () [(_ a b) (let ([t a]) (set! a b) (set! b t))])) \
+             (let ([x 1] [y 2]) (swap! x y) (list x y))",
+            "(define (make-counter) (let ([n 0]) (lambda () (set! n (+ n 1)) n))) \
+             (let* ([a (make-counter)] [b (make-counter)]) (a) (b) (b) (list (a) (b)))",
+            "(let loop ([i 0] [acc '()]) \
+               (if (= i 20) `(done ,@acc 2.5 sym) (loop (+ i 1) (cons (* i 1.5) acc))))",
+            "(let* ([a 1] [b 2] [c 3] [d 4] [e 5] [f 6] [g 7] [h 8] [i 9] [j 10] [k 11] [l 12]) \
+               (cond [(= a b) 'x] [else (list a b c d e f g h i j k l)]))",
+        ];
+        for rkt in programs {
+            let compile = || {
+                let node = Parser::parse(Lexer::lex(rkt.into()));
+                Compiler::default().compile(&node)
+            };
+            assert_eq!(compile(), compile(), "{rkt}");
+        }
+    }
+
+    #[test]
+    fn inline_allocation() {
+        let rkt = "(define (pair n) (cons n (cons 'a '()))) (pair 2.5)";
+        let (_, lines) = Compiler::default().compile(&Parser::parse(Lexer::lex(rkt.into())));
+        let calls: Vec<_> = lines.iter().filter(|l| l.starts_with("call")).collect();
+        assert!(calls
+            .iter()
+            .all(|l| *l == "call heapfull" || l.starts_with("call qword")));
+        assert!(lines.contains(&"mov qword [rax], 0x20004".to_string()));
+        assert!(lines.contains(&"mov qword [rax], 0x10103".to_string()));
+    }
+}
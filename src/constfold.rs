@@ -0,0 +1,238 @@
+use crate::ir::{Function, Inst, Lit, Module, Terminator};
+
+/// Evaluates what it can of a program at compile time
+///
+/// Calls to pure runtime functions whose arguments are all constants are replaced by their
+/// result, and branches on constants become jumps. The results are the ones the runtime would
+/// give, so arithmetic involving a float is done in double precision. Integer results that don't
+/// fit in a fixnum are left to the runtime, which makes bignums of them. Literals and copies that
+/// are no longer used afterwards are removed.
+pub fn fold_constants(module: &mut Module) {
+    for func in &mut module.functions {
+        fold_function(func);
+    }
+}
+
+fn fold_function(func: &mut Function) {
+    // Registers assigned once can be followed to their value. Those assigned more than once,
+    // like the result of an `if`, can't.
+    let mut defs = vec![0; func.vregs];
+    for param in &func.params {
+        defs[param.0] += 1;
+    }
+    for inst in func.blocks.iter().flat_map(|b| &b.insts) {
+        if let Some(d) = inst.def() {
+            defs[d.0] += 1;
+        }
+    }
+
+    let mut known: Vec<Option<Lit>> = vec![None; func.vregs];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in &mut func.blocks {
+            for inst in &mut block.insts {
+                let value = match inst {
+                    Inst::Lit(_, lit) => Some(lit.clone()),
+                    Inst::Move(_, s) => known[s.0].clone(),
+                    Inst::Call(d, name, args) => {
+                        let args: Option<Vec<_>> =
+                            args.iter().map(|a| known[a.0].as_ref()).collect();
+                        let result = args.and_then(|args| eval(name, &args));
+                        if let Some(lit) = &result {
+                            *inst = Inst::Lit(*d, lit.clone());
+                            changed = true;
+                        }
+                        result
+                    }
+                    _ => None,
+                };
+                match (inst.def(), value) {
+                    (Some(d), Some(value)) if defs[d.0] == 1 && known[d.0].is_none() => {
+                        known[d.0] = Some(value);
+                        changed = true;
+                    }
+                    _ => {}
+                }
+            }
+            if let Terminator::Branch(cond, t, e) = block.term {
+                if let Some(lit) = &known[cond.0] {
+                    block.term = Terminator::Jump(if truthy(lit) { t } else { e });
+                    changed = true;
+                }
+            }
+        }
+    }
+    remove_unused_copies(func);
+}
+
+/// Removes literals and copies into registers that are never read
+fn remove_unused_copies(func: &mut Function) {
+    loop {
+        let mut used = vec![false; func.vregs];
+        for block in &func.blocks {
+            let uses = block.insts.iter().flat_map(Inst::uses);
+            for v in uses.chain(block.term.uses()) {
+                used[v.0] = true;
+            }
+        }
+        let mut removed = false;
+        for block in &mut func.blocks {
+            block.insts.retain(|inst| match inst {
+                Inst::Lit(d, _) | Inst::Move(d, _) if !used[d.0] => {
+                    removed = true;
+                    false
+                }
+                _ => true,
+            });
+        }
+        if !removed {
+            return;
+        }
+    }
+}
+
+/// Whether a branch on the value goes to its first block
+fn truthy(lit: &Lit) -> bool {
+    *lit != Lit::Bool(false)
+}
+
+/// Whether the integer fits in a fixnum
+fn fits(i: i64) -> bool {
+    (i << 2) >> 2 == i
+}
+
+/// The result of calling a runtime function on constants, or `None` if it can't or shouldn't be
+/// worked out at compile time
+fn eval(name: &str, args: &[&Lit]) -> Option<Lit> {
+    use Lit::{Float, Int, Symbol};
+    // Integers are converted to floats when mixed with them
+    let float = |lit: &Lit| match lit {
+        Int(i) => Some(*i as f64),
+        Float(f) => Some(*f),
+        _ => None,
+    };
+    let arith = |int: fn(i64, i64) -> Option<i64>, float_op: fn(f64, f64) -> f64| match args {
+        // Results that don't fit in a fixnum's 62 bits are left for the runtime to make bignums
+        [Int(a), Int(b)] => int(*a, *b).filter(|&i| fits(i)).map(Int),
+        // Debug doesn't print infinities and NaNs as Racket reads them
+        [a, b] => Some(float_op(float(a)?, float(b)?))
+            .filter(|f| f.is_finite())
+            .map(Float),
+        _ => None,
+    };
+    // Integer division by 0 is left for the runtime to report
+    let divide = |op: fn(i64, i64) -> i64| match args {
+        [Int(a), Int(b)] if *b != 0 && fits(op(*a, *b)) => Some(Int(op(*a, *b))),
+        _ => None,
+    };
+    match name {
+        "madd" => arith(|a, b| a.checked_add(b), |a, b| a + b),
+        "msub" => arith(|a, b| a.checked_sub(b), |a, b| a - b),
+        "mmul" => arith(|a, b| a.checked_mul(b), |a, b| a * b),
+        // Integers that don't divide exactly make a ratio, which isn't a literal
+        "mdiv" => match args {
+            [_, Int(0)] => None,
+            _ => arith(|a, b| (a % b == 0).then(|| a / b), |a, b| a / b),
+        },
+        "mquotient" => divide(|a, b| a / b),
+        "mremainder" => divide(|a, b| a % b),
+        "mmod" => divide(i64::rem_euclid).map(|r| match (r, args) {
+            // The result takes the sign of the divisor
+            (Int(r), [_, Int(b)]) if r != 0 && *b < 0 => Int(r + b),
+            (r, _) => r,
+        }),
+        "eq" => match args {
+            [Int(a), Int(b)] => Some(Lit::Bool(a == b)),
+            [Symbol(a), Symbol(b)] => Some(Lit::Bool(a == b)),
+            [Float(a), Float(b)] if !a.is_nan() && !b.is_nan() => Some(Lit::Bool(a == b)),
+            // Like arithmetic, a comparison with a float converts the integer to a float
+            [Int(a), Float(b)] | [Float(b), Int(a)] => Some(Lit::Bool(*a as f64 == *b)),
+            [Int(_) | Float(_), Symbol(_)] | [Symbol(_), Int(_) | Float(_)] => {
+                Some(Lit::Bool(false))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lexer::Lexer, parser::Parser};
+
+    use super::*;
+
+    fn folded(rkt: &str) -> String {
+        let mut module = Module::new(&Parser::parse(Lexer::lex(rkt.into())));
+        fold_constants(&mut module);
+        module.to_string()
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(
+            folded("(+ 1 (* 2 3))"),
+            "f0():\nb0:\n    v4 = 7\n    return v4\n"
+        );
+        assert_eq!(
+            folded("(let ([x 1]) (- x 0.25))"),
+            "f0():\nb0:\n    v2 = 0.75\n    return v2\n"
+        );
+        assert_eq!(
+            folded("(* 0.1 3)"),
+            "f0():\nb0:\n    v2 = 0.30000000000000004\n    return v2\n"
+        );
+        assert!(folded("(+ 2305843009213693951 1)").contains("call madd"));
+        assert!(folded("(* 2305843009213693951 -1)").contains(" = -2305843009213693951\n"));
+        assert!(folded("(quotient -2305843009213693952 -1)").contains("call mquotient"));
+        assert_eq!(
+            folded("(/ -8 2)"),
+            "f0():\nb0:\n    v2 = -4\n    return v2\n"
+        );
+        assert!(folded("(/ -7 2)").contains("call mdiv"));
+        assert!(folded("(/ 1 0)").contains("call mdiv"));
+        assert!(folded("(/ 1.0 0.0)").contains("call mdiv"));
+    }
+
+    #[test]
+    fn division() {
+        let value = |rkt: &str| folded(rkt).lines().nth(2).unwrap().to_string();
+        assert_eq!(value("(quotient -7 2)"), "    v2 = -3");
+        assert_eq!(value("(remainder -7 2)"), "    v2 = -1");
+        assert_eq!(value("(modulo -7 2)"), "    v2 = 1");
+        assert_eq!(value("(modulo 7 -2)"), "    v2 = -1");
+        assert_eq!(value("(modulo -6 2)"), "    v2 = 0");
+        assert!(folded("(modulo 7 0)").contains("call mmod"));
+    }
+
+    #[test]
+    fn branches() {
+        assert_eq!(
+            folded("(if (= 'a 'a) 1 (+ 1 2.5))"),
+            "\
+f0():
+b0:
+    jump b1
+b1:
+    v4 = 1
+    v3 = v4
+    jump b3
+b2:
+    v7 = 3.5
+    v3 = v7
+    jump b3
+b3:
+    return v3
+"
+        );
+        assert_eq!(
+            folded("(if (= 1 1.0) 1 2)").lines().nth(2),
+            Some("    jump b1")
+        );
+        assert_eq!(
+            folded("(if (= 1.5 1) 1 2)").lines().nth(2),
+            Some("    jump b2")
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::BTreeSet;
+
+use crate::ir::{Block, BlockId, FuncId, Function, Inst, Module, Terminator};
+
+/// Removes code that can't affect what a program does
+///
+/// This removes blocks that can't be reached, like the branch of an `if` that constant folding
+/// has ruled out, and instructions with unused results and no side effects, like the bindings of a
+/// `let` that are never referenced, and functions no closure is made of any more. A block that is
+/// only reached by a jump is merged into the block it jumps from.
+pub fn eliminate_dead_code(module: &mut Module) {
+    for func in &mut module.functions {
+        remove_unreachable_blocks(func);
+        merge_blocks(func);
+        remove_unused_values(func);
+    }
+    remove_unreferenced_functions(module);
+}
+
+/// Whether an instruction can be removed if its result isn't used. Runtime functions that can
+/// fail, like `first` on an empty list, have to stay.
+fn is_pure(inst: &Inst) -> bool {
+    match inst {
+        Inst::Lit(..)
+        | Inst::Move(..)
+        | Inst::Closure(..)
+        | Inst::Captured(..)
+        | Inst::NewCell(..)
+        | Inst::CellGet(..) => true,
+        Inst::Call(_, name, _) => matches!(&name[..], "cons" | "isempty"),
+        Inst::CallStack(_, name, _) => name == "list",
+        Inst::Apply(..) | Inst::CellSet(..) => false,
+    }
+}
+
+fn remove_unreachable_blocks(func: &mut Function) {
+    let mut reachable = BTreeSet::from([BlockId(0)]);
+    let mut stack = vec![BlockId(0)];
+    while let Some(b) = stack.pop() {
+        for s in func.blocks[b.0].term.successors() {
+            if reachable.insert(s) {
+                stack.push(s);
+            }
+        }
+    }
+    // Blocks keep their order, so a block that fell through to the next still does
+    let mut renumbered = vec![None; func.blocks.len()];
+    for (new, old) in reachable.iter().enumerate() {
+        renumbered[old.0] = Some(BlockId(new));
+    }
+    let new = |b: BlockId| renumbered[b.0].unwrap();
+    let blocks = std::mem::take(&mut func.blocks);
+    for (b, mut block) in blocks.into_iter().enumerate() {
+        if renumbered[b].is_none() {
+            continue;
+        }
+        block.term = match block.term {
+            Terminator::Jump(b) => Terminator::Jump(new(b)),
+            Terminator::Branch(c, t, e) => Terminator::Branch(c, new(t), new(e)),
+            Terminator::Return(v) => Terminator::Return(v),
+        };
+        func.blocks.push(block);
+    }
+}
+
+/// Appends each block that is only reached by a jump to the block making it
+fn merge_blocks(func: &mut Function) {
+    let mut preds = vec![0; func.blocks.len()];
+    for block in &func.blocks {
+        for s in block.term.successors() {
+            preds[s.0] += 1;
+        }
+    }
+    for b in 0..func.blocks.len() {
+        while let Terminator::Jump(next) = func.blocks[b].term {
+            if next.0 == b || next.0 == 0 || preds[next.0] != 1 {
+                break;
+            }
+            let next = std::mem::replace(
+                &mut func.blocks[next.0],
+                Block {
+                    insts: Vec::new(),
+                    term: Terminator::Jump(next),
+                },
+            );
+            func.blocks[b].insts.extend(next.insts);
+            func.blocks[b].term = next.term;
+        }
+    }
+    // The blocks merged into others are left jumping to themselves, and can't be reached
+    remove_unreachable_blocks(func);
+}
+
+fn remove_unused_values(func: &mut Function) {
+    loop {
+        let mut used = vec![false; func.vregs];
+        for block in &func.blocks {
+            let uses = block.insts.iter().flat_map(Inst::uses);
+            for v in uses.chain(block.term.uses()) {
+                used[v.0] = true;
+            }
+        }
+        let mut removed = false;
+        for block in &mut func.blocks {
+            block.insts.retain(|inst| {
+                let dead = inst.def().is_some_and(|d| !used[d.0]) && is_pure(inst);
+                removed |= dead;
+                !dead
+            });
+        }
+        if !removed {
+            return;
+        }
+    }
+}
+
+/// Removes the functions the program can no longer call, starting from the one that runs it
+fn remove_unreferenced_functions(module: &mut Module) {
+    let mut reachable = BTreeSet::from([FuncId(0)]);
+    let mut stack = vec![FuncId(0)];
+    while let Some(f) = stack.pop() {
+        for inst in module.functions[f.0].blocks.iter().flat_map(|b| &b.insts) {
+            if let Inst::Closure(_, g, _) = inst {
+                if reachable.insert(*g) {
+                    stack.push(*g);
+                }
+            }
+        }
+    }
+    let mut renumbered = vec![None; module.functions.len()];
+    for (new, old) in reachable.iter().enumerate() {
+        renumbered[old.0] = Some(FuncId(new));
+    }
+    let functions = std::mem::take(&mut module.functions);
+    for (f, mut func) in functions.into_iter().enumerate() {
+        if renumbered[f].is_none() {
+            continue;
+        }
+        for inst in func.blocks.iter_mut().flat_map(|b| &mut b.insts) {
+            if let Inst::Closure(_, g, _) = inst {
+                *g = renumbered[g.0].unwrap();
+            }
+        }
+        module.functions.push(func);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{constfold::fold_constants, lexer::Lexer, parser::Parser};
+
+    use super::*;
+
+    fn optimized(rkt: &str) -> String {
+        let mut module = Module::new(&Parser::parse(Lexer::lex(rkt.into())));
+        fold_constants(&mut module);
+        eliminate_dead_code(&mut module);
+        module.to_string()
+    }
+
+    #[test]
+    fn unreachable_branches() {
+        assert_eq!(
+            optimized("(if (= 1 2) (first '()) 3)"),
+            "f0():\nb0:\n    v6 = 3\n    v3 = v6\n    return v3\n"
+        );
+    }
+
+    #[test]
+    fn unused_bindings() {
+        assert_eq!(
+            optimized("(let ([x (list 1 2)] [y (first '())] [z 3]) z)"),
+            "\
+f0():
+b0:
+    v3 = '()
+    v4 = call first(v3)
+    v5 = 3
+    return v5
+"
+        );
+    }
+
+    #[test]
+    fn unreferenced_lambdas() {
+        let functions = |rkt| {
+            let ir = optimized(rkt);
+            ir.lines().filter(|l| l.starts_with('f')).count()
+        };
+        assert_eq!(
+            functions(
+                "(define (unused x) x) \
+                 (define (inner) 1) \
+                 (define (used) (inner)) \
+                 (used)"
+            ),
+            3
+        );
+        assert_eq!(functions("(lambda (x) x)"), 2);
+        assert_eq!(functions("(let ([f (lambda (x) x)]) 1)"), 1);
+    }
+}
@@ -0,0 +1,488 @@
+use crate::{
+    fold::{self, Fold},
+    parser::{CondExpr, Datum, DefineExpr, Expr, LambdaExpr, LetExpr, LetKind, Node, Template},
+    visit::Visitor,
+};
+
+/// The core language every surface form is lowered to before compilation
+///
+/// Forms that only exist for convenience, like `let*`, `cond`, `and` or `define`, are rewritten
+/// in terms of these. Builtins such as `+` are applications of a `Var` naming them.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Core {
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    /// The value of forms that have no useful result
+    Void,
+    /// Literal data
+    Quote(Datum),
+    Var(String),
+    Lambda(Vec<String>, Box<Core>),
+    /// Condition, then branch and else branch
+    If(Box<Core>, Box<Core>, Box<Core>),
+    /// Procedure and arguments
+    App(Box<Core>, Vec<Core>),
+    /// Binds each name to its value in the body. The values are evaluated outside the scope of
+    /// the new bindings.
+    Let(Vec<(String, Core)>, Box<Core>),
+    /// Assigns a new value to a bound variable
+    Set(String, Box<Core>),
+}
+
+/// Name of bindings whose values are only evaluated for their effects. Names starting with `#%`
+/// are reserved for the compiler.
+pub const IGNORED: &str = "#%ignored";
+
+/// Lowers a surface AST to the core language
+pub fn desugar(node: &Node) -> Core {
+    Reserved.visit_node(node);
+    let node = Shorthands.fold_node(node.clone());
+    Desugarer::default().desugar(&node)
+}
+
+/// Rejects programs using names reserved for the compiler, so temporaries can't capture them
+struct Reserved;
+
+impl Reserved {
+    fn check(name: &str) {
+        if name.starts_with("#%") {
+            panic!("{name}: identifier is reserved for the compiler");
+        }
+    }
+}
+
+impl Visitor for Reserved {
+    fn visit_ident(&mut self, name: &str) {
+        Self::check(name)
+    }
+
+    fn visit_binder(&mut self, name: &str) {
+        Self::check(name)
+    }
+}
+
+/// Rewrites shorthands into the forms they stand for: `let*` into nested `let`s, and
+/// `(define (f params ...) body ...)` into a `define` of a lambda
+struct Shorthands;
+
+impl Fold for Shorthands {
+    fn fold_let_expr(&mut self, e: LetExpr) -> Node {
+        if e.kind != LetKind::LetStar {
+            return fold::fold_let_expr(self, e);
+        }
+        let body = self.fold_node(e.body);
+        e.bindings
+            .into_iter()
+            .rev()
+            .fold(body, |body, (name, val)| {
+                Node::LetExpr(Box::new(LetExpr {
+                    kind: LetKind::Let,
+                    bindings: vec![(name, self.fold_node(val))],
+                    body,
+                }))
+            })
+    }
+
+    fn fold_define_expr(&mut self, e: DefineExpr) -> Node {
+        let body = self.fold_node(e.body);
+        let body = match e.params {
+            Some(params) => Node::LambdaExpr(Box::new(LambdaExpr { params, body })),
+            None => body,
+        };
+        Node::DefineExpr(Box::new(DefineExpr {
+            name: e.name,
+            params: None,
+            body,
+        }))
+    }
+}
+
+#[derive(Default)]
+struct Desugarer {
+    /// Number of temporaries made so far, used to make fresh names
+    temps: usize,
+}
+
+impl Desugarer {
+    fn desugar(&mut self, node: &Node) -> Core {
+        match node {
+            Node::Integer(i) => Core::Integer(*i),
+            Node::Float(f) => Core::Float(*f),
+            Node::String(s) if s == "#t" => Core::Bool(true),
+            Node::String(s) if s == "#f" => Core::Bool(false),
+            Node::String(s) => Core::Var(s.clone()),
+            Node::Quote(d) => Core::Quote((**d).clone()),
+            Node::Quasiquote(t) => self.desugar_template(t),
+            Node::LambdaExpr(e) => Core::Lambda(e.params.clone(), Box::new(self.desugar(&e.body))),
+            Node::LetExpr(e) => self.desugar_let(e),
+            Node::CondExpr(e) => self.desugar_cond(e),
+            Node::Begin(body) => self.desugar_body(body),
+            Node::DefineExpr(e) => {
+                panic!("define: not allowed in an expression context: {}", e.name)
+            }
+            Node::Expr(e) => self.desugar_expr(e),
+            Node::AppExpr(e) => Core::App(
+                Box::new(self.desugar(&e.op)),
+                e.params.iter().map(|p| self.desugar(p)).collect(),
+            ),
+        }
+    }
+
+    fn desugar_expr(&mut self, e: &Expr) -> Core {
+        let params = &e.params[..];
+        match (&e.op[..], params) {
+            ("if", [c, t, f]) => Core::If(
+                Box::new(self.desugar(c)),
+                Box::new(self.desugar(t)),
+                Box::new(self.desugar(f)),
+            ),
+            ("if", _) => panic!("if: bad syntax"),
+            ("and", params) => self.desugar_and(params),
+            ("or", params) => self.desugar_or(params),
+            ("when", [c, body @ ..]) if !body.is_empty() => Core::If(
+                Box::new(self.desugar(c)),
+                Box::new(self.desugar_body(body)),
+                Box::new(Core::Void),
+            ),
+            ("unless", [c, body @ ..]) if !body.is_empty() => Core::If(
+                Box::new(self.desugar(c)),
+                Box::new(Core::Void),
+                Box::new(self.desugar_body(body)),
+            ),
+            (op @ ("when" | "unless"), _) => panic!("{op}: bad syntax"),
+            ("set!", [Node::String(name), value]) => {
+                Core::Set(name.clone(), Box::new(self.desugar(value)))
+            }
+            ("set!", _) => panic!("set!: bad syntax"),
+            (op, params) => Core::App(
+                Box::new(Core::Var(op.into())),
+                params.iter().map(|p| self.desugar(p)).collect(),
+            ),
+        }
+    }
+
+    fn desugar_and(&mut self, params: &[Node]) -> Core {
+        match params {
+            [] => Core::Bool(true),
+            [e] => self.desugar(e),
+            [e, rest @ ..] => Core::If(
+                Box::new(self.desugar(e)),
+                Box::new(self.desugar_and(rest)),
+                Box::new(Core::Bool(false)),
+            ),
+        }
+    }
+
+    fn desugar_or(&mut self, params: &[Node]) -> Core {
+        match params {
+            [] => Core::Bool(false),
+            [e] => self.desugar(e),
+            [e, rest @ ..] => {
+                // (let ([tmp e]) (if tmp tmp (or rest ...)))
+                let tmp = self.temp("or");
+                Core::Let(
+                    vec![(tmp.clone(), self.desugar(e))],
+                    Box::new(Core::If(
+                        Box::new(Core::Var(tmp.clone())),
+                        Box::new(Core::Var(tmp)),
+                        Box::new(self.desugar_or(rest)),
+                    )),
+                )
+            }
+        }
+    }
+
+    fn desugar_let(&mut self, e: &LetExpr) -> Core {
+        let bindings: Vec<_> = e
+            .bindings
+            .iter()
+            .map(|(name, val)| (name.clone(), self.desugar(val)))
+            .collect();
+        let body = self.desugar(&e.body);
+        match &e.kind {
+            LetKind::Let => Core::Let(bindings, Box::new(body)),
+            LetKind::LetStar => unreachable!("let* is rewritten by `Shorthands`"),
+            LetKind::Letrec => Self::letrec(bindings, body),
+            LetKind::Named(name) => {
+                // ((letrec ([name (lambda (params ...) body)]) name) args ...)
+                let (params, args): (Vec<_>, Vec<_>) = bindings.into_iter().unzip();
+                let f = Core::Lambda(params, Box::new(body));
+                let f = Self::letrec(vec![(name.clone(), f)], Core::Var(name.clone()));
+                Core::App(Box::new(f), args)
+            }
+        }
+    }
+
+    /// Binds every name before evaluating any of the values, so they can refer to each other
+    fn letrec(bindings: Vec<(String, Core)>, body: Core) -> Core {
+        let names = bindings
+            .iter()
+            .map(|(name, _)| (name.clone(), Core::Bool(false)))
+            .collect();
+        let sets = bindings
+            .into_iter()
+            .map(|(name, val)| Core::Set(name, Box::new(val)));
+        let body = Self::sequence(sets.chain([body]).collect());
+        Core::Let(names, Box::new(body))
+    }
+
+    fn desugar_cond(&mut self, e: &CondExpr) -> Core {
+        let otherwise = match &e.else_body {
+            Some(body) => self.desugar(body),
+            None => Core::Void,
+        };
+        e.clauses
+            .iter()
+            .rev()
+            .fold(otherwise, |otherwise, (test, body)| {
+                Core::If(
+                    Box::new(self.desugar(test)),
+                    Box::new(self.desugar(body)),
+                    Box::new(otherwise),
+                )
+            })
+    }
+
+    /// Lowers a sequence of expressions. Definitions in the sequence are visible to the whole
+    /// sequence, like `letrec`.
+    fn desugar_body(&mut self, body: &[Node]) -> Core {
+        let mut defines = Vec::new();
+        let mut exprs = Vec::new();
+        for node in body {
+            match node {
+                Node::DefineExpr(e) => {
+                    let val = self.desugar(&e.body);
+                    defines.push(e.name.clone());
+                    exprs.push(Core::Set(e.name.clone(), Box::new(val)));
+                }
+                _ => exprs.push(self.desugar(node)),
+            }
+        }
+        assert!(
+            !matches!(body.last(), Some(Node::DefineExpr(_))),
+            "no expression after a sequence of definitions"
+        );
+
+        let body = Self::sequence(exprs);
+        if defines.is_empty() {
+            body
+        } else {
+            let names = defines
+                .into_iter()
+                .map(|name| (name, Core::Bool(false)))
+                .collect();
+            Core::Let(names, Box::new(body))
+        }
+    }
+
+    /// Evaluates each expression in order and returns the value of the last one
+    fn sequence(mut exprs: Vec<Core>) -> Core {
+        let last = exprs.pop().expect("empty sequence");
+        exprs.into_iter().rev().fold(last, |rest, e| {
+            Core::Let(vec![(IGNORED.into(), e)], Box::new(rest))
+        })
+    }
+
+    /// Builds the value of a quasiquoted template out of `cons`, `list` and `append`
+    fn desugar_template(&mut self, t: &Template) -> Core {
+        match t {
+            Template::Datum(d) => Core::Quote(d.clone()),
+            Template::Unquote(e) => self.desugar(e),
+            Template::UnquoteSplicing(_) => {
+                panic!("unquote-splicing: invalid context within quasiquote")
+            }
+            Template::List(items) => {
+                let call = |op: &str, params| Core::App(Box::new(Core::Var(op.into())), params);
+                if !items
+                    .iter()
+                    .any(|item| matches!(item, Template::UnquoteSplicing(_)))
+                {
+                    let items = items.iter().map(|t| self.desugar_template(t)).collect();
+                    return call("list", items);
+                }
+
+                let empty = Core::Quote(Datum::List(Vec::new()));
+                let mut out = empty.clone();
+                for item in items.iter().rev() {
+                    out = match item {
+                        // Splicing at the end shares the list instead of copying it
+                        Template::UnquoteSplicing(e) if out == empty => self.desugar(e),
+                        Template::UnquoteSplicing(e) => call("append", vec![self.desugar(e), out]),
+                        _ => call("cons", vec![self.desugar_template(item), out]),
+                    };
+                }
+                out
+            }
+        }
+    }
+
+    fn temp(&mut self, name: &str) -> String {
+        self.temps += 1;
+        format!("#%{name}{}", self.temps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lexer::Lexer, parser::Parser};
+
+    use super::*;
+
+    fn lower(rkt: &str) -> Core {
+        desugar(&Parser::parse(Lexer::lex(rkt.into())))
+    }
+
+    fn var(name: &str) -> Core {
+        Core::Var(name.into())
+    }
+
+    fn app(op: &str, params: Vec<Core>) -> Core {
+        Core::App(Box::new(var(op)), params)
+    }
+
+    #[test]
+    fn define_shorthand() {
+        assert_eq!(
+            lower("(define (f x) x) (f 1)"),
+            lower("(define f (lambda (x) x)) (f 1)")
+        );
+    }
+
+    #[test]
+    fn let_star() {
+        assert_eq!(
+            lower("(let* ([x 1] [y x]) y)"),
+            Core::Let(
+                vec![("x".into(), Core::Integer(1))],
+                Box::new(Core::Let(vec![("y".into(), var("x"))], Box::new(var("y"))))
+            )
+        );
+    }
+
+    #[test]
+    fn cond_and_or() {
+        assert_eq!(
+            lower("(cond [(and a b) 1] [(or c d) 2])"),
+            Core::If(
+                Box::new(Core::If(
+                    Box::new(var("a")),
+                    Box::new(var("b")),
+                    Box::new(Core::Bool(false))
+                )),
+                Box::new(Core::Integer(1)),
+                Box::new(Core::If(
+                    Box::new(Core::Let(
+                        vec![("#%or1".into(), var("c"))],
+                        Box::new(Core::If(
+                            Box::new(var("#%or1")),
+                            Box::new(var("#%or1")),
+                            Box::new(var("d"))
+                        ))
+                    )),
+                    Box::new(Core::Integer(2)),
+                    Box::new(Core::Void)
+                ))
+            )
+        );
+    }
+
+    #[test]
+    fn defines() {
+        assert_eq!(
+            lower("(define (f x) x) (define y 1) (f y)"),
+            Core::Let(
+                vec![
+                    ("f".into(), Core::Bool(false)),
+                    ("y".into(), Core::Bool(false))
+                ],
+                Box::new(Core::Let(
+                    vec![(
+                        IGNORED.into(),
+                        Core::Set(
+                            "f".into(),
+                            Box::new(Core::Lambda(vec!["x".into()], Box::new(var("x"))))
+                        )
+                    )],
+                    Box::new(Core::Let(
+                        vec![(
+                            IGNORED.into(),
+                            Core::Set("y".into(), Box::new(Core::Integer(1)))
+                        )],
+                        Box::new(Core::App(Box::new(var("f")), vec![var("y")]))
+                    ))
+                ))
+            )
+        );
+    }
+
+    #[test]
+    fn named_let() {
+        assert_eq!(
+            lower("(let loop ([i 0]) (loop i))"),
+            Core::App(
+                Box::new(Core::Let(
+                    vec![("loop".into(), Core::Bool(false))],
+                    Box::new(Core::Let(
+                        vec![(
+                            IGNORED.into(),
+                            Core::Set(
+                                "loop".into(),
+                                Box::new(Core::Lambda(
+                                    vec!["i".into()],
+                                    Box::new(app("loop", vec![var("i")]))
+                                ))
+                            )
+                        )],
+                        Box::new(var("loop"))
+                    ))
+                )),
+                vec![Core::Integer(0)]
+            )
+        );
+    }
+
+    #[test]
+    fn quasiquote() {
+        let quoted = |s: &str| Core::Quote(Datum::Symbol(s.into()));
+        assert_eq!(
+            lower("`(a ,b ,@c (d ,e))"),
+            app(
+                "cons",
+                vec![
+                    quoted("a"),
+                    app(
+                        "cons",
+                        vec![
+                            var("b"),
+                            app(
+                                "append",
+                                vec![
+                                    var("c"),
+                                    app(
+                                        "cons",
+                                        vec![
+                                            app("list", vec![quoted("d"), var("e")]),
+                                            Core::Quote(Datum::List(Vec::new()))
+                                        ]
+                                    )
+                                ]
+                            )
+                        ]
+                    )
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn quasiquote_without_unquote_is_quote() {
+        assert_eq!(lower("`(1 (a))"), lower("'(1 (a))"));
+    }
+
+    #[test]
+    #[should_panic(expected = "reserved")]
+    fn reserved_names() {
+        lower("(let ([#%or1 1]) (or #f #%or1))");
+    }
+}
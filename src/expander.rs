@@ -0,0 +1,513 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::Datum;
+
+const ELLIPSIS: &str = "...";
+
+/// Expands `syntax-rules` macros defined with `define-syntax`
+///
+/// Expansion is hygienic by renaming: every identifier a template introduces is given a fresh
+/// name. Introduced identifiers that end up bound by a `lambda`, `let` or `define` in the
+/// expansion keep their fresh name so they cannot capture or be captured by the macro user's
+/// variables. The rest refer to whatever they meant where the macro was used, and get their
+/// names back.
+///
+/// That makes the hygiene partial. A free identifier in a template, like `if` or a helper
+/// function, isn't looked up where the macro was defined, so a binding of the same name around
+/// the macro use captures it.
+#[derive(Default)]
+pub struct Expander {
+    macros: HashMap<String, SyntaxRules>,
+    /// Number of macro uses expanded so far, used to make fresh names
+    expansions: usize,
+}
+
+struct SyntaxRules {
+    literals: Vec<String>,
+    /// Pattern and template for each rule, tried in order
+    rules: Vec<(Datum, Datum)>,
+}
+
+/// What a pattern variable matched
+#[derive(Debug, Clone)]
+enum Binding {
+    One(Datum),
+    /// One binding per repetition of a pattern followed by `...`
+    Many(Vec<Binding>),
+}
+
+impl Expander {
+    /// Registers the top-level `define-syntax` forms and expands the forms that are left
+    pub fn expand_program(&mut self, forms: Vec<Datum>) -> Vec<Datum> {
+        let mut exprs = Vec::new();
+        for form in forms {
+            match &form {
+                Datum::List(items) if Self::is_form(&items[..], "define-syntax") => {
+                    self.define_syntax(&items[..])
+                }
+                _ => exprs.push(form),
+            }
+        }
+        exprs.iter().map(|e| self.expand(e)).collect()
+    }
+
+    fn define_syntax(&mut self, items: &[Datum]) {
+        let (name, rules) = match items {
+            [_, Datum::Symbol(name), Datum::List(rules)] => (name, rules),
+            _ => panic!("define-syntax: bad syntax"),
+        };
+        let (literals, rules) = match &rules[..] {
+            [Datum::Symbol(head), Datum::List(literals), rules @ ..] if head == "syntax-rules" => {
+                (literals, rules)
+            }
+            _ => panic!("define-syntax: only syntax-rules is supported"),
+        };
+        let literals = literals
+            .iter()
+            .map(|l| match l {
+                Datum::Symbol(l) => l.clone(),
+                _ => panic!("syntax-rules: literal must be an identifier, got {l:?}"),
+            })
+            .collect();
+        let rules = rules
+            .iter()
+            .map(|rule| match rule {
+                Datum::List(rule) if rule.len() == 2 => (rule[0].clone(), rule[1].clone()),
+                _ => panic!("syntax-rules: bad rule {rule:?}"),
+            })
+            .collect();
+        self.macros
+            .insert(name.clone(), SyntaxRules { literals, rules });
+    }
+
+    pub fn expand(&mut self, d: &Datum) -> Datum {
+        let items = match d {
+            Datum::List(items) => items,
+            _ => return d.clone(),
+        };
+        let op = match items.first() {
+            Some(Datum::Symbol(op)) => op,
+            _ => return Datum::List(items.iter().map(|item| self.expand(item)).collect()),
+        };
+        if let Some(expansion) = self.expand_macro_use(op, items) {
+            return self.expand(&expansion);
+        }
+        match &op[..] {
+            "quote" => d.clone(),
+            "quasiquote" if items.len() == 2 => {
+                Datum::List(vec![items[0].clone(), self.expand_quasiquote(&items[1], 1)])
+            }
+            "lambda" | "define" => self.expand_from(items, 2),
+            "let" | "let*" | "letrec" => {
+                // Named `let` has its name before the bindings
+                let at = if matches!(items.get(1), Some(Datum::Symbol(_))) {
+                    2
+                } else {
+                    1
+                };
+                let bindings = match items.get(at) {
+                    Some(Datum::List(bindings)) => bindings
+                        .iter()
+                        .map(|binding| match binding {
+                            Datum::List(binding) if binding.len() == 2 => {
+                                Datum::List(vec![binding[0].clone(), self.expand(&binding[1])])
+                            }
+                            _ => binding.clone(),
+                        })
+                        .collect(),
+                    _ => return d.clone(),
+                };
+                let mut expanded = items[..at].to_vec();
+                expanded.push(Datum::List(bindings));
+                expanded.extend(items[at + 1..].iter().map(|item| self.expand(item)));
+                Datum::List(expanded)
+            }
+            _ => Datum::List(items.iter().map(|item| self.expand(item)).collect()),
+        }
+    }
+
+    /// Expands the items of a form after the first `n`
+    fn expand_from(&mut self, items: &[Datum], n: usize) -> Datum {
+        let n = n.min(items.len());
+        let mut expanded = items[..n].to_vec();
+        expanded.extend(items[n..].iter().map(|item| self.expand(item)));
+        Datum::List(expanded)
+    }
+
+    /// Expands only the parts of a quasiquoted template that are evaluated
+    fn expand_quasiquote(&mut self, d: &Datum, depth: usize) -> Datum {
+        let items = match d {
+            Datum::List(items) => items,
+            _ => return d.clone(),
+        };
+        match &items[..] {
+            [Datum::Symbol(name), inner] if name == "unquote" || name == "unquote-splicing" => {
+                let inner = if depth == 1 {
+                    self.expand(inner)
+                } else {
+                    self.expand_quasiquote(inner, depth - 1)
+                };
+                Datum::List(vec![items[0].clone(), inner])
+            }
+            [Datum::Symbol(name), inner] if name == "quasiquote" => Datum::List(vec![
+                items[0].clone(),
+                self.expand_quasiquote(inner, depth + 1),
+            ]),
+            _ => Datum::List(
+                items
+                    .iter()
+                    .map(|item| self.expand_quasiquote(item, depth))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Returns the expansion of `form` if `op` is a macro
+    fn expand_macro_use(&mut self, op: &str, form: &[Datum]) -> Option<Datum> {
+        let rules = self.macros.get(op)?;
+        // The macro keyword is not matched against the first element of the pattern
+        let args = Datum::List(form[1..].to_vec());
+        let (bindings, template) = rules
+            .rules
+            .iter()
+            .find_map(|(pattern, template)| {
+                let pattern = match pattern {
+                    Datum::List(patterns) if !patterns.is_empty() => {
+                        Datum::List(patterns[1..].to_vec())
+                    }
+                    _ => panic!("syntax-rules: bad pattern {pattern:?}"),
+                };
+                let mut bindings = HashMap::new();
+                Self::match_pattern(&rules.literals, &pattern, &args, &mut bindings)
+                    .then(|| (bindings, template.clone()))
+            })
+            .unwrap_or_else(|| panic!("{op}: bad syntax"));
+
+        self.expansions += 1;
+        let mut renamed = HashMap::new();
+        let expansion = self.instantiate(&template, &bindings, &mut renamed);
+        let mut bound = HashSet::new();
+        Self::find_bound(&expansion, &renamed, &mut bound);
+        Some(Self::restore_free(&expansion, &renamed, &bound))
+    }
+
+    /// Matches `d` against `pattern`, adding the pattern variables to `bindings`
+    fn match_pattern(
+        literals: &[String],
+        pattern: &Datum,
+        d: &Datum,
+        bindings: &mut HashMap<String, Binding>,
+    ) -> bool {
+        match pattern {
+            Datum::Symbol(s) if s == "_" => true,
+            Datum::Symbol(s) if literals.contains(s) => d == pattern,
+            Datum::Symbol(s) => {
+                bindings.insert(s.clone(), Binding::One(d.clone()));
+                true
+            }
+            Datum::List(patterns) => {
+                let items = match d {
+                    Datum::List(items) => items,
+                    _ => return false,
+                };
+                let (before, repeated, after) = match patterns
+                    .iter()
+                    .position(|p| p == &Datum::Symbol(ELLIPSIS.into()))
+                {
+                    Some(i) if i > 0 => (
+                        &patterns[..i - 1],
+                        Some(&patterns[i - 1]),
+                        &patterns[i + 1..],
+                    ),
+                    _ => (&patterns[..], None, &patterns[patterns.len()..]),
+                };
+                if items.len() < before.len() + after.len()
+                    || (repeated.is_none() && items.len() != before.len())
+                {
+                    return false;
+                }
+
+                let (head, rest) = items.split_at(before.len());
+                let (middle, tail) = rest.split_at(rest.len() - after.len());
+                let fixed = before.iter().zip(head).chain(after.iter().zip(tail));
+                for (p, item) in fixed {
+                    if !Self::match_pattern(literals, p, item, bindings) {
+                        return false;
+                    }
+                }
+                if let Some(repeated) = repeated {
+                    let mut matches = Vec::new();
+                    for item in middle {
+                        let mut inner = HashMap::new();
+                        if !Self::match_pattern(literals, repeated, item, &mut inner) {
+                            return false;
+                        }
+                        matches.push(inner);
+                    }
+                    for var in Self::pattern_vars(literals, repeated) {
+                        let each = matches.iter().map(|m| m[&var].clone()).collect();
+                        bindings.insert(var, Binding::Many(each));
+                    }
+                }
+                true
+            }
+            _ => d == pattern,
+        }
+    }
+
+    fn pattern_vars(literals: &[String], pattern: &Datum) -> Vec<String> {
+        match pattern {
+            Datum::Symbol(s) if s == "_" || s == ELLIPSIS || literals.contains(s) => Vec::new(),
+            Datum::Symbol(s) => vec![s.clone()],
+            Datum::List(patterns) => patterns
+                .iter()
+                .flat_map(|p| Self::pattern_vars(literals, p))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Fills in a template. Identifiers that are not pattern variables are renamed, and
+    /// `renamed` maps the new names back to the original ones.
+    fn instantiate(
+        &self,
+        template: &Datum,
+        bindings: &HashMap<String, Binding>,
+        renamed: &mut HashMap<String, String>,
+    ) -> Datum {
+        match template {
+            Datum::Symbol(s) => match bindings.get(s) {
+                Some(Binding::One(d)) => d.clone(),
+                Some(Binding::Many(_)) => panic!("{s}: missing ellipsis in template"),
+                None => {
+                    let fresh = format!("{s}#{}", self.expansions);
+                    renamed.insert(fresh.clone(), s.clone());
+                    Datum::Symbol(fresh)
+                }
+            },
+            Datum::List(templates) => {
+                let mut items = Vec::new();
+                let mut i = 0;
+                while i < templates.len() {
+                    let repeated = templates.get(i + 1) == Some(&Datum::Symbol(ELLIPSIS.into()));
+                    if !repeated {
+                        items.push(self.instantiate(&templates[i], bindings, renamed));
+                        i += 1;
+                        continue;
+                    }
+
+                    let vars: Vec<_> = Self::template_vars(&templates[i])
+                        .into_iter()
+                        .filter(|var| matches!(bindings.get(var), Some(Binding::Many(_))))
+                        .collect();
+                    let len = vars
+                        .iter()
+                        .map(|var| match &bindings[var] {
+                            Binding::Many(each) => each.len(),
+                            Binding::One(_) => unreachable!(),
+                        })
+                        .max()
+                        .unwrap_or_else(|| panic!("no pattern variables before ellipsis"));
+                    for n in 0..len {
+                        let mut inner = bindings.clone();
+                        for var in &vars {
+                            if let Binding::Many(each) = &bindings[var] {
+                                let b = each.get(n).unwrap_or_else(|| {
+                                    panic!("{var}: incompatible ellipsis match counts")
+                                });
+                                inner.insert(var.clone(), b.clone());
+                            }
+                        }
+                        items.push(self.instantiate(&templates[i], &inner, renamed));
+                    }
+                    i += 2;
+                }
+                Datum::List(items)
+            }
+            _ => template.clone(),
+        }
+    }
+
+    fn template_vars(template: &Datum) -> Vec<String> {
+        match template {
+            Datum::Symbol(s) => vec![s.clone()],
+            Datum::List(templates) => templates.iter().flat_map(Self::template_vars).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Collects the renamed identifiers that are bound by a binding form in `d`
+    fn find_bound<'a>(
+        d: &'a Datum,
+        renamed: &HashMap<String, String>,
+        bound: &mut HashSet<String>,
+    ) {
+        let items = match d {
+            Datum::List(items) => items,
+            _ => return,
+        };
+        let op = match items.first() {
+            Some(Datum::Symbol(op)) => renamed.get(op).unwrap_or(op),
+            _ => "",
+        };
+        let binding_names = |bindings: &'a [Datum]| {
+            bindings.iter().filter_map(|b| match b {
+                Datum::List(b) => b.first(),
+                _ => None,
+            })
+        };
+        let binders: Vec<&Datum> = match (op, items.get(1..).unwrap_or_default()) {
+            ("lambda", [Datum::List(params), ..]) => params.iter().collect(),
+            ("define", [Datum::List(header), ..]) => header.iter().collect(),
+            ("define", [name, ..]) => vec![name],
+            ("let", [name @ Datum::Symbol(_), Datum::List(bindings), ..]) => {
+                binding_names(bindings).chain([name]).collect()
+            }
+            ("let" | "let*" | "letrec", [Datum::List(bindings), ..]) => {
+                binding_names(bindings).collect()
+            }
+            ("quote", _) => return,
+            _ => Vec::new(),
+        };
+        for binder in binders {
+            if let Datum::Symbol(s) = binder {
+                if renamed.contains_key(s) {
+                    bound.insert(s.clone());
+                }
+            }
+        }
+        for item in items {
+            Self::find_bound(item, renamed, bound);
+        }
+    }
+
+    /// Gives renamed identifiers that are not in `bound` their original names back. Quoted
+    /// identifiers always get their original names.
+    fn restore_free(
+        d: &Datum,
+        renamed: &HashMap<String, String>,
+        bound: &HashSet<String>,
+    ) -> Datum {
+        match d {
+            Datum::Symbol(s) if !bound.contains(s) => {
+                Datum::Symbol(renamed.get(s).unwrap_or(s).clone())
+            }
+            Datum::List(items) if matches!(items.first(), Some(Datum::Symbol(op)) if renamed.get(op).unwrap_or(op) == "quote") => {
+                Self::restore_free(d, renamed, &HashSet::new())
+            }
+            Datum::List(items) => Datum::List(
+                items
+                    .iter()
+                    .map(|item| Self::restore_free(item, renamed, bound))
+                    .collect(),
+            ),
+            _ => d.clone(),
+        }
+    }
+
+    fn is_form(items: &[Datum], name: &str) -> bool {
+        matches!(items.first(), Some(Datum::Symbol(s)) if s == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lexer::Lexer, parser::Parser};
+
+    use super::*;
+
+    fn expand(rkt: &str) -> Datum {
+        Expander::default()
+            .expand_program(Parser::read(Lexer::lex(rkt.into())))
+            .remove(0)
+    }
+
+    fn read(rkt: &str) -> Datum {
+        Parser::read(Lexer::lex(rkt.into())).remove(0)
+    }
+
+    #[test]
+    fn ellipsis() {
+        assert_eq!(
+            expand(
+                "(define-syntax my-let
+                   (syntax-rules ()
+                     [(_ ([name val] ...) body) ((lambda (name ...) body) val ...)]))
+                 (my-let ([x 1] [y 2]) (+ x y))"
+            ),
+            read("((lambda (x y) (+ x y)) 1 2)")
+        );
+    }
+
+    #[test]
+    fn literals_and_recursion() {
+        assert_eq!(
+            expand(
+                "(define-syntax my-cond
+                   (syntax-rules (else)
+                     [(_ [else e]) e]
+                     [(_ [c e] clause ...) (if c e (my-cond clause ...))]))
+                 (my-cond [(= x 1) 10] [(= x 2) 20] [else 30])"
+            ),
+            read("(if (= x 1) 10 (if (= x 2) 20 30))")
+        );
+    }
+
+    #[test]
+    fn introduced_bindings_are_renamed() {
+        assert_eq!(
+            expand(
+                "(define-syntax my-or
+                   (syntax-rules ()
+                     [(_ a b) (let* ([t a]) (if t t b))]))
+                 (my-or #f t)"
+            ),
+            read("(let* ([t#1 #f]) (if t#1 t#1 t))")
+        );
+    }
+
+    #[test]
+    fn free_identifiers_can_be_captured() {
+        // Racket would still use the `if` from where `my-or` is defined
+        assert_eq!(
+            expand(
+                "(define-syntax my-or
+                   (syntax-rules ()
+                     [(_ a b) (let* ([t a]) (if t t b))]))
+                 (let ([if 1]) (my-or #f 2))"
+            ),
+            read("(let ([if 1]) (let* ([t#1 #f]) (if t#1 t#1 2)))")
+        );
+    }
+
+    #[test]
+    fn empty_lists_in_templates() {
+        assert_eq!(
+            expand(
+                "(define-syntax thunk (syntax-rules () [(_ e) (lambda () e)]))
+                 ((thunk 5))"
+            ),
+            read("((lambda () 5))")
+        );
+        assert_eq!(
+            expand(
+                "(define-syntax while
+                   (syntax-rules ()
+                     [(_ c body ...) (let lp () (when c body ... (lp)))]))
+                 (while (< i 3) (set! i (+ i 1)))"
+            ),
+            read("(let lp#1 () (when (< i 3) (set! i (+ i 1)) (lp#1)))")
+        );
+    }
+
+    #[test]
+    fn quoted_data_is_not_expanded() {
+        assert_eq!(
+            expand(
+                "(define-syntax one (syntax-rules () [(_) 1]))
+                 (list '(one) `(,(one) (one)))"
+            ),
+            read("(list '(one) `(,1 (one)))")
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use crate::parser::{
+    AppExpr, CondExpr, Datum, DefineExpr, Expr, LambdaExpr, LetExpr, LetKind, Node, Template,
+};
+
+/// Rebuilds a `Node` tree, possibly changing it
+///
+/// Every method defaults to folding the children of what it is given and putting them back
+/// together, so an implementation only overrides the parts it rewrites. An override can call
+/// the matching `fold_*` function to keep folding.
+pub trait Fold {
+    fn fold_node(&mut self, node: Node) -> Node {
+        fold_node(self, node)
+    }
+
+    fn fold_expr(&mut self, e: Expr) -> Node {
+        fold_expr(self, e)
+    }
+
+    fn fold_app_expr(&mut self, e: AppExpr) -> Node {
+        fold_app_expr(self, e)
+    }
+
+    fn fold_let_expr(&mut self, e: LetExpr) -> Node {
+        fold_let_expr(self, e)
+    }
+
+    fn fold_lambda_expr(&mut self, e: LambdaExpr) -> Node {
+        fold_lambda_expr(self, e)
+    }
+
+    fn fold_cond_expr(&mut self, e: CondExpr) -> Node {
+        fold_cond_expr(self, e)
+    }
+
+    fn fold_define_expr(&mut self, e: DefineExpr) -> Node {
+        fold_define_expr(self, e)
+    }
+
+    fn fold_template(&mut self, t: Template) -> Template {
+        fold_template(self, t)
+    }
+
+    /// Called for identifiers used as expressions, including `#t` and `#f`
+    fn fold_ident(&mut self, name: String) -> Node {
+        Node::String(name)
+    }
+
+    /// Called for the operator of every `Expr`, including keywords like `if`
+    fn fold_op(&mut self, op: String) -> String {
+        op
+    }
+
+    /// Called for identifiers being bound by `let`, `lambda` or `define`
+    fn fold_binder(&mut self, name: String) -> String {
+        name
+    }
+
+    fn fold_integer(&mut self, i: i64) -> Node {
+        Node::Integer(i)
+    }
+
+    fn fold_float(&mut self, f: f64) -> Node {
+        Node::Float(f)
+    }
+
+    /// Called for quoted data. The constant parts of quasiquoted templates are left alone.
+    fn fold_quote(&mut self, d: Datum) -> Node {
+        Node::Quote(Box::new(d))
+    }
+}
+
+pub fn fold_node<F: Fold + ?Sized>(f: &mut F, node: Node) -> Node {
+    match node {
+        Node::Expr(e) => f.fold_expr(e),
+        Node::AppExpr(e) => f.fold_app_expr(*e),
+        Node::String(s) => f.fold_ident(s),
+        Node::Float(x) => f.fold_float(x),
+        Node::Integer(i) => f.fold_integer(i),
+        Node::LetExpr(e) => f.fold_let_expr(*e),
+        Node::LambdaExpr(e) => f.fold_lambda_expr(*e),
+        Node::CondExpr(e) => f.fold_cond_expr(*e),
+        Node::DefineExpr(e) => f.fold_define_expr(*e),
+        Node::Begin(body) => Node::Begin(body.into_iter().map(|n| f.fold_node(n)).collect()),
+        Node::Quote(d) => f.fold_quote(*d),
+        Node::Quasiquote(t) => Node::Quasiquote(Box::new(f.fold_template(*t))),
+    }
+}
+
+pub fn fold_expr<F: Fold + ?Sized>(f: &mut F, e: Expr) -> Node {
+    let op = f.fold_op(e.op);
+    let params = e.params.into_iter().map(|p| f.fold_node(p)).collect();
+    Node::Expr(Expr::new(op, params))
+}
+
+pub fn fold_app_expr<F: Fold + ?Sized>(f: &mut F, e: AppExpr) -> Node {
+    let op = f.fold_node(e.op);
+    let params = e.params.into_iter().map(|p| f.fold_node(p)).collect();
+    Node::AppExpr(Box::new(AppExpr { op, params }))
+}
+
+pub fn fold_let_expr<F: Fold + ?Sized>(f: &mut F, e: LetExpr) -> Node {
+    let kind = match e.kind {
+        LetKind::Named(name) => LetKind::Named(f.fold_binder(name)),
+        kind => kind,
+    };
+    let bindings = e
+        .bindings
+        .into_iter()
+        .map(|(name, val)| (f.fold_binder(name), f.fold_node(val)))
+        .collect();
+    let body = f.fold_node(e.body);
+    Node::LetExpr(Box::new(LetExpr {
+        kind,
+        bindings,
+        body,
+    }))
+}
+
+pub fn fold_lambda_expr<F: Fold + ?Sized>(f: &mut F, e: LambdaExpr) -> Node {
+    let params = e.params.into_iter().map(|p| f.fold_binder(p)).collect();
+    let body = f.fold_node(e.body);
+    Node::LambdaExpr(Box::new(LambdaExpr { params, body }))
+}
+
+pub fn fold_cond_expr<F: Fold + ?Sized>(f: &mut F, e: CondExpr) -> Node {
+    let clauses = e
+        .clauses
+        .into_iter()
+        .map(|(test, body)| (f.fold_node(test), f.fold_node(body)))
+        .collect();
+    let else_body = e.else_body.map(|body| f.fold_node(body));
+    Node::CondExpr(Box::new(CondExpr { clauses, else_body }))
+}
+
+pub fn fold_define_expr<F: Fold + ?Sized>(f: &mut F, e: DefineExpr) -> Node {
+    let name = f.fold_binder(e.name);
+    let params = e
+        .params
+        .map(|params| params.into_iter().map(|p| f.fold_binder(p)).collect());
+    let body = f.fold_node(e.body);
+    Node::DefineExpr(Box::new(DefineExpr { name, params, body }))
+}
+
+pub fn fold_template<F: Fold + ?Sized>(f: &mut F, t: Template) -> Template {
+    match t {
+        Template::Datum(d) => Template::Datum(d),
+        Template::Unquote(e) => Template::Unquote(f.fold_node(e)),
+        Template::UnquoteSplicing(e) => Template::UnquoteSplicing(f.fold_node(e)),
+        Template::List(items) => {
+            Template::List(items.into_iter().map(|t| f.fold_template(t)).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lexer::Lexer, parser::Parser};
+
+    use super::*;
+
+    /// Renames every identifier, bound or not
+    struct Prefix;
+
+    impl Fold for Prefix {
+        fn fold_ident(&mut self, name: String) -> Node {
+            Node::String(format!("p-{name}"))
+        }
+
+        fn fold_binder(&mut self, name: String) -> String {
+            format!("p-{name}")
+        }
+    }
+
+    #[test]
+    fn rebuilds_every_variant() {
+        let parse = |rkt: &str| Parser::parse(Lexer::lex(rkt.into()));
+        assert_eq!(
+            Prefix.fold_node(parse(
+                "(define (f x) (let loop ([y x]) (cond [y `(,y)] [else ((lambda (z) z) x)])))"
+            )),
+            parse(
+                "(define (p-f p-x) \
+                   (let p-loop ([p-y p-x]) (cond [p-y `(,p-y)] [else ((lambda (p-z) p-z) p-x)])))"
+            )
+        );
+    }
+}
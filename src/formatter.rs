@@ -0,0 +1,152 @@
+use crate::{
+    lexer::{Lexer, Token},
+    parser::Parser,
+    printer::{self, Brackets, Sexp},
+};
+
+/// Reformats source with the pretty-printer's indentation rules
+///
+/// Macros aren't expanded and comments are kept. Comments that end a line stay at the end of
+/// it, and runs of blank lines are collapsed to one.
+pub fn format(src: &str) -> String {
+    let mut reader = Reader {
+        data: Lexer::lex(src.into()),
+        ptr: 0,
+        hoisted: Vec::new(),
+    };
+    let mut out = String::new();
+    for item in reader.read_items(true) {
+        match item {
+            Sexp::Blank => out.push('\n'),
+            Sexp::Comment(c, true) => {
+                out.pop();
+                out.push_str(&format!(" {c}\n"));
+            }
+            item => {
+                out.push_str(&printer::layout(&item, 0, Brackets::default()));
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+/// Reads tokens into s-expressions, keeping the comments and blank lines between them
+struct Reader {
+    data: Vec<Token>,
+    ptr: usize,
+    /// Comments found between a quote and what it quotes, which are moved before the quote
+    hoisted: Vec<Sexp>,
+}
+
+impl Reader {
+    /// Reads the items of a list up to and including its closing bracket, or every top-level
+    /// form if `top` is set
+    fn read_items(&mut self, top: bool) -> Vec<Sexp> {
+        let mut items = Vec::new();
+        // Newlines since the last item. A comment is trailing if there are none, unless it
+        // starts the file.
+        let mut newlines = 0;
+        loop {
+            match self.data.get(self.ptr).unwrap_or(&Token::EOF) {
+                Token::EOF if top => break,
+                Token::EOF => panic!("expected a closing bracket"),
+                Token::RightParen | Token::RightBracket if !top => {
+                    self.ptr += 1;
+                    break;
+                }
+                Token::Newline => {
+                    self.ptr += 1;
+                    newlines += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            if newlines > 1 && !items.is_empty() {
+                items.push(Sexp::Blank);
+            }
+            if let Token::Comment(c) = &self.data[self.ptr] {
+                let trailing = newlines == 0 && !(top && items.is_empty());
+                items.push(Sexp::Comment(c.clone(), trailing));
+                self.ptr += 1;
+            } else {
+                let item = self.read_sexp();
+                items.append(&mut self.hoisted);
+                items.push(item);
+            }
+            newlines = 0;
+        }
+        if items.last() == Some(&Sexp::Blank) {
+            items.pop();
+        }
+        items
+    }
+
+    fn read_sexp(&mut self) -> Sexp {
+        self.ptr += 1;
+        match &self.data[self.ptr - 1] {
+            Token::LeftParen | Token::LeftBracket => Sexp::List(self.read_items(false)),
+            Token::Integer(i) => Sexp::Atom(i.to_string()),
+            Token::Float(f) => Sexp::Atom(printer::float(*f)),
+            Token::Str(s) => Sexp::Atom(printer::string(s)),
+            Token::Identifier(s) => Sexp::Atom(s.clone()),
+            t @ (Token::Quote | Token::Quasiquote | Token::Unquote | Token::UnquoteSplicing) => {
+                let name = Sexp::Atom(Parser::shorthand_name(t).into());
+                while self.data.get(self.ptr).is_some_and(Token::is_trivia) {
+                    if let Token::Comment(c) = &self.data[self.ptr] {
+                        self.hoisted.push(Sexp::Comment(c.clone(), false));
+                    }
+                    self.ptr += 1;
+                }
+                Sexp::List(vec![name, self.read_sexp()])
+            }
+            t => panic!("unexpected {t:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_comments_and_blank_lines() {
+        let src = "\
+; adds one
+(define (add1 x) ; trailing
+  ; before the body
+  (+ x 1))
+
+
+
+(let* [(x 1) (y 2)]   (add1 (+ x y)))
+";
+        let formatted = "\
+; adds one
+(define (add1 x) ; trailing
+  ; before the body
+  (+ x 1))
+
+(let* ([x 1] [y 2]) (add1 (+ x y)))
+";
+        assert_eq!(format(src), formatted);
+        assert_eq!(format(formatted), formatted);
+    }
+
+    #[test]
+    fn comments_break_lists() {
+        let src = "(f a ; why\n b '; hoisted\n c)";
+        assert_eq!(format(src), "(f a ; why\n   b\n   ; hoisted\n   'c)\n");
+    }
+
+    #[test]
+    fn leaves_macros_alone() {
+        let src = "\
+(define-syntax my-or
+  (syntax-rules ()
+    [(_) #f]
+    [(_ e r ...) (let ([tmp e]) (if tmp tmp (my-or r ...)))]))
+";
+        assert_eq!(format(src), src);
+    }
+}
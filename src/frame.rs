@@ -0,0 +1,230 @@
+use crate::{
+    compiler::Reg,
+    ir::{Function, VReg},
+    regalloc::{allocate, live_across, Loc},
+};
+
+/// The stack frame of a compiled function, and where each of its values lives
+///
+/// ```text
+/// rbp+8     return address
+/// rbp       caller's rbp
+/// rbp-8     root map
+/// rbp-16    callee-saved registers the function uses
+/// ...
+///           slots for locals that didn't get a register
+/// ...
+///           the closure being run, if it has captured values
+/// ...       padding to a multiple of 16 bytes
+/// ```
+///
+/// Because the frame below the return address is a multiple of 16 bytes, the stack is aligned
+/// for calls everywhere in the function's body. Before each call, and before each collection
+/// an allocation may start, the function points the root map at the list of words that hold
+/// values there, which is all the garbage collector looks at in the frame. See `gc.asm`.
+#[derive(Debug)]
+pub struct Frame {
+    locs: Vec<Option<Loc>>,
+    saved: Vec<Reg>,
+    slots: usize,
+    env: bool,
+    entry: bool,
+    /// The virtual registers that have to survive each instruction, by block
+    live: Vec<Vec<Vec<VReg>>>,
+}
+
+impl Frame {
+    pub fn new(func: &Function) -> Self {
+        let alloc = allocate(func);
+        Self {
+            locs: alloc.locs,
+            saved: alloc.saved,
+            slots: alloc.slots,
+            env: func.captures > 0,
+            entry: false,
+            live: live_across(func),
+        }
+    }
+
+    /// The frame of the function that runs the program, which is called from C
+    ///
+    /// It tells the garbage collector that the frames it looks at end at this one.
+    pub fn entry(func: &Function) -> Self {
+        Self {
+            entry: true,
+            ..Self::new(func)
+        }
+    }
+
+    /// Bytes the prologue takes off `rsp` after pushing `rbp`
+    pub fn size(&self) -> usize {
+        let words = 1 + self.saved.len() + self.slots + self.env as usize;
+        8 * words.next_multiple_of(2)
+    }
+
+    pub fn prologue(&self, label: &str) -> Vec<String> {
+        let mut lines = vec![
+            format!("{label}:"),
+            "push rbp".into(),
+            "mov rbp, rsp".into(),
+            format!("sub rsp, {}", self.size()),
+        ];
+        for (j, reg) in self.saved.iter().enumerate() {
+            lines.push(format!("mov {}, {reg:?}", self.word(j)));
+        }
+        if self.env {
+            lines.push(format!("mov {}, r10", self.env_slot()));
+        }
+        if self.entry {
+            lines.push("mov [base_frame], rbp".into());
+        }
+        lines
+    }
+
+    /// Returns to the caller, which gets the value in RAX
+    pub fn epilogue(&self) -> Vec<String> {
+        let mut lines: Vec<_> = self
+            .saved
+            .iter()
+            .enumerate()
+            .map(|(j, reg)| format!("mov {reg:?}, {}", self.word(j)))
+            .collect();
+        lines.push("mov rsp, rbp".into());
+        lines.push("pop rbp".into());
+        lines.push("ret".into());
+        lines
+    }
+
+    /// The location of `v`, or `None` if it is never used
+    pub fn loc(&self, v: VReg) -> Option<Loc> {
+        self.locs[v.0]
+    }
+
+    /// The operand for the location of `v`, or `None` if it is never used
+    pub fn operand(&self, v: VReg) -> Option<String> {
+        self.loc(v).map(|loc| match loc {
+            Loc::Reg(reg) => format!("{reg:?}"),
+            Loc::Stack(i) => self.slot(i),
+        })
+    }
+
+    /// The operand for the location of `v`, which has to be used
+    pub fn get(&self, v: VReg) -> String {
+        self.operand(v)
+            .unwrap_or_else(|| panic!("{v} is used but never given a location"))
+    }
+
+    pub fn env_slot(&self) -> String {
+        assert!(self.env, "the function has no captured values");
+        self.slot(self.slots)
+    }
+
+    /// The root map entries for instruction `inst` of block `block`: the offsets from `rbp` of
+    /// the slots, and the numbers of the registers, that hold values it has to keep
+    ///
+    /// Registers only hold such values at an allocation, since values that live across a call
+    /// are spilled.
+    pub fn roots(&self, block: usize, inst: usize) -> Vec<i64> {
+        let mut roots: Vec<_> = self.live[block][inst]
+            .iter()
+            .filter_map(|v| self.loc(*v))
+            .map(|loc| match loc {
+                Loc::Reg(reg) => reg.number(),
+                Loc::Stack(i) => self.offset(self.saved.len() + i),
+            })
+            .collect();
+        if self.env {
+            roots.push(self.offset(self.saved.len() + self.slots));
+        }
+        roots
+    }
+
+    fn slot(&self, i: usize) -> String {
+        self.word(self.saved.len() + i)
+    }
+
+    /// The operand for word `i` of the frame below the root map
+    fn word(&self, i: usize) -> String {
+        format!("qword [rbp{}]", self.offset(i))
+    }
+
+    fn offset(&self, i: usize) -> i64 {
+        -8 * (i as i64 + 2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ir::Module, lexer::Lexer, parser::Parser};
+
+    use super::*;
+
+    fn frames(rkt: &str) -> Vec<Frame> {
+        let module = Module::new(&Parser::parse(Lexer::lex(rkt.into())));
+        module.functions.iter().map(Frame::new).collect()
+    }
+
+    #[test]
+    fn aligned() {
+        for n in 0..16 {
+            let names: Vec<_> = (0..n).map(|i| format!("x{i}")).collect();
+            let bindings: Vec<_> = names.iter().map(|x| format!("[{x} 1]")).collect();
+            let rkt = format!(
+                "(let* ({}) (lambda () (list {})))",
+                bindings.join(" "),
+                names.join(" ")
+            );
+            for frame in frames(&rkt) {
+                let words = 1 + frame.saved.len() + frame.slots + frame.env as usize;
+                assert_eq!(frame.size() % 16, 0, "{frame:?}");
+                assert!(frame.size() >= 8 * words);
+            }
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let frame = Frame {
+            locs: vec![
+                Some(Loc::Stack(0)),
+                Some(Loc::Stack(1)),
+                Some(Loc::Reg(Reg::RSI)),
+            ],
+            saved: vec![Reg::RBX],
+            slots: 2,
+            env: true,
+            entry: false,
+            live: vec![vec![vec![VReg(1), VReg(2)]]],
+        };
+        assert_eq!(frame.get(VReg(0)), "qword [rbp-24]");
+        assert_eq!(frame.get(VReg(1)), "qword [rbp-32]");
+        assert_eq!(frame.env_slot(), "qword [rbp-40]");
+        assert_eq!(frame.roots(0, 0), [-32, 6, -40]);
+        assert_eq!(
+            frame.prologue("f"),
+            [
+                "f:",
+                "push rbp",
+                "mov rbp, rsp",
+                "sub rsp, 48",
+                "mov qword [rbp-16], RBX",
+                "mov qword [rbp-40], r10"
+            ]
+        );
+        assert_eq!(
+            frame.epilogue(),
+            ["mov RBX, qword [rbp-16]", "mov rsp, rbp", "pop rbp", "ret"]
+        );
+    }
+
+    #[test]
+    fn entry() {
+        let module = Module::new(&Parser::parse(Lexer::lex("(+ 1 2)".into())));
+        let frame = Frame::entry(&module.functions[0]);
+        let prologue = frame.prologue("main");
+        assert_eq!(prologue[..3], ["main:", "push rbp", "mov rbp, rsp"]);
+        assert!(prologue.contains(&"mov [base_frame], rbp".to_string()));
+        assert!(frame.saved.is_empty());
+        assert_eq!(frame.epilogue()[0], "mov rsp, rbp");
+    }
+}
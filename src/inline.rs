@@ -0,0 +1,254 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::ir::{Block, BlockId, FuncId, Function, Inst, Module, Terminator, VReg};
+
+/// What a register is known to hold: a closure of a function over some captured values
+type Known = BTreeMap<VReg, (FuncId, Vec<VReg>)>;
+
+/// Replaces calls of known functions of at most `threshold` instructions with their body
+///
+/// A call is of a known function if the closure called was made in the same block, like an
+/// immediately applied lambda, or in a register that is only assigned once, like most bindings
+/// of lambdas. Recursive calls go through a cell, so they are never inlined, and neither is a
+/// call in code that was inlined from the function being called, like a lambda that is passed
+/// itself. The closures left unused are removed by dead code elimination.
+pub fn inline(module: &mut Module, threshold: usize) {
+    for f in 0..module.functions.len() {
+        // The functions each block's code was inlined from, including `f` itself
+        let mut origins = vec![BTreeSet::from([FuncId(f)]); module.functions[f].blocks.len()];
+        // Inlining can make more closures known, like the one a function returns
+        while inline_calls(module, f, threshold, &mut origins) {}
+    }
+}
+
+/// Inlines the calls of known functions in function `f`, returning whether there were any
+fn inline_calls(
+    module: &mut Module,
+    f: usize,
+    threshold: usize,
+    origins: &mut Vec<BTreeSet<FuncId>>,
+) -> bool {
+    let global = known_everywhere(&module.functions[f]);
+    // What is known at the start of the blocks made by inlining
+    let mut entry: BTreeMap<BlockId, Known> = BTreeMap::new();
+    let mut inlined = false;
+    let mut b = 0;
+    while b < module.functions[f].blocks.len() {
+        let mut known = entry.remove(&BlockId(b)).unwrap_or_else(|| global.clone());
+        let block = &module.functions[f].blocks[b];
+        for (i, inst) in block.insts.iter().enumerate() {
+            if let Inst::Apply(d, closure, args) = inst {
+                if let Some((g, captured)) = known.get(closure) {
+                    let callee = &module.functions[g.0];
+                    let size: usize = callee.blocks.iter().map(|b| b.insts.len()).sum();
+                    if !origins[b].contains(g)
+                        && callee.params.len() == args.len()
+                        && size <= threshold
+                    {
+                        let mut inner = origins[b].clone();
+                        inner.insert(*g);
+                        origins.extend(vec![inner; callee.blocks.len()]);
+                        origins.push(origins[b].clone());
+                        let callee = callee.clone();
+                        let (d, args, captured) = (*d, args.clone(), captured.clone());
+                        let caller = &mut module.functions[f];
+                        let first = caller.blocks.len();
+                        splice(caller, BlockId(b), i, d, &args, &captured, &callee);
+                        inlined = true;
+                        forget(&mut known, d);
+                        for new in first..caller.blocks.len() {
+                            entry.insert(BlockId(new), known.clone());
+                        }
+                        break;
+                    }
+                }
+            }
+            learn(&mut known, inst);
+        }
+        b += 1;
+    }
+    inlined
+}
+
+/// Updates what is known after an instruction
+fn learn(known: &mut Known, inst: &Inst) {
+    let Some(d) = inst.def() else {
+        return;
+    };
+    let value = match inst {
+        Inst::Closure(_, g, captured) => Some((*g, captured.clone())),
+        Inst::Move(_, s) => known.get(s).cloned(),
+        _ => None,
+    };
+    forget(known, d);
+    if let Some(value) = value {
+        known.insert(d, value);
+    }
+}
+
+/// Forgets what was known about a register that is being assigned, and about closures that
+/// captured it
+fn forget(known: &mut Known, v: VReg) {
+    known.retain(|r, (_, captured)| *r != v && !captured.contains(&v));
+}
+
+/// Finds the registers that hold the same closure everywhere they are used, because they are
+/// assigned once
+fn known_everywhere(func: &Function) -> Known {
+    let mut defs = vec![0; func.vregs];
+    for param in &func.params {
+        defs[param.0] += 1;
+    }
+    let insts = || func.blocks.iter().flat_map(|b| &b.insts);
+    for inst in insts() {
+        if let Some(d) = inst.def() {
+            defs[d.0] += 1;
+        }
+    }
+    let mut known = Known::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for inst in insts() {
+            let value = match inst {
+                Inst::Closure(d, g, captured) if captured.iter().all(|c| defs[c.0] == 1) => {
+                    Some((*d, (*g, captured.clone())))
+                }
+                Inst::Move(d, s) => known.get(s).map(|value| (*d, value.clone())),
+                _ => None,
+            };
+            if let Some((d, value)) = value {
+                if defs[d.0] == 1 && !known.contains_key(&d) {
+                    known.insert(d, value);
+                    changed = true;
+                }
+            }
+        }
+    }
+    known
+}
+
+/// Replaces instruction `i` of block `b`, which applies a closure of `callee` and puts the
+/// result in `d`, with a copy of the callee's body
+///
+/// The instructions after the call are moved to a new block, which each return of the copied
+/// body jumps to. The copied blocks and that block go at the end of the function.
+fn splice(
+    caller: &mut Function,
+    b: BlockId,
+    i: usize,
+    d: VReg,
+    args: &[VReg],
+    captured: &[VReg],
+    callee: &Function,
+) {
+    let offset = caller.vregs;
+    caller.vregs += callee.vregs;
+    let reg = |v: VReg| VReg(v.0 + offset);
+    let first = caller.blocks.len();
+    let block = |b: BlockId| BlockId(b.0 + first);
+    let after = BlockId(first + callee.blocks.len());
+
+    let call = &mut caller.blocks[b.0];
+    let rest = call.insts.split_off(i + 1);
+    call.insts.pop();
+    for (param, arg) in callee.params.iter().zip(args) {
+        call.insts.push(Inst::Move(reg(*param), *arg));
+    }
+    let term = std::mem::replace(&mut call.term, Terminator::Jump(block(BlockId(0))));
+
+    for body in &callee.blocks {
+        let mut insts: Vec<_> = body
+            .insts
+            .iter()
+            .map(|inst| match inst {
+                Inst::Captured(v, n) => Inst::Move(reg(*v), captured[*n]),
+                inst => rename(inst, reg),
+            })
+            .collect();
+        let term = match &body.term {
+            Terminator::Jump(t) => Terminator::Jump(block(*t)),
+            Terminator::Branch(c, t, e) => Terminator::Branch(reg(*c), block(*t), block(*e)),
+            Terminator::Return(v) => {
+                insts.push(Inst::Move(d, reg(*v)));
+                Terminator::Jump(after)
+            }
+        };
+        caller.blocks.push(Block { insts, term });
+    }
+    caller.blocks.push(Block { insts: rest, term });
+}
+
+/// Renames every register an instruction reads or writes
+fn rename(inst: &Inst, reg: impl Fn(VReg) -> VReg) -> Inst {
+    let regs = |vs: &[VReg]| vs.iter().map(|v| reg(*v)).collect();
+    match inst {
+        Inst::Lit(d, lit) => Inst::Lit(reg(*d), lit.clone()),
+        Inst::Move(d, s) => Inst::Move(reg(*d), reg(*s)),
+        Inst::Call(d, name, args) => Inst::Call(reg(*d), name.clone(), regs(args)),
+        Inst::CallStack(d, name, args) => Inst::CallStack(reg(*d), name.clone(), regs(args)),
+        Inst::Closure(d, g, captured) => Inst::Closure(reg(*d), *g, regs(captured)),
+        Inst::Apply(d, f, args) => Inst::Apply(reg(*d), reg(*f), regs(args)),
+        Inst::Captured(d, n) => Inst::Captured(reg(*d), *n),
+        Inst::NewCell(d, v) => Inst::NewCell(reg(*d), reg(*v)),
+        Inst::CellGet(d, c) => Inst::CellGet(reg(*d), reg(*c)),
+        Inst::CellSet(c, v) => Inst::CellSet(reg(*c), reg(*v)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        constfold::fold_constants, dce::eliminate_dead_code, lexer::Lexer, parser::Parser,
+    };
+
+    use super::*;
+
+    fn optimized(rkt: &str, threshold: usize) -> String {
+        let mut module = Module::new(&Parser::parse(Lexer::lex(rkt.into())));
+        inline(&mut module, threshold);
+        fold_constants(&mut module);
+        eliminate_dead_code(&mut module);
+        module.to_string()
+    }
+
+    #[test]
+    fn known_functions() {
+        let rkt = "(define (add1 x) (+ x 1)) (let ([y 2]) (add1 (add1 y)))";
+        assert_eq!(
+            optimized(rkt, 10),
+            "f0():\nb0:\n    v14 = 4\n    v8 = v14\n    return v8\n"
+        );
+        assert_eq!(optimized(rkt, 1).matches("apply").count(), 2);
+    }
+
+    #[test]
+    fn applied_lambdas() {
+        let rkt = "((lambda (x) (+ x 1)) 5)";
+        assert_eq!(
+            optimized(rkt, 10),
+            "f0():\nb0:\n    v5 = 6\n    v2 = v5\n    return v2\n"
+        );
+    }
+
+    #[test]
+    fn captured_values() {
+        let rkt = "(let* ([n 3] [add (lambda (x) (+ x n))]) (add 4))";
+        assert!(optimized(rkt, 10).contains("= 7\n"));
+        let rkt = "(define (adder n) (lambda (x) (+ x n))) (let ([add3 (adder 3)]) (add3 4))";
+        assert!(optimized(rkt, 10).contains("= 7\n"));
+    }
+
+    #[test]
+    fn not_recursive_functions() {
+        let rkt = "(define (fact n) (if (= n 0) 1 (* n (fact (- n 1))))) (fact 5)";
+        assert_eq!(optimized(rkt, 100), optimized(rkt, 0));
+    }
+
+    #[test]
+    fn self_application() {
+        // Inlining `f` into itself through `g` would never end
+        let rkt = "(let ([f (lambda (g n) (if (= n 0) 0 (g g (- n 1))))]) (f f 3))";
+        assert_eq!(optimized(rkt, 100).matches("apply").count(), 2);
+    }
+}
@@ -0,0 +1,306 @@
+use std::fmt;
+
+use crate::{desugar::desugar, lower::lower, parser::Node, printer};
+
+/// A virtual register. Every value an instruction makes goes in one; the backend decides where
+/// each one really lives.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct VReg(pub usize);
+
+/// Index of a basic block in its function
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct BlockId(pub usize);
+
+/// Index of a function in its module
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct FuncId(pub usize);
+
+/// A constant an instruction can make
+#[derive(Debug, PartialEq, Clone)]
+pub enum Lit {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Symbol(String),
+    Str(String),
+    Empty,
+    Void,
+}
+
+/// Three-address code. Each instruction writes at most one register, named first.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Inst {
+    Lit(VReg, Lit),
+    Move(VReg, VReg),
+    /// Calls a runtime function, passing the arguments in registers
+    Call(VReg, String, Vec<VReg>),
+    /// Calls a variadic runtime function like `list`, passing the number of arguments in a
+    /// register and the arguments on the stack
+    CallStack(VReg, String, Vec<VReg>),
+    /// Makes a closure of a function over the values it captures
+    Closure(VReg, FuncId, Vec<VReg>),
+    /// Calls the closure in the second register
+    Apply(VReg, VReg, Vec<VReg>),
+    /// Reads a value captured by the closure being run
+    Captured(VReg, usize),
+    /// Puts a value in a new mutable cell, for variables that closures capture and `set!`
+    NewCell(VReg, VReg),
+    /// Reads the value in a cell
+    CellGet(VReg, VReg),
+    /// Writes the second register into the cell in the first
+    CellSet(VReg, VReg),
+}
+
+impl Inst {
+    /// The register the instruction writes, if any
+    pub fn def(&self) -> Option<VReg> {
+        match self {
+            Inst::Lit(d, _)
+            | Inst::Move(d, _)
+            | Inst::Call(d, ..)
+            | Inst::CallStack(d, ..)
+            | Inst::Closure(d, ..)
+            | Inst::Apply(d, ..)
+            | Inst::Captured(d, _)
+            | Inst::NewCell(d, _)
+            | Inst::CellGet(d, _) => Some(*d),
+            Inst::CellSet(..) => None,
+        }
+    }
+
+    /// The registers the instruction reads, in order
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Inst::Lit(..) | Inst::Captured(..) => vec![],
+            Inst::Move(_, s) | Inst::NewCell(_, s) | Inst::CellGet(_, s) => vec![*s],
+            Inst::Call(_, _, args) | Inst::CallStack(_, _, args) | Inst::Closure(_, _, args) => {
+                args.clone()
+            }
+            Inst::Apply(_, f, args) => [*f].into_iter().chain(args.iter().copied()).collect(),
+            Inst::CellSet(c, v) => vec![*c, *v],
+        }
+    }
+}
+
+/// How a block ends
+#[derive(Debug, PartialEq, Clone)]
+pub enum Terminator {
+    Jump(BlockId),
+    /// Goes to the first block unless the register holds #f
+    Branch(VReg, BlockId, BlockId),
+    Return(VReg),
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(b) => vec![*b],
+            Terminator::Branch(_, t, f) => vec![*t, *f],
+            Terminator::Return(_) => vec![],
+        }
+    }
+
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Terminator::Jump(_) => vec![],
+            Terminator::Branch(c, ..) | Terminator::Return(c) => vec![*c],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Block {
+    pub insts: Vec<Inst>,
+    pub term: Terminator,
+}
+
+/// A procedure. Control starts at the first block.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Function {
+    pub params: Vec<VReg>,
+    /// Number of values the function's closures capture
+    pub captures: usize,
+    pub blocks: Vec<Block>,
+    /// Number of virtual registers used, which are numbered from 0
+    pub vregs: usize,
+}
+
+/// A whole program. The first function is the one that runs it, and takes no parameters.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Module {
+    pub functions: Vec<Function>,
+}
+
+impl Module {
+    /// Lowers a program to IR
+    pub fn new(node: &Node) -> Self {
+        lower(&desugar(node))
+    }
+}
+
+impl fmt::Display for VReg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "b{}", self.0)
+    }
+}
+
+impl fmt::Display for FuncId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "f{}", self.0)
+    }
+}
+
+fn list(regs: &[VReg]) -> String {
+    let regs: Vec<_> = regs.iter().map(|r| r.to_string()).collect();
+    regs.join(", ")
+}
+
+impl fmt::Display for Lit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Lit::Int(i) => write!(f, "{i}"),
+            Lit::Float(x) => write!(f, "{x:?}"),
+            Lit::Bool(b) => write!(f, "{}", if *b { "#t" } else { "#f" }),
+            Lit::Symbol(s) => write!(f, "'{s}"),
+            Lit::Str(s) => write!(f, "{}", printer::string(s)),
+            Lit::Empty => write!(f, "'()"),
+            Lit::Void => write!(f, "#<void>"),
+        }
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inst::Lit(d, lit) => write!(f, "{d} = {lit}"),
+            Inst::Move(d, s) => write!(f, "{d} = {s}"),
+            Inst::Call(d, name, args) => write!(f, "{d} = call {name}({})", list(args)),
+            Inst::CallStack(d, name, args) => write!(f, "{d} = call* {name}({})", list(args)),
+            Inst::Closure(d, func, caps) => write!(f, "{d} = closure {func}({})", list(caps)),
+            Inst::Apply(d, func, args) => write!(f, "{d} = apply {func}({})", list(args)),
+            Inst::Captured(d, i) => write!(f, "{d} = captured {i}"),
+            Inst::NewCell(d, s) => write!(f, "{d} = cell {s}"),
+            Inst::CellGet(d, c) => write!(f, "{d} = *{c}"),
+            Inst::CellSet(c, s) => write!(f, "*{c} = {s}"),
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Terminator::Jump(b) => write!(f, "jump {b}"),
+            Terminator::Branch(c, t, e) => write!(f, "branch {c} {t} {e}"),
+            Terminator::Return(v) => write!(f, "return {v}"),
+        }
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, func) in self.functions.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}({})", FuncId(i), list(&func.params))?;
+            if func.captures > 0 {
+                write!(f, " captures {}", func.captures)?;
+            }
+            writeln!(f, ":")?;
+            for (b, block) in func.blocks.iter().enumerate() {
+                writeln!(f, "{}:", BlockId(b))?;
+                for inst in &block.insts {
+                    writeln!(f, "    {inst}")?;
+                }
+                writeln!(f, "    {}", block.term)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lexer::Lexer, parser::Parser};
+
+    use super::*;
+
+    fn ir(rkt: &str) -> String {
+        Module::new(&Parser::parse(Lexer::lex(rkt.into()))).to_string()
+    }
+
+    #[test]
+    fn branches() {
+        assert_eq!(
+            ir("(let ([x 1]) (if (= x 2) 'a (list x 2.5)))"),
+            "\
+f0():
+b0:
+    v0 = 1
+    v1 = 2
+    v2 = call eq(v0, v1)
+    branch v2 b1 b2
+b1:
+    v4 = 'a
+    v3 = v4
+    jump b3
+b2:
+    v5 = 2.5
+    v6 = call* list(v0, v5)
+    v3 = v6
+    jump b3
+b3:
+    return v3
+"
+        );
+    }
+
+    #[test]
+    fn closures() {
+        assert_eq!(
+            ir("(define (count) (set! n (+ n 1)) n) (define n 0) (count)"),
+            "\
+f0():
+b0:
+    v0 = #f
+    v1 = #f
+    v2 = v0
+    v3 = cell v1
+    v4 = closure f1(v3)
+    v2 = v4
+    v5 = #<void>
+    v6 = 0
+    *v3 = v6
+    v7 = #<void>
+    v8 = v2
+    v9 = apply v8()
+    return v9
+
+f1() captures 1:
+b0:
+    v0 = captured 0
+    v1 = *v0
+    v2 = 1
+    v3 = call madd(v1, v2)
+    v4 = captured 0
+    *v4 = v3
+    v5 = #<void>
+    v6 = captured 0
+    v7 = *v6
+    return v7
+"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "frobnicate: unbound identifier")]
+    fn unbound_identifiers() {
+        ir("(let ([x 1]) (frobnicate x))");
+    }
+}
@@ -1,27 +1,38 @@
 #[derive(Debug, PartialEq, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Token {
     LeftParen,
     RightParen,
     LeftBracket,
     RightBracket,
+    /// `'`
+    Quote,
+    /// `` ` ``
+    Quasiquote,
+    /// `,`
+    Unquote,
+    /// `,@`
+    UnquoteSplicing,
+    /// A `;` comment, up to the end of its line
+    Comment(String),
+    Newline,
     Identifier(String),
+    /// A string literal, with its escapes replaced by the characters they stand for
+    Str(String),
     Integer(i64),
     Float(f64),
     EOF,
 }
 
 impl Token {
-    pub fn inner_ident(self) -> String {
-        if let Token::Identifier(s) = self {
-            s
-        } else {
-            panic!("expected identifier, got {self:?}");
-        }
+    /// Whether the token only matters for formatting, so the parser can skip it
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, Token::Comment(_) | Token::Newline)
     }
 }
 
 pub struct Lexer {
-    src: String,
+    src: Vec<char>,
     ptr: usize,
     tokens: Vec<Token>,
 }
@@ -29,7 +40,7 @@ pub struct Lexer {
 impl Lexer {
     pub fn lex(src: String) -> Vec<Token> {
         let mut lexer = Lexer {
-            src,
+            src: src.chars().collect(),
             ptr: 0,
             tokens: Vec::new(),
         };
@@ -51,7 +62,21 @@ impl Lexer {
             ')' => Token::RightParen,
             '[' => Token::LeftBracket,
             ']' => Token::RightBracket,
-            ' ' | '\n' => self.scan_token(),
+            '\'' => Token::Quote,
+            '`' => Token::Quasiquote,
+            ',' => {
+                if self.ptr < self.src.len() && self.peek() == '@' {
+                    self.advance();
+                    Token::UnquoteSplicing
+                } else {
+                    Token::Unquote
+                }
+            }
+            ';' => self.comment(),
+            '"' => self.string(),
+            '\n' => Token::Newline,
+            c if c.is_whitespace() => self.scan_token(),
+            '-' if self.ptr < self.src.len() && self.peek().is_numeric() => self.number(),
             c => {
                 if c.is_numeric() {
                     self.number()
@@ -63,21 +88,21 @@ impl Lexer {
     }
 
     fn peek(&self) -> char {
-        self.src.chars().nth(self.ptr).unwrap()
+        self.src[self.ptr]
     }
 
     fn advance(&mut self) -> char {
         self.ptr += 1;
-        self.src.chars().nth(self.ptr - 1).unwrap()
+        self.src[self.ptr - 1]
     }
 
     fn number(&mut self) -> Token {
         let start = self.ptr - 1;
-        while self.peek().is_numeric() || self.peek() == '.' {
+        while self.ptr < self.src.len() && (self.peek().is_numeric() || self.peek() == '.') {
             self.advance();
         }
         // self.advance();
-        let substr = &self.src[start..self.ptr];
+        let substr: String = self.src[start..self.ptr].iter().collect();
         if substr.contains('.') {
             Token::Float(substr.parse().unwrap())
         } else {
@@ -87,10 +112,38 @@ impl Lexer {
 
     fn identifier(&mut self) -> Token {
         let start = self.ptr - 1;
-        while !self.peek().is_whitespace() && self.peek() != ')' {
+        while self.ptr < self.src.len()
+            && !self.peek().is_whitespace()
+            && !matches!(self.peek(), '(' | ')' | '[' | ']')
+        {
             self.advance();
         }
-        Token::Identifier(self.src[start..self.ptr].to_string())
+        Token::Identifier(self.src[start..self.ptr].iter().collect())
+    }
+
+    fn string(&mut self) -> Token {
+        let mut text = String::new();
+        loop {
+            assert!(self.ptr < self.src.len(), "unterminated string");
+            match self.advance() {
+                '"' => return Token::Str(text),
+                '\\' if self.ptr < self.src.len() => text.push(match self.advance() {
+                    'n' => '\n',
+                    't' => '\t',
+                    c => c,
+                }),
+                c => text.push(c),
+            }
+        }
+    }
+
+    fn comment(&mut self) -> Token {
+        let start = self.ptr - 1;
+        while self.ptr < self.src.len() && self.peek() != '\n' {
+            self.advance();
+        }
+        let text: String = self.src[start..self.ptr].iter().collect();
+        Token::Comment(text.trim_end().into())
     }
 }
 
@@ -125,4 +178,74 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn quote_shorthand() {
+        let toks = Lexer::lex(String::from("`(a ,b ,@c '(d))"));
+        assert_eq!(
+            toks,
+            vec![
+                Token::Quasiquote,
+                Token::LeftParen,
+                Token::Identifier("a".into()),
+                Token::Unquote,
+                Token::Identifier("b".into()),
+                Token::UnquoteSplicing,
+                Token::Identifier("c".into()),
+                Token::Quote,
+                Token::LeftParen,
+                Token::Identifier("d".into()),
+                Token::RightParen,
+                Token::RightParen,
+            ]
+        );
+    }
+
+    #[test]
+    fn negative_numbers() {
+        let toks = Lexer::lex(String::from("(- -1 -2.5)"));
+        assert_eq!(
+            toks,
+            vec![
+                Token::LeftParen,
+                Token::Identifier("-".into()),
+                Token::Integer(-1),
+                Token::Float(-2.5),
+                Token::RightParen,
+            ]
+        );
+    }
+
+    #[test]
+    fn strings() {
+        let toks = Lexer::lex(String::from(r#"(f "a (b)" "\"c\"\n")"#));
+        assert_eq!(
+            toks,
+            vec![
+                Token::LeftParen,
+                Token::Identifier("f".into()),
+                Token::Str("a (b)".into()),
+                Token::Str("\"c\"\n".into()),
+                Token::RightParen,
+            ]
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let toks = Lexer::lex(String::from("; café\n(f x) ; trailing  \n"));
+        assert_eq!(
+            toks,
+            vec![
+                Token::Comment("; café".into()),
+                Token::Newline,
+                Token::LeftParen,
+                Token::Identifier("f".into()),
+                Token::Identifier("x".into()),
+                Token::RightParen,
+                Token::Comment("; trailing".into()),
+                Token::Newline,
+            ]
+        );
+    }
 }
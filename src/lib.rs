@@ -1,8 +1,25 @@
 mod compiler;
+mod constfold;
+mod dce;
+mod desugar;
+mod expander;
+pub mod fold;
+mod formatter;
+mod frame;
+mod inline;
+pub mod ir;
 mod lexer;
+mod lower;
 mod parser;
+pub mod passes;
+pub mod printer;
+mod regalloc;
+pub mod visit;
 mod writer;
 
-pub use compiler::Compiler;
+pub use compiler::{Compiler, Const, Options};
+pub use formatter::format;
 pub use lexer::Lexer;
-pub use parser::Parser;
+pub use parser::{
+    CondExpr, Datum, DefineExpr, Expr, LambdaExpr, LetExpr, LetKind, Node, Parser, Template,
+};
@@ -0,0 +1,453 @@
+use std::collections::{BTreeSet, HashSet};
+
+use crate::{
+    desugar::{Core, IGNORED},
+    ir::{Block, BlockId, FuncId, Function, Inst, Lit, Module, Terminator, VReg},
+    parser::Datum,
+};
+
+/// Lowers a program in the core language to IR. Each lambda becomes a function of its own.
+pub fn lower(core: &Core) -> Module {
+    let mut assigned = HashSet::new();
+    let mut captured = HashSet::new();
+    scan(core, &mut assigned, &mut captured);
+    let cells = assigned.intersection(&captured).cloned().collect();
+    let mut lowerer = Lowerer {
+        functions: Vec::new(),
+        assigned,
+        cells,
+    };
+    lowerer.function(&[], &[], core);
+    Module {
+        functions: lowerer.functions.into_iter().map(Option::unwrap).collect(),
+    }
+}
+
+/// Finds the names of variables that are assigned with `set!`, and of those captured by some
+/// lambda
+fn scan(c: &Core, assigned: &mut HashSet<String>, captured: &mut HashSet<String>) {
+    match c {
+        Core::Set(name, val) => {
+            assigned.insert(name.clone());
+            scan(val, assigned, captured);
+        }
+        Core::Lambda(params, body) => {
+            let mut free = BTreeSet::new();
+            free_vars(body, &mut params.clone(), &mut free);
+            captured.extend(free);
+            scan(body, assigned, captured);
+        }
+        Core::If(cond, t, e) => {
+            for c in [cond, t, e] {
+                scan(c, assigned, captured);
+            }
+        }
+        Core::App(f, args) => {
+            scan(f, assigned, captured);
+            for arg in args {
+                scan(arg, assigned, captured);
+            }
+        }
+        Core::Let(bindings, body) => {
+            for (_, val) in bindings {
+                scan(val, assigned, captured);
+            }
+            scan(body, assigned, captured);
+        }
+        Core::Integer(_)
+        | Core::Float(_)
+        | Core::Bool(_)
+        | Core::Void
+        | Core::Quote(_)
+        | Core::Var(_) => {}
+    }
+}
+
+/// Adds the names `c` uses without binding them to `out`, besides those in `bound`
+fn free_vars(c: &Core, bound: &mut Vec<String>, out: &mut BTreeSet<String>) {
+    match c {
+        Core::Var(name) | Core::Set(name, _) if !bound.contains(name) => {
+            out.insert(name.clone());
+        }
+        _ => {}
+    }
+    match c {
+        Core::Set(_, val) => free_vars(val, bound, out),
+        Core::Lambda(params, body) => {
+            let depth = bound.len();
+            bound.extend(params.iter().cloned());
+            free_vars(body, bound, out);
+            bound.truncate(depth);
+        }
+        Core::If(cond, t, e) => {
+            for c in [cond, t, e] {
+                free_vars(c, bound, out);
+            }
+        }
+        Core::App(f, args) => {
+            free_vars(f, bound, out);
+            for arg in args {
+                free_vars(arg, bound, out);
+            }
+        }
+        Core::Let(bindings, body) => {
+            for (_, val) in bindings {
+                free_vars(val, bound, out);
+            }
+            let depth = bound.len();
+            bound.extend(bindings.iter().map(|(name, _)| name.clone()));
+            free_vars(body, bound, out);
+            bound.truncate(depth);
+        }
+        Core::Integer(_)
+        | Core::Float(_)
+        | Core::Bool(_)
+        | Core::Void
+        | Core::Quote(_)
+        | Core::Var(_) => {}
+    }
+}
+
+/// Where the value of a variable is kept
+#[derive(Clone, Copy)]
+enum Var {
+    Reg(VReg),
+    /// In the cell whose address is in the register
+    Cell(VReg),
+    /// Captured by the closure being run
+    Captured(usize),
+    /// In a cell whose address the closure being run captured
+    CapturedCell(usize),
+}
+
+struct Lowerer {
+    /// Functions made so far. A function's slot is taken before its body is lowered, so the
+    /// functions of its lambdas come after it.
+    functions: Vec<Option<Function>>,
+    /// Names of variables assigned anywhere in the program
+    assigned: HashSet<String>,
+    /// Names of variables that are both assigned and captured, which are kept in cells so every
+    /// closure sees the same value
+    cells: HashSet<String>,
+}
+
+impl Lowerer {
+    /// Lowers a lambda. `captured` lists the variables its closures capture, and whether each
+    /// one is a cell.
+    fn function(&mut self, params: &[String], captured: &[(String, bool)], body: &Core) -> FuncId {
+        let id = FuncId(self.functions.len());
+        self.functions.push(None);
+
+        let mut b = Builder::default();
+        b.new_block();
+        for (i, (name, cell)) in captured.iter().enumerate() {
+            let var = if *cell {
+                Var::CapturedCell(i)
+            } else {
+                Var::Captured(i)
+            };
+            b.scope.push((name.clone(), var));
+        }
+        let regs: Vec<_> = params.iter().map(|_| b.fresh()).collect();
+        for (name, reg) in params.iter().zip(&regs) {
+            self.bind(&mut b, name, *reg);
+        }
+        let out = self.expr(&mut b, body);
+        b.terminate(Terminator::Return(out));
+
+        self.functions[id.0] = Some(b.finish(regs, captured.len()));
+        id
+    }
+
+    /// Binds `name` to the value in `reg` in the innermost scope
+    fn bind(&self, b: &mut Builder, name: &str, reg: VReg) {
+        let var = if self.cells.contains(name) {
+            Var::Cell(b.emit_with(|out| Inst::NewCell(out, reg)))
+        } else if self.assigned.contains(name) {
+            // The register may belong to another variable, so assigning this one can't use it
+            Var::Reg(b.emit_with(|out| Inst::Move(out, reg)))
+        } else {
+            Var::Reg(reg)
+        };
+        b.scope.push((name.into(), var));
+    }
+
+    fn expr(&mut self, b: &mut Builder, c: &Core) -> VReg {
+        match c {
+            Core::Integer(i) => b.int(*i),
+            Core::Float(f) => b.lit(Lit::Float(*f)),
+            Core::Bool(v) => b.lit(Lit::Bool(*v)),
+            Core::Void => b.lit(Lit::Void),
+            Core::Quote(d) => self.quote(b, d),
+            Core::Var(name) => self.var(b, name),
+            Core::If(cond, t, e) => {
+                let cond = self.expr(b, cond);
+                let (then_block, else_block, done) = (b.new_block(), b.new_block(), b.new_block());
+                b.terminate(Terminator::Branch(cond, then_block, else_block));
+                // Both branches leave their value in the same register
+                let out = b.fresh();
+                for (block, branch) in [(then_block, t), (else_block, e)] {
+                    b.switch_to(block);
+                    let val = self.expr(b, branch);
+                    b.emit(Inst::Move(out, val));
+                    b.terminate(Terminator::Jump(done));
+                }
+                b.switch_to(done);
+                out
+            }
+            Core::Let(bindings, body) => {
+                // The values can't see each other, so all of them are lowered before binding
+                // any names
+                let vals: Vec<_> = bindings
+                    .iter()
+                    .map(|(name, val)| (name, self.expr(b, val)))
+                    .collect();
+                let depth = b.scope.len();
+                for (name, val) in vals {
+                    if name != IGNORED {
+                        self.bind(b, name, val);
+                    }
+                }
+                let out = self.expr(b, body);
+                b.scope.truncate(depth);
+                out
+            }
+            Core::Set(name, val) => {
+                let val = self.expr(b, val);
+                match b.lookup(name) {
+                    Some(Var::Reg(reg)) => b.emit(Inst::Move(reg, val)),
+                    Some(Var::Cell(cell)) => b.emit(Inst::CellSet(cell, val)),
+                    Some(Var::CapturedCell(i)) => {
+                        let cell = b.emit_with(|out| Inst::Captured(out, i));
+                        b.emit(Inst::CellSet(cell, val));
+                    }
+                    Some(Var::Captured(_)) => unreachable!("{name}: assigned but not in a cell"),
+                    None => panic!("set!: {name}: unbound identifier"),
+                }
+                b.lit(Lit::Void)
+            }
+            Core::Lambda(params, body) => {
+                let mut free = BTreeSet::new();
+                free_vars(body, &mut params.clone(), &mut free);
+                // Free names that aren't variables are builtins
+                let captured: Vec<_> = free
+                    .into_iter()
+                    .filter_map(|name| b.lookup(&name).map(|var| (name, var)))
+                    .collect();
+                let values = captured.iter().map(|(_, var)| b.capture(*var)).collect();
+                let captured: Vec<_> = captured
+                    .into_iter()
+                    .map(|(name, var)| (name, matches!(var, Var::Cell(_) | Var::CapturedCell(_))))
+                    .collect();
+                let func = self.function(params, &captured, body);
+                b.emit_with(|out| Inst::Closure(out, func, values))
+            }
+            Core::App(f, args) => match &**f {
+                Core::Var(op) if b.lookup(op).is_none() => self.builtin(b, op, args),
+                f => {
+                    let f = self.expr(b, f);
+                    let args = args.iter().map(|arg| self.expr(b, arg)).collect();
+                    b.emit_with(|out| Inst::Apply(out, f, args))
+                }
+            },
+        }
+    }
+
+    fn var(&mut self, b: &mut Builder, name: &str) -> VReg {
+        let var = b
+            .lookup(name)
+            .unwrap_or_else(|| panic!("{name}: unbound identifier"));
+        match var {
+            // Reading a variable that is never assigned doesn't need a copy
+            Var::Reg(reg) if !self.assigned.contains(name) => reg,
+            Var::Reg(reg) => b.emit_with(|out| Inst::Move(out, reg)),
+            Var::Cell(cell) => b.emit_with(|out| Inst::CellGet(out, cell)),
+            Var::Captured(i) => b.emit_with(|out| Inst::Captured(out, i)),
+            Var::CapturedCell(i) => {
+                let cell = b.emit_with(|out| Inst::Captured(out, i));
+                b.emit_with(|out| Inst::CellGet(out, cell))
+            }
+        }
+    }
+
+    fn quote(&mut self, b: &mut Builder, d: &Datum) -> VReg {
+        match d {
+            Datum::Integer(i) => b.int(*i),
+            Datum::Float(f) => b.lit(Lit::Float(*f)),
+            Datum::Str(s) => b.lit(Lit::Str(s.clone())),
+            Datum::Symbol(s) if s == "#t" || s == "#f" => b.lit(Lit::Bool(s == "#t")),
+            Datum::Symbol(s) => b.lit(Lit::Symbol(s.clone())),
+            Datum::List(items) if items.is_empty() => b.lit(Lit::Empty),
+            Datum::List(items) => {
+                let items = items.iter().map(|d| self.quote(b, d)).collect();
+                b.emit_with(|out| Inst::CallStack(out, "list".into(), items))
+            }
+        }
+    }
+
+    fn builtin(&mut self, b: &mut Builder, op: &str, args: &[Core]) -> VReg {
+        let (name, arity) = match op {
+            // Arithmetic operations
+            "+" => ("madd", 2),
+            "-" => ("msub", 2),
+            "*" => ("mmul", 2),
+            "/" => ("mdiv", 2),
+            "quotient" => ("mquotient", 2),
+            "remainder" => ("mremainder", 2),
+            "modulo" | "mod" => ("mmod", 2),
+            "numerator" | "denominator" => (op, 1),
+            "exact->inexact" => ("toinexact", 1),
+            "inexact->exact" => ("toexact", 1),
+            "sqrt" => ("msqrt", 1),
+            "expt" => ("mexpt", 2),
+            "exp" => ("mexp", 1),
+            "log" => ("mlog", 1),
+            "sin" => ("msin", 1),
+            "cos" => ("mcos", 1),
+            "tan" => ("mtan", 1),
+            "atan" if args.len() == 2 => ("matan2", 2),
+            "atan" => ("matan", 1),
+            "floor" => ("mfloor", 1),
+            "ceiling" => ("mceiling", 1),
+            "round" => ("mround", 1),
+            "truncate" => ("mtruncate", 1),
+            "exact-round" => ("mexactround", 1),
+            "=" | "eq?" => ("eq", 2),
+
+            // List operations
+            "empty" => {
+                assert_params(args, 0);
+                return b.lit(Lit::Empty);
+            }
+            "first" | "rest" => (op, 1),
+            "empty?" => ("isempty", 1),
+            "cons" | "append" => (op, 2),
+            "list" => {
+                let args = args.iter().map(|arg| self.expr(b, arg)).collect();
+                return b.emit_with(|out| Inst::CallStack(out, "list".into(), args));
+            }
+
+            // Output
+            "display" => ("display", 1),
+            "write" => ("writevalue", 1),
+            "print" => ("printvalue", 1),
+            "newline" => ("newline", 0),
+            "printf" | "format" => {
+                assert!(!args.is_empty(), "{op} needs a format string");
+                let name = if op == "printf" {
+                    "printformatted"
+                } else {
+                    "formatstring"
+                };
+                let args = args.iter().map(|arg| self.expr(b, arg)).collect();
+                return b.emit_with(|out| Inst::CallStack(out, name.into(), args));
+            }
+
+            // Internals
+            "_getint" | "_getfloat" => (&op[1..], 1),
+            "_checkheap" => (&op[1..], 0),
+
+            op => panic!("{op}: unbound identifier"),
+        };
+        assert_params(args, arity);
+        let args = args.iter().map(|arg| self.expr(b, arg)).collect();
+        b.emit_with(|out| Inst::Call(out, name.into(), args))
+    }
+}
+
+fn assert_params(params: &[Core], n: usize) {
+    assert_eq!(params.len(), n);
+}
+
+/// A function being lowered
+#[derive(Default)]
+struct Builder {
+    blocks: Vec<(Vec<Inst>, Option<Terminator>)>,
+    /// Block instructions are added to
+    current: usize,
+    vregs: usize,
+    /// Variables in scope, innermost last
+    scope: Vec<(String, Var)>,
+}
+
+impl Builder {
+    fn fresh(&mut self) -> VReg {
+        self.vregs += 1;
+        VReg(self.vregs - 1)
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        self.blocks[self.current].0.push(inst);
+    }
+
+    /// Adds an instruction that writes a new register, which is returned
+    fn emit_with(&mut self, inst: impl FnOnce(VReg) -> Inst) -> VReg {
+        let out = self.fresh();
+        self.emit(inst(out));
+        out
+    }
+
+    fn lit(&mut self, lit: Lit) -> VReg {
+        self.emit_with(|out| Inst::Lit(out, lit))
+    }
+
+    /// An integer literal. One that doesn't fit in a fixnum is built from two halves that do, by
+    /// the runtime, which makes it a bignum.
+    fn int(&mut self, i: i64) -> VReg {
+        if (i << 2) >> 2 == i {
+            return self.lit(Lit::Int(i));
+        }
+        let high = self.lit(Lit::Int(i >> 32));
+        let shift = self.lit(Lit::Int(1 << 32));
+        let high = self.emit_with(|out| Inst::Call(out, "mmul".into(), vec![high, shift]));
+        let low = self.lit(Lit::Int(i & 0xffff_ffff));
+        self.emit_with(|out| Inst::Call(out, "madd".into(), vec![high, low]))
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.blocks.push((Vec::new(), None));
+        BlockId(self.blocks.len() - 1)
+    }
+
+    fn switch_to(&mut self, block: BlockId) {
+        self.current = block.0;
+    }
+
+    fn terminate(&mut self, term: Terminator) {
+        self.blocks[self.current].1 = Some(term);
+    }
+
+    fn lookup(&self, name: &str) -> Option<Var> {
+        self.scope
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, var)| *var)
+    }
+
+    /// Returns a register holding what a closure captures for a variable: its value, or the
+    /// address of its cell
+    fn capture(&mut self, var: Var) -> VReg {
+        match var {
+            Var::Reg(reg) | Var::Cell(reg) => reg,
+            Var::Captured(i) | Var::CapturedCell(i) => self.emit_with(|out| Inst::Captured(out, i)),
+        }
+    }
+
+    fn finish(self, params: Vec<VReg>, captures: usize) -> Function {
+        let blocks = self
+            .blocks
+            .into_iter()
+            .map(|(insts, term)| Block {
+                insts,
+                term: term.expect("block without a terminator"),
+            })
+            .collect();
+        Function {
+            params,
+            captures,
+            blocks,
+            vregs: self.vregs,
+        }
+    }
+}
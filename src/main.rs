@@ -2,28 +2,83 @@ use std::{
     env,
     fs::{self, File},
     io::{self, Write},
-    process::Command,
+    process::{self, Command},
 };
 
-use compiler_lib::{Compiler, Lexer, Parser};
+use compiler_lib::{format, Compiler, Lexer, Options, Parser};
 
 fn main() {
-    let args: Vec<_> = env::args().collect();
-    let file_path = &args[1];
-    let contents = fs::read_to_string(file_path).unwrap();
+    let args: Vec<_> = env::args().skip(1).collect();
+    if args[0] == "fmt" {
+        return fmt(&args[1..]);
+    }
+    let mut options = Options::default();
+    let mut emit_ir = false;
+    let mut file_path = None;
+    for arg in &args {
+        if arg == "--emit=ir" {
+            emit_ir = true;
+        } else if let Some(pass) = arg.strip_prefix("--print-after=") {
+            options.print_after = Some(pass.into());
+        } else if let Some(level) = arg.strip_prefix("-O") {
+            options.opt_level = match level {
+                "0" => 0,
+                "1" => 1,
+                "2" => 2,
+                _ => panic!("{arg}: the optimization level must be 0, 1 or 2"),
+            };
+        } else if arg.starts_with('-') {
+            panic!("{arg}: unknown option");
+        } else {
+            file_path = Some(arg);
+        }
+    }
+    let contents = fs::read_to_string(file_path.expect("no file to compile")).unwrap();
     let e = Parser::parse(Lexer::lex(contents));
+    let mut compiler = Compiler {
+        options,
+        ..Default::default()
+    };
+    if emit_ir {
+        print!("{}", compiler.optimize(&e));
+        return;
+    }
     let mut file = File::create("a.asm").unwrap();
-    Compiler::default().compile_to_file(e, &mut file);
+    compiler.compile_to_file(e, &mut file);
 
     Command::new("nasm")
         .args(["-f", "elf64", "a.asm", "-o", "a.o"])
         .output()
         .unwrap();
 
+    // The runtime only refers to the C math library weakly, which wouldn't count as a use of it
     let output = Command::new("gcc")
-        .args(["-no-pie", "a.o"])
+        .args(["-no-pie", "a.o", "-Wl,--no-as-needed", "-lm"])
         .output()
         .unwrap();
     io::stdout().write_all(&output.stdout).unwrap();
     io::stderr().write_all(&output.stderr).unwrap();
 }
+
+/// Reformats each file in place. With `--check`, lists the files that aren't formatted instead
+/// and fails if there are any.
+fn fmt(args: &[String]) {
+    let check = args.iter().any(|arg| arg == "--check");
+    let mut unformatted = false;
+    for path in args.iter().filter(|arg| *arg != "--check") {
+        let contents = fs::read_to_string(path).unwrap();
+        let formatted = format(&contents);
+        if formatted == contents {
+            continue;
+        }
+        if check {
+            println!("{path}");
+            unformatted = true;
+        } else {
+            fs::write(path, formatted).unwrap();
+        }
+    }
+    if unformatted {
+        process::exit(1);
+    }
+}
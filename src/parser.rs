@@ -1,16 +1,38 @@
-use crate::lexer::Token;
+use crate::{expander::Expander, lexer::Token};
 
-#[derive(Debug, PartialEq)]
+/// The surface syntax of a program. `desugar` lowers it to a smaller core language before it is
+/// compiled.
+#[derive(Debug, PartialEq, Clone)]
 pub enum Node {
+    /// Function application, or a form whose parts are all expressions, like `if` or `and`
     Expr(Expr),
+    /// Application of an expression that isn't an identifier, like an immediately applied lambda
+    AppExpr(Box<AppExpr>),
     String(String),
     Float(f64),
     Integer(i64),
     LetExpr(Box<LetExpr>),
     LambdaExpr(Box<LambdaExpr>),
+    CondExpr(Box<CondExpr>),
+    DefineExpr(Box<DefineExpr>),
+    /// `begin`, and bodies with more than one expression
+    Begin(Vec<Node>),
+    Quote(Box<Datum>),
+    Quasiquote(Box<Template>),
 }
 
-#[derive(Debug, PartialEq)]
+/// An s-expression. Source is read into these before macro expansion, and `quote` keeps them as
+/// literal data.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Datum {
+    Symbol(String),
+    Integer(i64),
+    Float(f64),
+    Str(String),
+    List(Vec<Datum>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct Expr {
     pub op: String,
     pub params: Vec<Node>,
@@ -22,87 +44,314 @@ impl Expr {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
+pub struct AppExpr {
+    pub op: Node,
+    pub params: Vec<Node>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum LetKind {
+    Let,
+    LetStar,
+    Letrec,
+    /// `(let name ([x e] ...) body)`, which binds `name` to a procedure that runs the body
+    Named(String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct LetExpr {
+    pub kind: LetKind,
     pub bindings: Vec<(String, Node)>,
     pub body: Node,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct LambdaExpr {
     pub params: Vec<String>,
     pub body: Node,
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct CondExpr {
+    /// Test and body of each clause, in order
+    pub clauses: Vec<(Node, Node)>,
+    pub else_body: Option<Node>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DefineExpr {
+    pub name: String,
+    /// Set by the `(define (name params ...) body ...)` shorthand, in which case `body` is the
+    /// procedure's body rather than the value being defined
+    pub params: Option<Vec<String>>,
+    pub body: Node,
+}
+
+/// A quasiquoted template
+#[derive(Debug, PartialEq, Clone)]
+pub enum Template {
+    /// A part without any unquotes
+    Datum(Datum),
+    Unquote(Node),
+    UnquoteSplicing(Node),
+    List(Vec<Template>),
+}
+
 pub struct Parser {
     ptr: usize,
     data: Vec<Token>,
 }
 
 impl Parser {
+    /// Reads every top-level form, expands macros, and parses what is left. A program with more
+    /// than one form is parsed as a `begin`.
     pub fn parse(data: Vec<Token>) -> Node {
+        let forms = Expander::default().expand_program(Self::read(data));
+        let mut nodes: Vec<_> = forms.iter().map(Self::parse_datum).collect();
+        if nodes.len() == 1 {
+            nodes.remove(0)
+        } else {
+            Node::Begin(nodes)
+        }
+    }
+
+    /// Reads the tokens into s-expressions without interpreting them
+    pub fn read(mut data: Vec<Token>) -> Vec<Datum> {
+        data.retain(|t| !t.is_trivia());
         let mut parser = Parser { ptr: 0, data };
-        parser.parse_expr()
+        let mut forms = Vec::new();
+        while parser.peek_is(|c| c != &Token::EOF) {
+            forms.push(parser.read_datum());
+        }
+        forms
     }
 
-    fn parse_expr(&mut self) -> Node {
-        self.consume_open();
-        let op = self.consume_ident().clone().inner_ident();
-        if op == "let*" {
-            Node::LetExpr(Box::new(self.parse_let_expr()))
-        } else if op == "lambda" {
-            Node::LambdaExpr(Box::new(self.parse_lambda_expr()))
-        } else {
-            let mut params = Vec::new();
-            while self.peek_is(|c| c != &Token::RightParen) {
-                params.push(self.parse_param());
+    fn read_datum(&mut self) -> Datum {
+        match self.advance().clone() {
+            Token::LeftParen | Token::LeftBracket => {
+                let mut items = Vec::new();
+                while self.peek_is(|c| c != &Token::RightParen && c != &Token::RightBracket) {
+                    items.push(self.read_datum());
+                }
+                self.consume_close();
+                Datum::List(items)
             }
-            let e = Expr::new(op, params);
-            self.consume_close();
-            Node::Expr(e)
+            Token::Integer(i) => Datum::Integer(i),
+            Token::Float(f) => Datum::Float(f),
+            Token::Str(s) => Datum::Str(s),
+            Token::Identifier(s) => Datum::Symbol(s),
+            t @ (Token::Quote | Token::Quasiquote | Token::Unquote | Token::UnquoteSplicing) => {
+                let name = Self::shorthand_name(&t);
+                Datum::List(vec![Datum::Symbol(name.into()), self.read_datum()])
+            }
+            t => panic!("unexpected {t:?}"),
         }
     }
 
-    fn parse_let_expr(&mut self) -> LetExpr {
-        self.consume_open();
-        let mut bindings = Vec::new();
-        while self.peek_is(|c| c != &Token::RightParen && c != &Token::RightBracket) {
-            self.consume_open();
-            let name = self.consume_ident().clone().inner_ident();
-            let e = self.parse_param();
-            bindings.push((name, e));
-            self.consume_close();
+    pub fn parse_datum(d: &Datum) -> Node {
+        match d {
+            Datum::Integer(i) => Node::Integer(*i),
+            Datum::Float(f) => Node::Float(*f),
+            Datum::Symbol(s) => Node::String(s.clone()),
+            // Strings evaluate to themselves, so they are kept as quoted data
+            Datum::Str(_) => Node::Quote(Box::new(d.clone())),
+            Datum::List(items) => {
+                let op = match items.first() {
+                    Some(Datum::Symbol(op)) => op,
+                    Some(op @ Datum::List(_)) => {
+                        return Node::AppExpr(Box::new(AppExpr {
+                            op: Self::parse_datum(op),
+                            params: items[1..].iter().map(Self::parse_datum).collect(),
+                        }))
+                    }
+                    d => panic!("expected procedure, got {d:?}"),
+                };
+                match &op[..] {
+                    "let" if matches!(items.get(1), Some(Datum::Symbol(_))) => {
+                        let name = Self::ident(&items[1]);
+                        let e = Self::parse_let_expr(LetKind::Named(name), &items[1..]);
+                        Node::LetExpr(Box::new(e))
+                    }
+                    "let" => Node::LetExpr(Box::new(Self::parse_let_expr(LetKind::Let, items))),
+                    "let*" => {
+                        Node::LetExpr(Box::new(Self::parse_let_expr(LetKind::LetStar, items)))
+                    }
+                    "letrec" => {
+                        Node::LetExpr(Box::new(Self::parse_let_expr(LetKind::Letrec, items)))
+                    }
+                    "lambda" => Node::LambdaExpr(Box::new(Self::parse_lambda_expr(items))),
+                    "cond" => Node::CondExpr(Box::new(Self::parse_cond_expr(items))),
+                    "define" => Node::DefineExpr(Box::new(Self::parse_define_expr(items))),
+                    "begin" => {
+                        assert!(items.len() > 1, "begin: bad syntax");
+                        Node::Begin(items[1..].iter().map(Self::parse_datum).collect())
+                    }
+                    "quote" => Node::Quote(Box::new(Self::single_operand(items).clone())),
+                    "quasiquote" => match Self::parse_template(Self::single_operand(items), 1) {
+                        Template::UnquoteSplicing(_) => {
+                            panic!("unquote-splicing: invalid context within quasiquote")
+                        }
+                        t => Node::Quasiquote(Box::new(t)),
+                    },
+                    "unquote" | "unquote-splicing" => panic!("{op}: not in quasiquote"),
+                    _ => Node::Expr(Expr::new(
+                        op.clone(),
+                        items[1..].iter().map(Self::parse_datum).collect(),
+                    )),
+                }
+            }
         }
-        self.consume_close();
-        let body = self.parse_param();
-        self.consume_close();
-        LetExpr { bindings, body }
-    }
-
-    fn parse_lambda_expr(&mut self) -> LambdaExpr {
-        self.consume_open();
-        let mut params = Vec::new();
-        while self.peek_is(|c| c != &Token::RightParen && c != &Token::RightBracket) {
-            let name = self.consume_ident().clone().inner_ident();
-            params.push(name);
+    }
+
+    /// Parses the bindings and body of a `let` form. For a named `let`, `items` starts at the
+    /// name.
+    fn parse_let_expr(kind: LetKind, items: &[Datum]) -> LetExpr {
+        assert!(items.len() >= 3, "let: bad syntax");
+        let bindings = Self::list(&items[1])
+            .iter()
+            .map(|binding| match Self::list(binding) {
+                [name, e] => (Self::ident(name), Self::parse_datum(e)),
+                _ => panic!("let: bad syntax"),
+            })
+            .collect();
+        let body = Self::parse_body(&items[2..]);
+        LetExpr {
+            kind,
+            bindings,
+            body,
         }
-        self.consume_close();
-        let body = self.parse_param();
-        self.consume_close();
+    }
+
+    fn parse_lambda_expr(items: &[Datum]) -> LambdaExpr {
+        assert!(items.len() >= 3, "lambda: bad syntax");
+        let params = Self::list(&items[1]).iter().map(Self::ident).collect();
+        let body = Self::parse_body(&items[2..]);
         LambdaExpr { params, body }
     }
 
-    fn parse_param(&mut self) -> Node {
-        if self.peek_is(|c| c == &Token::LeftParen) {
-            self.parse_expr()
-        } else {
-            match self.advance() {
-                Token::Integer(i) => Node::Integer(*i),
-                Token::Float(i) => Node::Float(*i),
-                Token::Identifier(i) => Node::String(i.to_string()),
-                _ => panic!(),
+    fn parse_cond_expr(items: &[Datum]) -> CondExpr {
+        let mut clauses = Vec::new();
+        let mut else_body = None;
+        for clause in &items[1..] {
+            assert!(else_body.is_none(), "cond: `else` clause must be last");
+            match Self::list(clause) {
+                [Datum::Symbol(e), body @ ..] if e == "else" => {
+                    else_body = Some(Self::parse_body(body))
+                }
+                [test, body @ ..] => {
+                    clauses.push((Self::parse_datum(test), Self::parse_body(body)))
+                }
+                [] => panic!("cond: bad syntax"),
             }
         }
+        CondExpr { clauses, else_body }
+    }
+
+    fn parse_define_expr(items: &[Datum]) -> DefineExpr {
+        match items {
+            [_, Datum::Symbol(name), value] => DefineExpr {
+                name: name.clone(),
+                params: None,
+                body: Self::parse_datum(value),
+            },
+            [_, Datum::List(header), body @ ..] if !header.is_empty() => DefineExpr {
+                name: Self::ident(&header[0]),
+                params: Some(header[1..].iter().map(Self::ident).collect()),
+                body: Self::parse_body(body),
+            },
+            _ => panic!("define: bad syntax"),
+        }
+    }
+
+    /// Parses the expressions of a body, wrapping them in a `begin` if there are several
+    fn parse_body(items: &[Datum]) -> Node {
+        match items {
+            [] => panic!("bad syntax: empty body"),
+            [e] => Self::parse_datum(e),
+            _ => Node::Begin(items.iter().map(Self::parse_datum).collect()),
+        }
+    }
+
+    /// Parses a quasiquoted template `depth` levels deep. Parts without any unquotes are left as
+    /// data.
+    fn parse_template(d: &Datum, depth: usize) -> Template {
+        let items = match d {
+            Datum::List(items) => items,
+            _ => return Template::Datum(d.clone()),
+        };
+        if let [Datum::Symbol(name), inner] = &items[..] {
+            match &name[..] {
+                "unquote" if depth == 1 => return Template::Unquote(Self::parse_datum(inner)),
+                "unquote-splicing" if depth == 1 => {
+                    return Template::UnquoteSplicing(Self::parse_datum(inner))
+                }
+                "unquote" | "unquote-splicing" => {
+                    return Self::template_form(name, Self::parse_template(inner, depth - 1))
+                }
+                "quasiquote" => {
+                    return Self::template_form(name, Self::parse_template(inner, depth + 1))
+                }
+                _ => {}
+            }
+        }
+
+        let items: Vec<_> = items
+            .iter()
+            .map(|item| Self::parse_template(item, depth))
+            .collect();
+        if items.iter().all(|item| matches!(item, Template::Datum(_))) {
+            let data = items
+                .into_iter()
+                .map(|item| match item {
+                    Template::Datum(d) => d,
+                    _ => unreachable!(),
+                })
+                .collect();
+            Template::Datum(Datum::List(data))
+        } else {
+            Template::List(items)
+        }
+    }
+
+    /// Builds the template `(name inner)`, where `name` is one of the quoting forms
+    fn template_form(name: &str, inner: Template) -> Template {
+        let name = Datum::Symbol(name.into());
+        match inner {
+            Template::Datum(d) => Template::Datum(Datum::List(vec![name, d])),
+            inner => Template::List(vec![Template::Datum(name), inner]),
+        }
+    }
+
+    pub(crate) fn shorthand_name(t: &Token) -> &'static str {
+        match t {
+            Token::Quote => "quote",
+            Token::Quasiquote => "quasiquote",
+            Token::Unquote => "unquote",
+            Token::UnquoteSplicing => "unquote-splicing",
+            _ => unreachable!(),
+        }
+    }
+
+    fn single_operand(items: &[Datum]) -> &Datum {
+        match items {
+            [_, d] => d,
+            _ => panic!("{:?}: bad syntax", items[0]),
+        }
+    }
+
+    fn list(d: &Datum) -> &[Datum] {
+        match d {
+            Datum::List(items) => items,
+            _ => panic!("expected list, got {d:?}"),
+        }
+    }
+
+    fn ident(d: &Datum) -> String {
+        match d {
+            Datum::Symbol(s) => s.clone(),
+            _ => panic!("expected identifier, got {d:?}"),
+        }
     }
 
     fn peek(&self) -> Option<&Token> {
@@ -118,25 +367,12 @@ impl Parser {
         &self.data[self.ptr - 1]
     }
 
-    fn consume_open(&mut self) {
-        assert!(matches!(
-            self.advance(),
-            Token::LeftParen | Token::LeftBracket
-        ));
-    }
-
     fn consume_close(&mut self) {
         assert!(matches!(
             self.advance(),
             Token::RightParen | Token::RightBracket
         ));
     }
-
-    fn consume_ident(&mut self) -> &Token {
-        let next = self.advance();
-        assert!(matches!(next, Token::Identifier(_)));
-        next
-    }
 }
 
 #[cfg(test)]
@@ -151,6 +387,7 @@ mod tests {
         assert_eq!(
             Parser::parse(Lexer::lex(rkt)),
             Node::LetExpr(Box::new(LetExpr {
+                kind: LetKind::LetStar,
                 bindings: vec![
                     ("x".into(), Node::Integer(5)),
                     ("y".into(), Node::Float(4.0))
@@ -163,6 +400,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn named_let() {
+        let rkt = String::from("(let loop ([i 0]) (loop i) i)");
+        assert_eq!(
+            Parser::parse(Lexer::lex(rkt)),
+            Node::LetExpr(Box::new(LetExpr {
+                kind: LetKind::Named("loop".into()),
+                bindings: vec![("i".into(), Node::Integer(0))],
+                body: Node::Begin(vec![
+                    Node::Expr(Expr::new("loop".into(), vec![Node::String("i".into())])),
+                    Node::String("i".into())
+                ])
+            }))
+        );
+    }
+
     #[test]
     fn lambda() {
         let rkt = String::from("(lambda (x y) (+ x y))");
@@ -177,4 +430,83 @@ mod tests {
             }))
         );
     }
+
+    #[test]
+    fn cond() {
+        let rkt = String::from("(cond [(= x 1) 2] [else 3 4])");
+        assert_eq!(
+            Parser::parse(Lexer::lex(rkt)),
+            Node::CondExpr(Box::new(CondExpr {
+                clauses: vec![(
+                    Node::Expr(Expr::new(
+                        "=".into(),
+                        vec![Node::String("x".into()), Node::Integer(1)]
+                    )),
+                    Node::Integer(2)
+                )],
+                else_body: Some(Node::Begin(vec![Node::Integer(3), Node::Integer(4)]))
+            }))
+        );
+    }
+
+    #[test]
+    fn applied_lambda() {
+        let rkt = String::from("((lambda (x) x) 5)");
+        assert_eq!(
+            Parser::parse(Lexer::lex(rkt)),
+            Node::AppExpr(Box::new(AppExpr {
+                op: Node::LambdaExpr(Box::new(LambdaExpr {
+                    params: vec!["x".into()],
+                    body: Node::String("x".into())
+                })),
+                params: vec![Node::Integer(5)]
+            }))
+        );
+    }
+
+    #[test]
+    fn define_shorthand() {
+        let rkt = String::from("(define (f x) x) (f 1)");
+        assert_eq!(
+            Parser::parse(Lexer::lex(rkt)),
+            Node::Begin(vec![
+                Node::DefineExpr(Box::new(DefineExpr {
+                    name: "f".into(),
+                    params: Some(vec!["x".into()]),
+                    body: Node::String("x".into())
+                })),
+                Node::Expr(Expr::new("f".into(), vec![Node::Integer(1)]))
+            ])
+        );
+    }
+
+    #[test]
+    fn quasiquote() {
+        let rkt = String::from("`(a ,b ,@c (d ,e) (f) `(g ,h ,,i))");
+        let sym = |s: &str| Datum::Symbol(s.into());
+        assert_eq!(
+            Parser::parse(Lexer::lex(rkt)),
+            Node::Quasiquote(Box::new(Template::List(vec![
+                Template::Datum(sym("a")),
+                Template::Unquote(Node::String("b".into())),
+                Template::UnquoteSplicing(Node::String("c".into())),
+                Template::List(vec![
+                    Template::Datum(sym("d")),
+                    Template::Unquote(Node::String("e".into()))
+                ]),
+                Template::Datum(Datum::List(vec![sym("f")])),
+                Template::List(vec![
+                    Template::Datum(sym("quasiquote")),
+                    Template::List(vec![
+                        Template::Datum(sym("g")),
+                        Template::Datum(Datum::List(vec![sym("unquote"), sym("h")])),
+                        Template::List(vec![
+                            Template::Datum(sym("unquote")),
+                            Template::Unquote(Node::String("i".into()))
+                        ])
+                    ])
+                ])
+            ])))
+        );
+    }
 }
@@ -0,0 +1,135 @@
+use crate::{
+    compiler::Options, constfold::fold_constants, dce::eliminate_dead_code, inline::inline,
+    ir::Module,
+};
+
+/// An optimization over the IR of a whole program
+#[derive(Clone, Copy)]
+pub struct Pass {
+    pub name: &'static str,
+    /// The lowest optimization level that runs the pass
+    pub level: u8,
+    run: fn(&mut Module, &Options),
+}
+
+/// Every pass, in the order they run
+pub const PASSES: [Pass; 3] = [
+    Pass {
+        name: "inline",
+        level: 2,
+        run: |module, options| inline(module, options.inline_threshold),
+    },
+    Pass {
+        name: "constfold",
+        level: 1,
+        run: |module, _| fold_constants(module),
+    },
+    Pass {
+        name: "dce",
+        level: 1,
+        run: |module, _| eliminate_dead_code(module),
+    },
+];
+
+/// Runs the passes that `Options::opt_level` calls for
+///
+/// Level 0 runs none, level 1 folds constants and removes dead code, and level 2 inlines small
+/// functions first.
+pub struct PassManager<'a> {
+    options: &'a Options,
+    passes: Vec<Pass>,
+}
+
+impl<'a> PassManager<'a> {
+    pub fn new(options: &'a Options) -> Self {
+        let passes: Vec<_> = PASSES
+            .into_iter()
+            .filter(|pass| pass.level <= options.opt_level)
+            .collect();
+        if let Some(name) = &options.print_after {
+            let pass = PASSES.iter().find(|pass| pass.name == name);
+            let pass = pass.unwrap_or_else(|| panic!("{name}: no such pass"));
+            assert!(
+                pass.level <= options.opt_level,
+                "{name}: pass doesn't run at -O{}, only from -O{}",
+                options.opt_level,
+                pass.level
+            );
+        }
+        Self { options, passes }
+    }
+
+    /// The names of the passes that will run
+    pub fn names(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|pass| pass.name).collect()
+    }
+
+    /// Runs the passes in order. The IR is printed to stderr after the one named by
+    /// `Options::print_after`.
+    pub fn run(&self, module: &mut Module) {
+        for pass in &self.passes {
+            (pass.run)(module, self.options);
+            if self.options.print_after.as_deref() == Some(pass.name) {
+                eprint!("; IR after {}\n{module}", pass.name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lexer::Lexer, parser::Parser};
+
+    use super::*;
+
+    fn optimized(rkt: &str, opt_level: u8) -> String {
+        let options = Options {
+            opt_level,
+            ..Default::default()
+        };
+        let mut module = Module::new(&Parser::parse(Lexer::lex(rkt.into())));
+        PassManager::new(&options).run(&mut module);
+        module.to_string()
+    }
+
+    #[test]
+    fn levels() {
+        let rkt = "(let ([f (lambda (x) (* x 2))]) (f (+ 1 2)))";
+        let unoptimized = Module::new(&Parser::parse(Lexer::lex(rkt.into())));
+        assert_eq!(optimized(rkt, 0), unoptimized.to_string());
+        assert!(optimized(rkt, 1).contains(" = 3\n"));
+        assert!(optimized(rkt, 1).contains("apply"));
+        assert!(!optimized(rkt, 2).contains("apply"));
+        assert!(optimized(rkt, 2).contains("= 6\n"));
+    }
+
+    #[test]
+    fn names() {
+        let options = Options::default();
+        assert_eq!(
+            PassManager::new(&options).names(),
+            ["inline", "constfold", "dce"]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "no such pass")]
+    fn unknown_pass() {
+        let options = Options {
+            print_after: Some("fold".into()),
+            ..Default::default()
+        };
+        PassManager::new(&options);
+    }
+
+    #[test]
+    #[should_panic(expected = "inline: pass doesn't run at -O1")]
+    fn unscheduled_pass() {
+        let options = Options {
+            opt_level: 1,
+            print_after: Some("inline".into()),
+            ..Default::default()
+        };
+        PassManager::new(&options);
+    }
+}
@@ -0,0 +1,583 @@
+use std::fmt;
+
+use crate::parser::{Datum, LetKind, Node, Template};
+
+/// Column the pretty-printer tries to keep lines within
+pub const WIDTH: usize = 80;
+
+/// Turns a node back into an s-expression that parses to the same node
+pub fn unparse(node: &Node) -> Datum {
+    match node {
+        Node::Expr(e) => {
+            let mut items = vec![sym(&e.op)];
+            items.extend(e.params.iter().map(unparse));
+            Datum::List(items)
+        }
+        Node::AppExpr(e) => {
+            let mut items = vec![unparse(&e.op)];
+            items.extend(e.params.iter().map(unparse));
+            Datum::List(items)
+        }
+        Node::String(s) => sym(s),
+        Node::Float(f) => Datum::Float(*f),
+        Node::Integer(i) => Datum::Integer(*i),
+        Node::LetExpr(e) => {
+            let (name, named) = match &e.kind {
+                LetKind::Let => ("let", None),
+                LetKind::LetStar => ("let*", None),
+                LetKind::Letrec => ("letrec", None),
+                LetKind::Named(name) => ("let", Some(sym(name))),
+            };
+            let bindings = e
+                .bindings
+                .iter()
+                .map(|(name, val)| Datum::List(vec![sym(name), unparse(val)]))
+                .collect();
+            let mut items = vec![sym(name)];
+            items.extend(named);
+            items.push(Datum::List(bindings));
+            items.extend(unparse_body(&e.body));
+            Datum::List(items)
+        }
+        Node::LambdaExpr(e) => {
+            let params = e.params.iter().map(|p| sym(p)).collect();
+            let mut items = vec![sym("lambda"), Datum::List(params)];
+            items.extend(unparse_body(&e.body));
+            Datum::List(items)
+        }
+        Node::CondExpr(e) => {
+            let mut items = vec![sym("cond")];
+            for (test, body) in &e.clauses {
+                let mut clause = vec![unparse(test)];
+                clause.extend(unparse_body(body));
+                items.push(Datum::List(clause));
+            }
+            if let Some(body) = &e.else_body {
+                let mut clause = vec![sym("else")];
+                clause.extend(unparse_body(body));
+                items.push(Datum::List(clause));
+            }
+            Datum::List(items)
+        }
+        Node::DefineExpr(e) => match &e.params {
+            Some(params) => {
+                let mut header = vec![sym(&e.name)];
+                header.extend(params.iter().map(|p| sym(p)));
+                let mut items = vec![sym("define"), Datum::List(header)];
+                items.extend(unparse_body(&e.body));
+                Datum::List(items)
+            }
+            None => Datum::List(vec![sym("define"), sym(&e.name), unparse(&e.body)]),
+        },
+        Node::Begin(body) => {
+            let mut items = vec![sym("begin")];
+            items.extend(body.iter().map(unparse));
+            Datum::List(items)
+        }
+        Node::Quote(d) if matches!(**d, Datum::Str(_)) => (**d).clone(),
+        Node::Quote(d) => Datum::List(vec![sym("quote"), (**d).clone()]),
+        Node::Quasiquote(t) => Datum::List(vec![sym("quasiquote"), unparse_template(t)]),
+    }
+}
+
+/// Splices a `begin` into the body it was parsed from. A `begin` of one expression is kept,
+/// since a body of one expression doesn't parse to a `begin`.
+fn unparse_body(body: &Node) -> Vec<Datum> {
+    match body {
+        Node::Begin(body) if body.len() > 1 => body.iter().map(unparse).collect(),
+        _ => vec![unparse(body)],
+    }
+}
+
+fn unparse_template(t: &Template) -> Datum {
+    match t {
+        Template::Datum(d) => d.clone(),
+        Template::Unquote(e) => Datum::List(vec![sym("unquote"), unparse(e)]),
+        Template::UnquoteSplicing(e) => Datum::List(vec![sym("unquote-splicing"), unparse(e)]),
+        Template::List(items) => Datum::List(items.iter().map(unparse_template).collect()),
+    }
+}
+
+fn sym(s: &str) -> Datum {
+    Datum::Symbol(s.into())
+}
+
+/// An s-expression being laid out. Unlike a `Datum` it can hold comments and blank lines, so
+/// source can be reformatted by the same rules the AST is printed with.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Sexp {
+    Atom(String),
+    List(Vec<Sexp>),
+    /// A comment, including its `;`, and whether it ends the line of the item before it
+    Comment(String, bool),
+    /// An empty line between items
+    Blank,
+}
+
+impl Sexp {
+    fn is_code(&self) -> bool {
+        matches!(self, Sexp::Atom(_) | Sexp::List(_))
+    }
+}
+
+impl From<&Datum> for Sexp {
+    fn from(d: &Datum) -> Self {
+        match d {
+            Datum::Symbol(s) => Sexp::Atom(s.clone()),
+            Datum::Integer(i) => Sexp::Atom(i.to_string()),
+            Datum::Float(f) => Sexp::Atom(float(*f)),
+            Datum::Str(s) => Sexp::Atom(string(s)),
+            Datum::List(items) => Sexp::List(items.iter().map(Sexp::from).collect()),
+        }
+    }
+}
+
+/// Writes a float so that it reads back as one
+pub(crate) fn float(f: f64) -> String {
+    if f.fract() == 0.0 {
+        format!("{f}.0")
+    } else {
+        f.to_string()
+    }
+}
+
+/// Writes a string literal, with escapes for the characters that need them
+pub(crate) fn string(s: &str) -> String {
+    let mut text = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => text.push_str("\\\""),
+            '\\' => text.push_str("\\\\"),
+            '\n' => text.push_str("\\n"),
+            '\t' => text.push_str("\\t"),
+            c => text.push(c),
+        }
+    }
+    text.push('"');
+    text
+}
+
+/// Pretty-prints a datum that starts at column `col`
+///
+/// Lists that fit in the rest of the line are kept on it. Otherwise the bodies of binding forms
+/// like `define` and `let` are indented by two, and the arguments of other forms are aligned
+/// with the first one. `let` bindings and the clauses of `cond` and `syntax-rules` are written
+/// with square brackets.
+pub fn pretty(d: &Datum, col: usize) -> String {
+    layout(&d.into(), col, Brackets::default())
+}
+
+/// How a list and its items are bracketed
+#[derive(Default, Clone, Copy)]
+pub(crate) struct Brackets {
+    /// Whether the list itself uses square brackets
+    square: bool,
+    /// Whether every item of the list uses square brackets, as in a list of `let` bindings
+    items: bool,
+}
+
+impl Brackets {
+    fn pair(self) -> (&'static str, &'static str) {
+        if self.square {
+            ("[", "]")
+        } else {
+            ("(", ")")
+        }
+    }
+}
+
+/// How the items of a list that doesn't fit on one line are placed
+enum Shape {
+    /// A form whose first `n` operands stay on the line of its head, and whose body is indented
+    /// by two
+    Body(usize),
+    /// An application, whose arguments line up with the first one
+    App,
+    /// A list that doesn't start with an identifier, with each item on its own line
+    Data,
+}
+
+fn head<'a>(code: &[&'a Sexp]) -> Option<&'a str> {
+    match code.first() {
+        Some(Sexp::Atom(s)) if !is_number(s) => Some(s),
+        _ => None,
+    }
+}
+
+/// Whether an atom is a number literal. Rust also parses identifiers like `inf` and `nan` as
+/// floats, so only digits and Racket's spellings of the special values count.
+fn is_number(s: &str) -> bool {
+    let digits = s.trim_start_matches(['+', '-']);
+    matches!(s, "+inf.0" | "-inf.0" | "+nan.0")
+        || digits.starts_with(|c: char| c.is_ascii_digit() || c == '.') && s.parse::<f64>().is_ok()
+}
+
+fn shape(code: &[&Sexp]) -> Shape {
+    match head(code) {
+        Some("begin" | "cond") => Shape::Body(0),
+        Some(
+            "define" | "define-syntax" | "syntax-rules" | "lambda" | "let*" | "letrec" | "when"
+            | "unless",
+        ) => Shape::Body(1),
+        Some("let") if matches!(code.get(1), Some(Sexp::Atom(_))) => Shape::Body(2),
+        Some("let") => Shape::Body(1),
+        Some(_) => Shape::App,
+        None => Shape::Data,
+    }
+}
+
+/// The brackets of the `k`th code item of a list, going by the form the list is
+fn item_brackets(code: &[&Sexp], outer: Brackets) -> impl Fn(usize) -> Brackets {
+    let head = head(code);
+    let bindings = match (head, code.get(1)) {
+        (Some("let"), Some(Sexp::Atom(_))) => Some(2),
+        (Some("let" | "let*" | "letrec"), _) => Some(1),
+        _ => None,
+    };
+    // Index of the first clause of forms whose clauses are written with square brackets
+    let clauses = match head {
+        Some("cond") => Some(1),
+        Some("syntax-rules") => Some(2),
+        _ => None,
+    };
+    move |k| Brackets {
+        square: outer.items || clauses.is_some_and(|first| k >= first),
+        items: bindings == Some(k),
+    }
+}
+
+/// The reader shorthand for a quoting form, like `'` for `(quote d)`
+fn shorthand(items: &[Sexp]) -> Option<(&'static str, &Sexp)> {
+    let prefix = match items {
+        [Sexp::Atom(s), d] if d.is_code() => match &s[..] {
+            "quote" => "'",
+            "quasiquote" => "`",
+            "unquote" => ",",
+            "unquote-splicing" => ",@",
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some((prefix, &items[1]))
+}
+
+/// Writes an s-expression on one line, if it has no comments or blank lines
+fn flat(s: &Sexp, brackets: Brackets) -> Option<String> {
+    let items = match s {
+        Sexp::Atom(a) => return Some(a.clone()),
+        Sexp::List(items) => items,
+        Sexp::Comment(..) | Sexp::Blank => return None,
+    };
+    if let Some((prefix, s)) = shorthand(items) {
+        return Some(format!("{prefix}{}", flat(s, Brackets::default())?));
+    }
+    let code: Vec<_> = items.iter().collect();
+    let item_brackets = item_brackets(&code, brackets);
+    let items = items
+        .iter()
+        .enumerate()
+        .map(|(k, item)| flat(item, item_brackets(k)))
+        .collect::<Option<Vec<_>>>()?;
+    let (open, close) = brackets.pair();
+    Some(format!("{open}{}{close}", items.join(" ")))
+}
+
+pub(crate) fn layout(s: &Sexp, col: usize, brackets: Brackets) -> String {
+    let items = match s {
+        Sexp::List(items) => items,
+        Sexp::Atom(a) | Sexp::Comment(a, _) => return a.clone(),
+        Sexp::Blank => return String::new(),
+    };
+    if let Some(flat) = flat(s, brackets) {
+        if col + flat.len() <= WIDTH {
+            return flat;
+        }
+    }
+    if let Some((prefix, s)) = shorthand(items) {
+        return format!(
+            "{prefix}{}",
+            layout(s, col + prefix.len(), Brackets::default())
+        );
+    }
+
+    let code: Vec<_> = items.iter().filter(|s| s.is_code()).collect();
+    let shape = shape(&code);
+    let item_brackets = item_brackets(&code, brackets);
+    let (open, close) = brackets.pair();
+    let mut out = open.to_string();
+    let mut line_col = col + open.len();
+    // Column of the arguments of an application, once the first one is placed
+    let mut arg_col = col + 1;
+    // Number of code items placed so far
+    let mut k = 0;
+    // Whether the next item has to start a new line, because a comment ends this one
+    let mut must_break = false;
+    let mut blank = false;
+    for item in items {
+        match item {
+            Sexp::Blank => {
+                blank = true;
+                continue;
+            }
+            Sexp::Comment(c, true) => {
+                if k > 0 {
+                    out.push(' ');
+                }
+                out.push_str(c);
+                must_break = true;
+                continue;
+            }
+            _ => {}
+        }
+
+        // Whether the item goes on the line before it, and its column if it doesn't
+        let (same_line, indent) = match shape {
+            _ if k == 0 => (true, col + 1),
+            Shape::Body(n) if k <= n => (true, col + 4),
+            Shape::Body(_) => (false, col + 2),
+            Shape::App if k == 1 => (true, col + 1),
+            Shape::App => (false, arg_col),
+            Shape::Data => (false, col + 1),
+        };
+        if same_line && !must_break && item.is_code() {
+            if k > 0 {
+                out.push(' ');
+                line_col += 1;
+            }
+            if k == 1 {
+                arg_col = line_col;
+            }
+        } else {
+            if blank {
+                out.push('\n');
+            }
+            out.push_str(&newline(indent));
+            line_col = indent;
+        }
+        let text = layout(item, line_col, item_brackets(k));
+        line_col = match text.rfind('\n') {
+            Some(n) => text.len() - n - 1,
+            None => line_col + text.len(),
+        };
+        out.push_str(&text);
+        must_break = !item.is_code();
+        blank = false;
+        if item.is_code() {
+            k += 1;
+        }
+    }
+    if must_break {
+        out.push_str(&newline(col));
+    }
+    out.push_str(close);
+    out
+}
+
+fn newline(col: usize) -> String {
+    format!("\n{}", " ".repeat(col))
+}
+
+impl fmt::Display for Datum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", pretty(self, 0))
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", unparse(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::{
+        lexer::Lexer,
+        parser::{AppExpr, CondExpr, DefineExpr, Expr, LambdaExpr, LetExpr, Parser},
+    };
+
+    use super::*;
+
+    fn parse(rkt: &str) -> Node {
+        Parser::parse(Lexer::lex(rkt.into()))
+    }
+
+    #[test]
+    fn short_forms_stay_on_one_line() {
+        let rkt = "(let* ([x 1] [y '(a 2.0)]) (cond [(= x 1) `(,x ,@y)] [else -3]))";
+        assert_eq!(parse(rkt).to_string(), rkt);
+    }
+
+    #[test]
+    fn long_forms_are_indented() {
+        let node = parse(
+            "(define (f xs) (let loop ([xs xs] [acc 0]) \
+             (if (empty? xs) acc (loop (rest xs) (+ acc (first xs) 1234567890)))))",
+        );
+        assert_eq!(
+            node.to_string(),
+            "\
+(define (f xs)
+  (let loop ([xs xs] [acc 0])
+    (if (empty? xs) acc (loop (rest xs) (+ acc (first xs) 1234567890)))))"
+        );
+        let node = parse(
+            "(cond [(= aaaaaaaaaaaaaaaa bbbbbbbbbbbbbbbb) (cccccccccccccccccccc dddddddddddd)] \
+             [else (eeeeeeeeeeeeeeeeeeeeeeeeeeeeeee ffffffffffffffffffffffffffffff gggggggggggg)])",
+        );
+        assert_eq!(
+            node.to_string(),
+            "\
+(cond
+  [(= aaaaaaaaaaaaaaaa bbbbbbbbbbbbbbbb) (cccccccccccccccccccc dddddddddddd)]
+  [else (eeeeeeeeeeeeeeeeeeeeeeeeeeeeeee ffffffffffffffffffffffffffffff
+                                         gggggggggggg)])"
+        );
+    }
+
+    #[test]
+    fn identifiers_like_special_floats_are_heads() {
+        let node = parse(
+            "(inf aaaaaaaaaaaaaaaaaaaaaaaaaaaaaa bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb cccccccccccccccc)",
+        );
+        assert_eq!(
+            node.to_string(),
+            "\
+(inf aaaaaaaaaaaaaaaaaaaaaaaaaaaaaa
+     bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb
+     cccccccccccccccc)"
+        );
+    }
+
+    const KEYWORDS: &[&str] = &[
+        "let",
+        "let*",
+        "letrec",
+        "lambda",
+        "cond",
+        "else",
+        "define",
+        "define-syntax",
+        "begin",
+        "quote",
+        "quasiquote",
+        "unquote",
+        "unquote-splicing",
+    ];
+
+    fn ident() -> impl Strategy<Value = String> {
+        "[a-z][a-z0-9*?!-]{0,7}".prop_filter("keyword", |s| !KEYWORDS.contains(&&s[..]))
+    }
+
+    fn float() -> impl Strategy<Value = f64> {
+        any::<f64>().prop_filter("finite", |f| f.is_finite())
+    }
+
+    fn datum() -> impl Strategy<Value = Datum> {
+        let leaf = prop_oneof![
+            ident().prop_map(Datum::Symbol),
+            any::<i64>().prop_map(Datum::Integer),
+            float().prop_map(Datum::Float),
+            "[a-z \"\\\\\n\t]{0,6}".prop_map(Datum::Str),
+        ];
+        leaf.prop_recursive(3, 16, 4, |inner| {
+            prop::collection::vec(inner, 0..4).prop_map(Datum::List)
+        })
+    }
+
+    /// Templates in the form the parser makes them, with unquotes in every `List`
+    fn template(node: BoxedStrategy<Node>) -> impl Strategy<Value = Template> {
+        let leaf = prop_oneof![
+            datum().prop_map(Template::Datum),
+            node.clone().prop_map(Template::Unquote),
+        ];
+        leaf.prop_recursive(3, 12, 4, move |inner| {
+            let item = prop_oneof![inner, node.clone().prop_map(Template::UnquoteSplicing)];
+            prop::collection::vec(item, 0..4).prop_map(|items| {
+                if items.iter().all(|t| matches!(t, Template::Datum(_))) {
+                    let data = items
+                        .into_iter()
+                        .map(|t| match t {
+                            Template::Datum(d) => d,
+                            _ => unreachable!(),
+                        })
+                        .collect();
+                    Template::Datum(Datum::List(data))
+                } else {
+                    Template::List(items)
+                }
+            })
+        })
+    }
+
+    fn node() -> impl Strategy<Value = Node> {
+        let leaf = prop_oneof![
+            any::<i64>().prop_map(Node::Integer),
+            float().prop_map(Node::Float),
+            ident().prop_map(Node::String),
+            datum().prop_map(|d| Node::Quote(Box::new(d))),
+        ];
+        leaf.prop_recursive(4, 48, 4, |inner| {
+            let body = prop_oneof![
+                inner.clone(),
+                prop::collection::vec(inner.clone(), 2..4).prop_map(Node::Begin),
+            ];
+            let kind = prop_oneof![
+                Just(LetKind::Let),
+                Just(LetKind::LetStar),
+                Just(LetKind::Letrec),
+                ident().prop_map(LetKind::Named),
+            ];
+            let params = prop::collection::vec(ident(), 0..3).boxed();
+            prop_oneof![
+                (ident(), prop::collection::vec(inner.clone(), 0..4))
+                    .prop_map(|(op, params)| Node::Expr(Expr::new(op, params))),
+                (
+                    // Operators that unparse to a symbol or string would read back as an `Expr`
+                    // or not at all
+                    inner
+                        .clone()
+                        .prop_filter("list", |op| matches!(unparse(op), Datum::List(_))),
+                    prop::collection::vec(inner.clone(), 0..4)
+                )
+                    .prop_map(|(op, params)| Node::AppExpr(Box::new(AppExpr { op, params }))),
+                (
+                    kind,
+                    prop::collection::vec((ident(), inner.clone()), 0..3),
+                    body.clone()
+                )
+                    .prop_map(|(kind, bindings, body)| Node::LetExpr(Box::new(
+                        LetExpr {
+                            kind,
+                            bindings,
+                            body
+                        }
+                    ))),
+                (params.clone(), body.clone()).prop_map(|(params, body)| Node::LambdaExpr(
+                    Box::new(LambdaExpr { params, body })
+                )),
+                (
+                    prop::collection::vec((inner.clone(), body.clone()), 0..3),
+                    prop::option::of(body.clone())
+                )
+                    .prop_map(|(clauses, else_body)| Node::CondExpr(Box::new(
+                        CondExpr { clauses, else_body }
+                    ))),
+                (ident(), prop::option::of(params), body).prop_map(|(name, params, body)| {
+                    Node::DefineExpr(Box::new(DefineExpr { name, params, body }))
+                }),
+                prop::collection::vec(inner.clone(), 1..4).prop_map(Node::Begin),
+                template(inner.boxed()).prop_map(|t| Node::Quasiquote(Box::new(t))),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(ast in node()) {
+            prop_assert_eq!(parse(&ast.to_string()), ast);
+        }
+    }
+}
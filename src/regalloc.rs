@@ -0,0 +1,383 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    compiler::Reg,
+    ir::{Block, Function, Inst, Lit, VReg},
+};
+
+/// Registers that calls leave alone. A function that uses them has to save them, so they are
+/// only tried once the caller-saved ones have run out.
+pub const CALLEE_SAVED: [Reg; 5] = [Reg::RBX, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+/// Registers that calls may overwrite. These are also the parameter registers.
+pub const CALLER_SAVED: [Reg; 6] = [Reg::RDI, Reg::RSI, Reg::RDX, Reg::RCX, Reg::R8, Reg::R9];
+
+/// Where a virtual register lives
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Loc {
+    Reg(Reg),
+    /// A stack slot, numbered from 0. Values whose intervals don't overlap may share one.
+    Stack(usize),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Allocation {
+    /// The location of each virtual register, or `None` if it is never used
+    pub locs: Vec<Option<Loc>>,
+    /// Number of stack slots used
+    pub slots: usize,
+    /// The callee-saved registers used, which the function has to save and restore
+    pub saved: Vec<Reg>,
+}
+
+/// The positions from the first to the last where a virtual register is live
+///
+/// Instruction `n` of the function, counting through the blocks in order, reads its operands at
+/// position `2n + 2` and writes its result at `2n + 3`. Parameters are written at position 1.
+#[derive(Debug, Clone, Copy)]
+struct Interval {
+    vreg: VReg,
+    start: usize,
+    end: usize,
+}
+
+/// Assigns every virtual register of a function a machine register or a spill slot, using
+/// linear scan
+///
+/// Values that live across a call are always spilled, because the garbage collector only looks
+/// for the values of the functions further up the stack in their frames. When there aren't
+/// enough registers for the others, the value whose interval ends last is spilled.
+pub fn allocate(func: &Function) -> Allocation {
+    let (intervals, calls) = intervals(func);
+    let mut locs = vec![None; func.vregs];
+    // The intervals spilled to each stack slot
+    let mut slots: Vec<Vec<Interval>> = Vec::new();
+    let mut saved = BTreeSet::new();
+    // Intervals holding a register, by the order they were given one
+    let mut active: Vec<(Interval, Reg)> = Vec::new();
+    for interval in intervals {
+        active.retain(|(a, _)| a.end >= interval.start);
+        let crosses = calls
+            .iter()
+            .any(|&call| interval.start < call + 1 && interval.end > call);
+        if crosses {
+            locs[interval.vreg.0] = Some(spill(&mut slots, interval));
+            continue;
+        }
+        let free = CALLER_SAVED
+            .iter()
+            .chain(&CALLEE_SAVED)
+            .find(|reg| active.iter().all(|(_, r)| r != *reg));
+        let reg = match free {
+            Some(&reg) => Some(reg),
+            None => {
+                let victim = active
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(i, (a, _))| (a.end, usize::MAX - i))
+                    .map(|(i, (a, _))| (i, a.end));
+                match victim {
+                    Some((i, end)) if end > interval.end => {
+                        let (spilled, reg) = active.remove(i);
+                        locs[spilled.vreg.0] = Some(spill(&mut slots, spilled));
+                        Some(reg)
+                    }
+                    _ => None,
+                }
+            }
+        };
+        match reg {
+            Some(reg) => {
+                if CALLEE_SAVED.contains(&reg) {
+                    saved.insert(reg);
+                }
+                locs[interval.vreg.0] = Some(Loc::Reg(reg));
+                active.push((interval, reg));
+            }
+            None => locs[interval.vreg.0] = Some(spill(&mut slots, interval)),
+        }
+    }
+    Allocation {
+        locs,
+        slots: slots.len(),
+        saved: CALLEE_SAVED
+            .into_iter()
+            .filter(|r| saved.contains(r))
+            .collect(),
+    }
+}
+
+/// Puts an interval in the first stack slot that is free for all of it
+fn spill(slots: &mut Vec<Vec<Interval>>, interval: Interval) -> Loc {
+    let free = slots.iter().position(|slot| {
+        slot.iter()
+            .all(|i| i.end < interval.start || interval.end < i.start)
+    });
+    let i = free.unwrap_or_else(|| {
+        slots.push(Vec::new());
+        slots.len() - 1
+    });
+    slots[i].push(interval);
+    Loc::Stack(i)
+}
+
+/// Whether an instruction calls into the runtime or another function, overwriting the
+/// caller-saved registers
+///
+/// Allocating a box, which includes `cons`, doesn't count: it is done inline, and the garbage
+/// collector it may call preserves every register, updating the ones that hold values.
+pub fn is_call(inst: &Inst) -> bool {
+    match inst {
+        Inst::Call(_, name, _) => name != "cons",
+        Inst::CallStack(..) | Inst::Apply(..) => true,
+        Inst::Lit(..)
+        | Inst::Move(..)
+        | Inst::Closure(..)
+        | Inst::Captured(..)
+        | Inst::NewCell(..)
+        | Inst::CellGet(..)
+        | Inst::CellSet(..) => false,
+    }
+}
+
+/// Whether an instruction allocates a box inline, so that the garbage collector may run in the
+/// middle of it
+pub fn allocates(inst: &Inst) -> bool {
+    match inst {
+        Inst::Lit(_, lit) => matches!(lit, Lit::Float(_) | Lit::Symbol(_) | Lit::Str(_)),
+        Inst::Call(_, name, _) => name == "cons",
+        Inst::Closure(..) | Inst::NewCell(..) => true,
+        Inst::Move(..)
+        | Inst::CallStack(..)
+        | Inst::Apply(..)
+        | Inst::Captured(..)
+        | Inst::CellGet(..)
+        | Inst::CellSet(..) => false,
+    }
+}
+
+/// The virtual registers holding values that have to survive each instruction, by block and
+/// then by instruction
+///
+/// These are the ones live after the instruction, other than the one it writes, and for an
+/// instruction that allocates, the ones it reads, which are stored in the box afterwards.
+pub fn live_across(func: &Function) -> Vec<Vec<Vec<VReg>>> {
+    let live_out = live_out(func);
+    func.blocks
+        .iter()
+        .zip(live_out)
+        .map(|(block, mut live)| {
+            live.extend(block.term.uses());
+            let mut across: Vec<Vec<VReg>> = Vec::new();
+            for inst in block.insts.iter().rev() {
+                if let Some(d) = inst.def() {
+                    live.remove(&d);
+                }
+                let mut kept = live.clone();
+                if allocates(inst) {
+                    kept.extend(inst.uses());
+                }
+                across.push(kept.into_iter().collect());
+                live.extend(inst.uses());
+            }
+            across.reverse();
+            across
+        })
+        .collect()
+}
+
+/// Finds the live interval of every virtual register that is used, sorted by where they start,
+/// and the positions at which calls read their operands
+fn intervals(func: &Function) -> (Vec<Interval>, Vec<usize>) {
+    let live_out = live_out(func);
+    let mut ranges: Vec<Option<(usize, usize)>> = vec![None; func.vregs];
+    let mut extend = |v: VReg, pos: usize| {
+        let range = ranges[v.0].get_or_insert((pos, pos));
+        range.0 = range.0.min(pos);
+        range.1 = range.1.max(pos);
+    };
+    for param in &func.params {
+        extend(*param, 1);
+    }
+    let mut calls = Vec::new();
+    let mut n = 0;
+    for (b, block) in func.blocks.iter().enumerate() {
+        let start = 2 * n + 2;
+        for v in live_in(block, &live_out[b]) {
+            extend(v, start);
+        }
+        for inst in &block.insts {
+            let (read, write) = (2 * n + 2, 2 * n + 3);
+            // A closure's captured values are copied into it after it has been allocated
+            let read = if matches!(inst, Inst::Closure(..)) {
+                write
+            } else {
+                read
+            };
+            for v in inst.uses() {
+                extend(v, read);
+            }
+            if let Some(d) = inst.def() {
+                extend(d, write);
+            }
+            if is_call(inst) {
+                calls.push(2 * n + 2);
+            }
+            n += 1;
+        }
+        let end = 2 * n + 2;
+        for v in block.term.uses() {
+            extend(v, end);
+        }
+        for v in &live_out[b] {
+            extend(*v, end);
+        }
+        n += 1;
+    }
+    let mut intervals: Vec<_> = ranges
+        .into_iter()
+        .enumerate()
+        .filter_map(|(v, range)| {
+            range.map(|(start, end)| Interval {
+                vreg: VReg(v),
+                start,
+                end,
+            })
+        })
+        .collect();
+    intervals.sort_by_key(|i| (i.start, i.vreg));
+    (intervals, calls)
+}
+
+/// The virtual registers live at the end of each block
+fn live_out(func: &Function) -> Vec<BTreeSet<VReg>> {
+    let mut live_out = vec![BTreeSet::new(); func.blocks.len()];
+    loop {
+        let mut changed = false;
+        for (b, block) in func.blocks.iter().enumerate().rev() {
+            let out: BTreeSet<_> = block
+                .term
+                .successors()
+                .iter()
+                .flat_map(|s| live_in(&func.blocks[s.0], &live_out[s.0]))
+                .collect();
+            if out != live_out[b] {
+                live_out[b] = out;
+                changed = true;
+            }
+        }
+        if !changed {
+            return live_out;
+        }
+    }
+}
+
+/// The virtual registers live at the start of a block, given those live at its end
+fn live_in(block: &Block, live_out: &BTreeSet<VReg>) -> BTreeSet<VReg> {
+    let mut live = live_out.clone();
+    live.extend(block.term.uses());
+    for inst in block.insts.iter().rev() {
+        if let Some(d) = inst.def() {
+            live.remove(&d);
+        }
+        live.extend(inst.uses());
+    }
+    live
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ir::Module, lexer::Lexer, parser::Parser};
+
+    use super::*;
+
+    fn allocate_main(rkt: &str) -> (Function, Allocation) {
+        let module = Module::new(&Parser::parse(Lexer::lex(rkt.into())));
+        let func = module.functions.into_iter().next().unwrap();
+        let alloc = allocate(&func);
+        (func, alloc)
+    }
+
+    /// Checks that no two values live at once share a register, and that none that is live
+    /// across a call is in a register
+    fn check(func: &Function, alloc: &Allocation) {
+        let (intervals, calls) = intervals(func);
+        for (i, a) in intervals.iter().enumerate() {
+            let Some(Loc::Reg(reg)) = alloc.locs[a.vreg.0] else {
+                continue;
+            };
+            for b in &intervals[i + 1..] {
+                let overlaps = a.start <= b.end && b.start <= a.end;
+                assert!(
+                    !overlaps || alloc.locs[b.vreg.0] != Some(Loc::Reg(reg)),
+                    "{} and {} share {reg:?}",
+                    a.vreg,
+                    b.vreg
+                );
+            }
+            assert!(
+                !calls.iter().any(|&c| a.start < c + 1 && a.end > c),
+                "{} is in {reg:?} across a call",
+                a.vreg
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_values_apart() {
+        let (func, alloc) = allocate_main("(let ([x 1] [y 2]) (if (= x y) x (+ x y)))");
+        check(&func, &alloc);
+        // Without calls, nothing has to be spilled
+        let (func, alloc) = allocate_main("(let ([x 1] [y 2]) (if x (cons x y) (cons y x)))");
+        check(&func, &alloc);
+        assert_eq!(alloc.slots, 0);
+    }
+
+    #[test]
+    fn spills_when_out_of_registers() {
+        let names: Vec<_> = (0..12).map(|i| format!("x{i}")).collect();
+        let bindings: Vec<_> = names.iter().map(|x| format!("[{x} 1]")).collect();
+        let rkt = format!("(let* ({}) (list {}))", bindings.join(" "), names.join(" "));
+        let (func, alloc) = allocate_main(&rkt);
+        check(&func, &alloc);
+        assert_eq!(alloc.saved, CALLEE_SAVED);
+        assert!(alloc.slots > 0);
+    }
+
+    #[test]
+    fn keeps_values_across_allocations() {
+        let (func, _) = allocate_main("(let ([x (list 1)]) (cons x 2.5))");
+        let across = live_across(&func);
+        let mut float = None;
+        for (block, sets) in func.blocks.iter().zip(&across) {
+            for (inst, set) in block.insts.iter().zip(sets) {
+                if allocates(inst) {
+                    assert!(inst.uses().iter().all(|v| set.contains(v)), "{inst:?}");
+                }
+                if let Some(d) = inst.def() {
+                    assert!(!set.contains(&d), "{inst:?}");
+                }
+                if matches!(inst, Inst::Lit(_, Lit::Float(_))) {
+                    float = Some(set);
+                }
+            }
+        }
+        // x has to survive the boxing of 2.5
+        assert_eq!(float.map(Vec::len), Some(1));
+    }
+
+    #[test]
+    fn shares_stack_slots() {
+        let mut slots = Vec::new();
+        let interval = |v, start, end| Interval {
+            vreg: VReg(v),
+            start,
+            end,
+        };
+        assert_eq!(spill(&mut slots, interval(0, 2, 10)), Loc::Stack(0));
+        assert_eq!(spill(&mut slots, interval(1, 4, 6)), Loc::Stack(1));
+        assert_eq!(spill(&mut slots, interval(2, 8, 12)), Loc::Stack(1));
+        assert_eq!(spill(&mut slots, interval(3, 11, 14)), Loc::Stack(0));
+        assert_eq!(spill(&mut slots, interval(4, 12, 12)), Loc::Stack(2));
+    }
+}
@@ -0,0 +1,638 @@
+; Integers too big to be fixnums
+;
+; A bignum is a box of type 6. Its first field is the number of limbs it uses
+; times 2, plus 1 if it is negative. The limbs follow: the magnitude in base
+; 2^64, least significant first. None of its fields are values, and it may have
+; room for more limbs than it uses.
+;
+; An integer is only ever a bignum if it doesn't fit in a fixnum, so every
+; integer has one representation and bignums are never 0.
+;
+; The routines below work on views of integers: the address of a word holding
+; the length and sign, followed by the limbs. The view of a bignum is inside the
+; box, so they don't allocate while they hold one. Until they have allocated
+; their results, they keep the integers they were given in a frame.
+
+section .text
+; Integers
+;   Arguments: values in rdi and rsi
+;   Sets the carry flag if both are integers, fixnums or bignums, and clears it
+;   if not
+;   Only modifies rax
+integers:
+    mov     rax, rdi
+    call    integer
+    jnc     integers_done
+    mov     rax, rsi
+    jmp     integer
+integers_done:
+    ret
+
+; Integer
+;   Arguments: value in rax
+;   Sets the carry flag if it is a fixnum or a bignum, and clears it if not
+;   Doesn't modify any registers
+integer:
+    test    al, 2
+    jnz     integer_yes
+    test    al, 1
+    jnz     integer_no
+    test    rax, rax
+    jz      integer_no
+    cmp     byte [rax], 6
+    jne     integer_no
+integer_yes:
+    stc
+    ret
+integer_no:
+    clc
+    ret
+
+; Negative
+;   Arguments: integer in rax
+;   Returns 1 in rax if it is negative, 0 if not
+negative:
+    test    al, 2
+    jnz     negative_fixnum
+    mov     rax, [rax+8]
+    and     eax, 1
+    ret
+negative_fixnum:
+    shr     rax, 63
+    ret
+
+; BigLength
+;   Arguments: integer in rdi
+;   Returns in rax the number of limbs it needs, which is 1 for a fixnum
+;   Only modifies rax
+biglength:
+    mov     eax, 1
+    test    dil, 2
+    jnz     biglength_done
+    mov     rax, [rdi+8]
+    shr     rax, 1
+biglength_done:
+    ret
+
+; BigNew
+;   Arguments: number of limbs in rdi
+;   Returns in rax a bignum with room for that many limbs, all 0, which uses
+;   none of them
+;   Only modifies rax and rcx
+bignew:
+    push    rdi
+    lea     rdi, [rdi*8+16]
+    call    alloc
+    pop     rdi
+    lea     rcx, [rdi+1]
+    shl     rcx, 16
+    or      rcx, 0xff06             ; no fields are values, type 6
+    mov     [rax], rcx
+    mov     qword [rax+8], 0
+    xor     ecx, ecx
+bignew_limb:
+    cmp     rcx, rdi
+    jae     bignew_done
+    mov     qword [rax+rcx*8+16], 0
+    inc     rcx
+    jmp     bignew_limb
+bignew_done:
+    ret
+
+; BigView
+;   Arguments: integer in rdi, a buffer of two words in rsi
+;   Returns the view of the integer in rax. A fixnum is written to the buffer.
+;   Only modifies rax and rcx
+bigview:
+    test    dil, 2
+    jz      bigview_bignum
+    mov     rax, rdi
+    sar     rax, 2
+    mov     ecx, 0
+    jz      bigview_fixnum      ; 0 has no limbs
+    mov     ecx, 2              ; one limb
+    jns     bigview_limb
+    neg     rax
+    mov     ecx, 3              ; one limb, negative
+bigview_limb:
+    mov     [rsi+8], rax
+bigview_fixnum:
+    mov     [rsi], rcx
+    mov     rax, rsi
+    ret
+bigview_bignum:
+    lea     rax, [rdi+8]
+    ret
+
+; BigNormalize
+;   Arguments: a bignum in rdi, the number of its limbs that may be in use in
+;   rsi, and 1 in rdx if it is negative or 0 if not
+;   Drops the zero limbs at the top, and returns in rax the integer, which is a
+;   fixnum if it fits in one
+;   Only modifies rax, rcx and rsi
+bignormalize:
+    test    rsi, rsi
+    jz      bignormalize_zero
+    cmp     qword [rdi+rsi*8+8], 0
+    jne     bignormalize_trimmed
+    dec     rsi
+    jmp     bignormalize
+bignormalize_zero:
+    mov     eax, 2
+    ret
+bignormalize_trimmed:
+    cmp     rsi, 1
+    jne     bignormalize_bignum
+    mov     rax, [rdi+16]
+    mov     rcx, 0x2000000000000000 ; 2^61
+    test    rdx, rdx
+    jnz     bignormalize_negative
+    cmp     rax, rcx
+    jae     bignormalize_bignum
+    lea     rax, [rax*4+2]
+    ret
+bignormalize_negative:
+    cmp     rax, rcx
+    ja      bignormalize_bignum
+    neg     rax
+    lea     rax, [rax*4+2]
+    ret
+bignormalize_bignum:
+    lea     rax, [rsi*2+rdx]
+    mov     [rdi+8], rax
+    mov     rax, rdi
+    ret
+
+; BigAdd
+;   Arguments: integers in rdi and rsi, and 1 in rdx to subtract the second
+;   instead of adding it, or 0
+;   Returns the result in rax
+bigadd:
+    push    rbx
+    push    r12
+    push    r13
+    push    r14
+    push    r15
+    push    rbp
+    mov     rbp, rsp
+    push    roots2
+    push    rdi
+    push    rsi
+    mov     r13, rdx
+    call    biglength
+    mov     r8, rax
+    mov     rdi, rsi
+    call    biglength
+    cmp     rax, r8
+    cmovb   rax, r8
+    lea     rdi, [rax+1]
+    call    bignew
+    mov     r14, rax
+    mov     rdi, [rbp-16]
+    mov     rsi, view_a
+    call    bigview
+    mov     rbx, rax
+    mov     rdi, [rbp-24]
+    mov     rsi, view_b
+    call    bigview
+    mov     r12, rax
+    mov     r15, [r12]
+    and     r15d, 1
+    xor     r15, r13            ; the sign of what is added
+    mov     r13, [rbx]
+    and     r13d, 1             ; the sign of the result, unless it changes
+    cmp     r13, r15
+    jne     bigadd_differ
+    mov     rdi, rbx
+    mov     rsi, r12
+    lea     rdx, [r14+16]
+    call    magadd
+    jmp     bigadd_normalize
+bigadd_differ:
+    ; The smaller magnitude is taken from the larger, whose sign is kept
+    mov     rdi, rbx
+    mov     rsi, r12
+    call    magcompare
+    test    eax, eax
+    jns     bigadd_subtract
+    xchg    rbx, r12
+    mov     r13, r15
+bigadd_subtract:
+    mov     rdi, rbx
+    mov     rsi, r12
+    lea     rdx, [r14+16]
+    call    magsub
+bigadd_normalize:
+    mov     rdi, r14
+    mov     rsi, rax
+    mov     rdx, r13
+    call    bignormalize
+    jmp     bigdone
+
+; BigMul
+;   Arguments: integers in rdi and rsi
+;   Returns their product in rax
+bigmul:
+    push    rbx
+    push    r12
+    push    r13
+    push    r14
+    push    r15
+    push    rbp
+    mov     rbp, rsp
+    push    roots2
+    push    rdi
+    push    rsi
+    call    biglength
+    mov     r8, rax
+    mov     rdi, rsi
+    call    biglength
+    lea     rdi, [rax+r8]
+    call    bignew
+    mov     r14, rax
+    mov     rdi, [rbp-16]
+    mov     rsi, view_a
+    call    bigview
+    mov     rbx, rax
+    mov     rdi, [rbp-24]
+    mov     rsi, view_b
+    call    bigview
+    mov     r12, rax
+    mov     r13, [rbx]
+    xor     r13, [r12]
+    and     r13d, 1
+    mov     rdi, rbx
+    mov     rsi, r12
+    lea     rdx, [r14+16]
+    call    magmul
+    mov     rdi, r14
+    mov     rsi, rax
+    mov     rdx, r13
+    call    bignormalize
+    jmp     bigdone
+
+; BigDivide
+;   Arguments: integers in rdi and rsi, the second not 0
+;   Returns the quotient, rounded towards zero, in rax and the remainder in rdx
+bigdivide:
+    push    rbx
+    push    r12
+    push    r13
+    push    r14
+    push    r15
+    push    rbp
+    mov     rbp, rsp
+    push    roots3
+    push    rdi
+    push    rsi
+    push    0                   ; the remainder, once there is one
+    call    biglength
+    mov     r13, rax
+    mov     rdi, rsi
+    call    biglength
+    lea     rdi, [rax+1]
+    call    bignew
+    mov     [rbp-32], rax
+    mov     rdi, r13
+    call    bignew
+    mov     r14, rax            ; the quotient
+    mov     r15, [rbp-32]       ; the remainder
+    mov     rdi, [rbp-16]
+    mov     rsi, view_a
+    call    bigview
+    mov     rbx, rax
+    mov     rdi, [rbp-24]
+    mov     rsi, view_b
+    call    bigview
+    mov     r12, rax
+    mov     rdi, rbx
+    mov     rsi, r12
+    lea     rdx, [r14+16]
+    lea     rcx, [r15+16]
+    call    magdivide
+    ; The quotient is negative if the signs differ, and the remainder has the
+    ; sign of the dividend
+    mov     rdi, r14
+    mov     rsi, [rbx]
+    shr     rsi, 1
+    mov     rdx, [rbx]
+    xor     rdx, [r12]
+    and     edx, 1
+    call    bignormalize
+    mov     r14, rax
+    mov     rdi, r15
+    mov     rsi, [r12]
+    shr     rsi, 1
+    mov     rdx, [rbx]
+    and     edx, 1
+    call    bignormalize
+    mov     rdx, rax
+    mov     rax, r14
+
+; Takes down the frame of one of the routines above, and returns from it
+bigdone:
+    mov     rsp, rbp
+    pop     rbp
+    pop     r15
+    pop     r14
+    pop     r13
+    pop     r12
+    pop     rbx
+    ret
+
+; BigDouble
+;   Arguments: bignum in rax
+;   Returns the double nearest to it in xmm2, give or take the last bit
+;   Only modifies xmm2 and xmm3
+bigdouble:
+    push    rcx
+    push    rdx
+    push    rbx
+    mov     rcx, [rax+8]
+    shr     rcx, 1
+    xorpd   xmm2, xmm2
+bigdouble_limb:
+    mulsd   xmm2, [two_to_64]
+    mov     rdx, [rax+rcx*8+8]
+    test    rdx, rdx
+    js      bigdouble_high
+    cvtsi2sd xmm3, rdx
+    jmp     bigdouble_add
+bigdouble_high:
+    ; Too big to convert as a signed integer, so it is halved, keeping the low
+    ; bit so it rounds the same way
+    mov     rbx, rdx
+    and     ebx, 1
+    shr     rdx, 1
+    or      rdx, rbx
+    cvtsi2sd xmm3, rdx
+    addsd   xmm3, xmm3
+bigdouble_add:
+    addsd   xmm2, xmm3
+    dec     rcx
+    jnz     bigdouble_limb
+    test    byte [rax+8], 1
+    jz      bigdouble_done
+    mov     rdx, 0x8000000000000000
+    movq    xmm3, rdx
+    xorpd   xmm2, xmm3
+bigdouble_done:
+    pop     rbx
+    pop     rdx
+    pop     rcx
+    ret
+
+; The magnitude routines take views in rdi and rsi, and the limbs of a result
+; in rdx, which has room for as many as they may need. They don't allocate.
+
+; MagCompare
+;   Returns in eax 1 if the first magnitude is bigger, -1 if it is smaller and
+;   0 if they are the same
+;   Only modifies rax, rcx and rdx
+magcompare:
+    mov     rax, [rdi]
+    shr     rax, 1
+    mov     rcx, [rsi]
+    shr     rcx, 1
+    cmp     rax, rcx
+    ja      magcompare_bigger
+    jb      magcompare_smaller
+magcompare_limb:
+    test    rcx, rcx
+    jz      magcompare_same
+    mov     rdx, [rdi+rcx*8]
+    cmp     rdx, [rsi+rcx*8]
+    ja      magcompare_bigger
+    jb      magcompare_smaller
+    dec     rcx
+    jmp     magcompare_limb
+magcompare_same:
+    xor     eax, eax
+    ret
+magcompare_bigger:
+    mov     eax, 1
+    ret
+magcompare_smaller:
+    mov     eax, -1
+    ret
+
+; MagAdd
+;   Adds the magnitudes, and returns the number of limbs written in rax, one
+;   more than the longer has
+magadd:
+    mov     r8, [rdi]
+    shr     r8, 1
+    mov     r9, [rsi]
+    shr     r9, 1
+    cmp     r8, r9
+    jae     magadd_ordered
+    xchg    rdi, rsi
+    xchg    r8, r9
+magadd_ordered:
+    xor     r10d, r10d          ; carry
+    xor     ecx, ecx
+magadd_limb:
+    cmp     rcx, r8
+    jae     magadd_done
+    xor     r11d, r11d
+    cmp     rcx, r9
+    jae     magadd_add
+    mov     r11, [rsi+rcx*8+8]
+magadd_add:
+    mov     rax, [rdi+rcx*8+8]
+    add     rax, r10
+    mov     r10d, 0
+    adc     r10, 0
+    add     rax, r11
+    adc     r10, 0
+    mov     [rdx+rcx*8], rax
+    inc     rcx
+    jmp     magadd_limb
+magadd_done:
+    mov     [rdx+r8*8], r10
+    lea     rax, [r8+1]
+    ret
+
+; MagSub
+;   Takes the second magnitude from the first, which can't be smaller, and
+;   returns the number of limbs written in rax, as many as the first has
+magsub:
+    mov     r8, [rdi]
+    shr     r8, 1
+    mov     r9, [rsi]
+    shr     r9, 1
+    xor     r10d, r10d          ; borrow
+    xor     ecx, ecx
+magsub_limb:
+    cmp     rcx, r8
+    jae     magsub_done
+    xor     r11d, r11d
+    cmp     rcx, r9
+    jae     magsub_sub
+    mov     r11, [rsi+rcx*8+8]
+magsub_sub:
+    mov     rax, [rdi+rcx*8+8]
+    sub     rax, r10
+    mov     r10d, 0
+    adc     r10, 0
+    sub     rax, r11
+    adc     r10, 0
+    mov     [rdx+rcx*8], rax
+    inc     rcx
+    jmp     magsub_limb
+magsub_done:
+    mov     rax, r8
+    ret
+
+; MagMul
+;   Multiplies the magnitudes into limbs that are all 0, and returns the number
+;   of limbs written in rax, as many as the two have together
+magmul:
+    push    rbx
+    push    r12
+    mov     rbx, [rdi]
+    shr     rbx, 1
+    mov     r12, [rsi]
+    shr     r12, 1
+    mov     r8, rdx
+    xor     r9d, r9d            ; limb of the first
+magmul_row:
+    cmp     r9, rbx
+    jae     magmul_done
+    mov     rcx, [rdi+r9*8+8]
+    xor     r10d, r10d          ; limb of the second
+    xor     r11d, r11d          ; carry
+magmul_limb:
+    cmp     r10, r12
+    jae     magmul_next
+    mov     rax, [rsi+r10*8+8]
+    mul     rcx
+    add     rax, r11
+    adc     rdx, 0
+    lea     r11, [r9+r10]
+    add     [r8+r11*8], rax
+    adc     rdx, 0
+    mov     r11, rdx
+    inc     r10
+    jmp     magmul_limb
+magmul_next:
+    lea     rax, [r9+r12]
+    mov     [r8+rax*8], r11
+    inc     r9
+    jmp     magmul_row
+magmul_done:
+    lea     rax, [rbx+r12]
+    pop     r12
+    pop     rbx
+    ret
+
+; MagDivide
+;   Divides the first magnitude by the second, which isn't 0. The quotient goes
+;   in the limbs in rdx, which has room for as many as the first has, and the
+;   remainder in the limbs in rcx, which has room for one more than the second
+;   has. Both are all 0 to start with.
+magdivide:
+    mov     r8, rdx
+    mov     r9, rcx
+    mov     r10, [rdi]
+    shr     r10, 1
+    mov     rax, [rsi]
+    shr     rax, 1
+    cmp     rax, 1
+    jne     magdivide_long
+    ; A divisor of one limb divides the first a limb at a time
+    mov     r11, [rsi+8]
+    xor     edx, edx
+magdivide_short:
+    test    r10, r10
+    jz      magdivide_short_done
+    dec     r10
+    mov     rax, [rdi+r10*8+8]
+    div     r11
+    mov     [r8+r10*8], rax
+    jmp     magdivide_short
+magdivide_short_done:
+    mov     [r9], rdx
+    ret
+magdivide_long:
+    ; Otherwise the bits of the first are shifted into the remainder one at a
+    ; time, and the divisor is taken from it whenever it fits
+    push    rbx
+    push    r12
+    push    r13
+    mov     r12, rax            ; limbs in the divisor
+    mov     rbx, r10
+    shl     rbx, 6              ; bits left
+magdivide_bit:
+    test    rbx, rbx
+    jz      magdivide_done
+    dec     rbx
+    mov     rax, rbx
+    shr     rax, 6
+    mov     rax, [rdi+rax*8+8]
+    mov     ecx, ebx
+    and     ecx, 63
+    shr     rax, cl
+    and     eax, 1
+    mov     r10, rax            ; the bit shifted in
+    xor     r13d, r13d
+magdivide_shift:
+    mov     rax, [r9+r13*8]
+    mov     r11, rax
+    shr     r11, 63
+    shl     rax, 1
+    or      rax, r10
+    mov     [r9+r13*8], rax
+    mov     r10, r11
+    inc     r13
+    cmp     r13, r12
+    jbe     magdivide_shift
+    ; Does the divisor fit?
+    cmp     qword [r9+r12*8], 0
+    jne     magdivide_subtract
+    mov     r13, r12
+magdivide_compare:
+    test    r13, r13
+    jz      magdivide_subtract
+    dec     r13
+    mov     rax, [r9+r13*8]
+    cmp     rax, [rsi+r13*8+8]
+    ja      magdivide_subtract
+    jb      magdivide_bit
+    jmp     magdivide_compare
+magdivide_subtract:
+    xor     r10d, r10d          ; borrow
+    xor     r13d, r13d
+magdivide_limb:
+    mov     rax, [r9+r13*8]
+    sub     rax, r10
+    mov     r10d, 0
+    adc     r10, 0
+    sub     rax, [rsi+r13*8+8]
+    adc     r10, 0
+    mov     [r9+r13*8], rax
+    inc     r13
+    cmp     r13, r12
+    jb      magdivide_limb
+    sub     [r9+r12*8], r10
+    mov     rax, rbx
+    shr     rax, 6
+    mov     ecx, ebx
+    and     ecx, 63
+    mov     r11d, 1
+    shl     r11, cl
+    or      [r8+rax*8], r11
+    jmp     magdivide_bit
+magdivide_done:
+    pop     r13
+    pop     r12
+    pop     rbx
+    ret
+
+section .data
+two_to_64:  dq 18446744073709551616.0
+
+section .bss
+; Views of fixnums
+view_a:     resq 2
+view_b:     resq 2
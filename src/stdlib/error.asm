@@ -0,0 +1,81 @@
+; Reporting errors the program can't recover from, the way Racket does
+
+extern stderr
+extern exit
+
+section .text
+; ContractError
+;   Arguments: name of the function in rdi, what it expects in rsi, the value
+;   it was given in rdx
+;   Prints the error to stderr and exits with status 1. Routines jump here
+;   when they are given a value they can't work with.
+contracterror:
+    and     rsp, -16
+    mov     rbx, rdx
+    mov     rcx, rsi
+    mov     rdx, rdi
+    mov     rdi, [stderr]
+    mov     rsi, contract_format
+    xor     eax, eax
+    call    fprintf
+    jmp     errorgiven
+
+; UndefinedError
+;   Arguments: name of the function in rdi, the value it was given in rdx
+;   Reports that the function is undefined for the value, like dividing by 0,
+;   and exits with status 1
+undefinederror:
+    mov     rsi, undefined_text
+
+; ReportError
+;   Arguments: name of the function in rdi, what went wrong in rsi, the value
+;   it was given in rdx
+;   Prints the name and what went wrong, followed by the value, and exits with
+;   status 1
+reporterror:
+    and     rsp, -16
+    mov     rbx, rdx
+    mov     rcx, rsi
+    mov     rdx, rdi
+    mov     rdi, [stderr]
+    mov     rsi, report_format
+    xor     eax, eax
+    call    fprintf
+    jmp     errorgiven
+
+; RuntimeError
+;   Arguments: the message in rdi
+;   Prints the message to stderr and exits with status 1
+runtimeerror:
+    and     rsp, -16
+    mov     rsi, [stderr]
+    call    fputs
+    jmp     errorend
+
+; NotProcedure
+;   Arguments: the value that was applied in rax
+;   Prints the error to stderr and exits with status 1
+notprocedure:
+    and     rsp, -16
+    mov     rbx, rax
+    mov     rdi, not_procedure_text
+    mov     rsi, [stderr]
+    call    fputs
+
+; Ends an error message with the value in rbx, and exits
+errorgiven:
+    mov     rdi, [stderr]
+    mov     rsi, rbx
+    call    fprintvalue
+errorend:
+    mov     edi, 10
+    mov     rsi, [stderr]
+    call    fputc
+    mov     edi, 1
+    call    exit
+
+section .data
+contract_format:    db "%s: contract violation", 10, "  expected: %s", 10, "  given: ", 0
+report_format:      db "%s: %s", 0
+undefined_text:     db "undefined for ", 0
+not_procedure_text: db "application: not a procedure;", 10, " expected a procedure that can be applied to arguments", 10, "  given: ", 0
@@ -0,0 +1,263 @@
+; A copying garbage collector
+;
+; Values are allocated in one space of the heap until it is full. Then every
+; value that can still be reached is copied to a new space, which the program
+; carries on allocating in, and the old space is freed.
+;
+; The collector is precise: it only looks at the words it is told hold values.
+; It finds them by following the rbp links between frames, from the innermost
+; one to the entry function's. The word at rbp-8 in each frame is the address
+; of a root map, which is the number of roots followed by that many entries. A
+; negative entry is the offset of a word in the frame from rbp. Any other entry
+; is the number of a register, as the instructions encode it (rax 0, rcx 1, rdx
+; 2, rbx 3, rsi 6, rdi 7, r8 8 and so on), for a frame that is allocating a box
+; itself.
+;
+; Compiled functions set the map before each call and each collection, and keep
+; the values that live across a call in their frames. Runtime routines that hold
+; values while they allocate make frames too, with one of the maps at the end
+; of this file. Any other words, on the stack or in registers, are left alone,
+; so they don't have to be values.
+
+extern malloc
+extern free
+
+section .text
+; Collect
+;   Arguments: number of bytes that are needed in rax
+;   Copies the reachable values to a new space with room for that many bytes
+;   Preserves every register
+collect:
+    mov     [collect_regs], rax
+    mov     [collect_regs+8], rcx
+    mov     [collect_regs+16], rdx
+    mov     [collect_regs+24], rbx
+    mov     [collect_regs+32], rsp
+    mov     [collect_regs+40], rbp
+    mov     [collect_regs+48], rsi
+    mov     [collect_regs+56], rdi
+    mov     [collect_regs+64], r8
+    mov     [collect_regs+72], r9
+    mov     [collect_regs+80], r10
+    mov     [collect_regs+88], r11
+    mov     [collect_regs+96], r12
+    mov     [collect_regs+104], r13
+    mov     [collect_regs+112], r14
+    mov     [collect_regs+120], r15
+    movsd   [collect_xmm0], xmm0
+    movsd   [collect_xmm1], xmm1
+    mov     r12, rax
+    and     rsp, -16
+collect_again:
+    mov     rdi, [heap_size]
+    call    malloc
+    mov     [to_space], rax
+    mov     r13, rax            ; next free byte in the new space
+    mov     r14, [collect_regs+40]
+collect_frame:
+    mov     r15, [r14-8]        ; the frame's root map
+    mov     rbx, [r15]
+collect_root:
+    test    rbx, rbx
+    jz      collect_next
+    mov     rax, [r15+rbx*8]
+    lea     rbp, [r14+rax]
+    test    rax, rax
+    js      collect_forward
+    lea     rbp, [collect_regs+rax*8]
+collect_forward:
+    mov     rdi, [rbp]
+    call    forward
+    mov     [rbp], rax
+    dec     rbx
+    jmp     collect_root
+collect_next:
+    cmp     r14, [base_frame]
+    je      collect_scan
+    mov     r14, [r14]          ; the caller's frame
+    jmp     collect_frame
+collect_scan:
+    ; The values copied so far may refer to others that haven't been, so their
+    ; fields are forwarded in turn until the copying catches up
+    mov     r14, [to_space]
+collect_value:
+    cmp     r14, r13
+    jae     collect_done
+    mov     r15, [r14]
+    shr     r15, 16
+    lea     r15, [r14+r15*8+8]  ; end of the value
+    movzx   eax, byte [r14+1]
+    lea     r14, [r14+rax*8+8]  ; first field that is a value
+    cmp     al, 0xff
+    cmove   r14, r15            ; none of them are
+collect_field:
+    cmp     r14, r15
+    jae     collect_value
+    mov     rdi, [r14]
+    call    forward
+    mov     [r14], rax
+    add     r14, 8
+    jmp     collect_field
+collect_done:
+    mov     rdi, [heap_start]
+    call    free
+    mov     rax, [to_space]
+    mov     [heap_start], rax
+    mov     [heap_next], r13
+    add     rax, [heap_size]
+    mov     [heap_end], rax
+    ; Make the next space bigger if this one is more than half full, so
+    ; collections don't get more frequent as the program keeps more values
+    mov     rax, r13
+    sub     rax, [heap_start]
+    add     rax, r12
+    shl     rax, 1
+collect_grow:
+    cmp     rax, [heap_size]
+    jbe     collect_check
+    shl     qword [heap_size], 1
+    jmp     collect_grow
+collect_check:
+    mov     rax, r13
+    add     rax, r12
+    cmp     rax, [heap_end]
+    ja      collect_again       ; the request doesn't fit, so copy to a bigger space
+    movsd   xmm0, [collect_xmm0]
+    movsd   xmm1, [collect_xmm1]
+    mov     rax, [collect_regs]
+    mov     rcx, [collect_regs+8]
+    mov     rdx, [collect_regs+16]
+    mov     rbx, [collect_regs+24]
+    mov     rsp, [collect_regs+32]
+    mov     rbp, [collect_regs+40]
+    mov     rsi, [collect_regs+48]
+    mov     rdi, [collect_regs+56]
+    mov     r8, [collect_regs+64]
+    mov     r9, [collect_regs+72]
+    mov     r10, [collect_regs+80]
+    mov     r11, [collect_regs+88]
+    mov     r12, [collect_regs+96]
+    mov     r13, [collect_regs+104]
+    mov     r14, [collect_regs+112]
+    mov     r15, [collect_regs+120]
+    ret
+
+; Forward
+;   Arguments: word in rdi
+;   Returns the word in rax, changed to the new address of the value it points
+;   to if that is in the old space. The value is copied to r13 unless it
+;   already has been, in which case its header is 0 and its first field is the
+;   new address.
+;   Only modifies rax, rcx, rdx, rsi and r13
+forward:
+    mov     rax, rdi
+    test    dil, 3
+    jnz     forward_done
+    cmp     rdi, [heap_start]
+    jb      forward_done
+    cmp     rdi, [heap_next]
+    jae     forward_done
+    mov     rcx, [rdi]
+    test    rcx, rcx
+    jnz     forward_copy
+    mov     rax, [rdi+8]
+    ret
+forward_copy:
+    mov     rax, r13
+    shr     rcx, 16
+    inc     rcx                 ; the header is copied too
+    xor     edx, edx
+forward_word:
+    mov     rsi, [rdi+rdx*8]
+    mov     [r13], rsi
+    add     r13, 8
+    inc     rdx
+    cmp     rdx, rcx
+    jne     forward_word
+    mov     qword [rdi], 0
+    mov     [rdi+8], rax
+forward_done:
+    ret
+
+; CheckHeap
+;   Takes no arguments; returns #t in rax if every box in the heap is well
+;   formed, #f if not
+;   A box is well formed if its header has a known type, its fields fit in the
+;   heap, and each of its fields that is a value is an immediate, a fixnum, #f
+;   or a pointer to the header of a box in the heap.
+checkheap:
+    mov     rcx, [heap_start]
+checkheap_box:
+    cmp     rcx, [heap_next]
+    jae     checkheap_ok
+    mov     rax, [rcx]
+    mov     rdx, rax
+    and     edx, 0xff
+    cmp     rdx, 1
+    jb      checkheap_bad
+    cmp     rdx, 8
+    ja      checkheap_bad
+    mov     rdx, rax
+    shr     rdx, 16             ; fields
+    jz      checkheap_bad
+    mov     rsi, rax
+    shr     rsi, 8
+    and     esi, 0xff           ; fields that aren't values
+    cmp     rsi, 0xff
+    cmove   rsi, rdx
+    cmp     rsi, rdx
+    ja      checkheap_bad
+    lea     r8, [rcx+rdx*8+8]   ; end of the box
+    cmp     r8, [heap_next]
+    ja      checkheap_bad
+    lea     rdi, [rcx+rsi*8+8]
+checkheap_field:
+    cmp     rdi, r8
+    jae     checkheap_next
+    mov     rax, [rdi]
+    test    al, 3
+    jnz     checkheap_value
+    test    rax, rax
+    jz      checkheap_value
+    cmp     rax, [heap_start]
+    jb      checkheap_bad
+    cmp     rax, [heap_next]
+    jae     checkheap_bad
+    mov     r9, [rax]
+    and     r9d, 0xff
+    cmp     r9, 1
+    jb      checkheap_bad
+    cmp     r9, 8
+    ja      checkheap_bad
+checkheap_value:
+    add     rdi, 8
+    jmp     checkheap_field
+checkheap_next:
+    mov     rcx, r8
+    jmp     checkheap_box
+checkheap_ok:
+    mov     rax, 1
+    ret
+checkheap_bad:
+    mov     rax, 0
+    ret
+
+section .data
+; Bytes in each new space
+heap_size: dq 1048576
+; The maps of runtime frames that hold one to four values, in the words below
+; the map
+roots1: dq 1, -16
+roots2: dq 2, -16, -24
+roots3: dq 3, -16, -24, -32
+roots4: dq 4, -16, -24, -32, -40
+
+section .bss
+; The frame of the entry function, which is the last one the collector looks
+; at. It is set by the function.
+base_frame:     resq 1
+to_space:       resq 1
+; The registers when the collection started, by their numbers
+collect_regs:   resq 16
+collect_xmm0:   resq 1
+collect_xmm1:   resq 1
@@ -1,28 +1,29 @@
-extern malloc
-
 section .text
 ; Empty
-;   Takes no arguments; returns an empty list
+;   Takes no arguments; returns an empty list, the immediate 5
 ;   Only modifies rax
 empty:
-    mov     rax, 0
+    mov     rax, 5
     ret
 
 ; Cons
 ;   Arguments: `first` in rdi, `rest` in rsi
 ;   Returns list in rax
 cons:
-    push    rbx
+    push    rbp
+    mov     rbp, rsp
+    push    roots2
+    push    rdi
     push    rsi
-    push    rsi             ; extra push to align stack pointer
-    mov     rbx,     rdi
-    mov     rdi,     16
-    call    malloc
-    pop     rsi
-    pop     rsi
-    mov     [rax],   rbx    ; store `first` on the heap
-    mov     [rax-8], rsi    ; store `rest` on the heap
-    pop     rbx
+    mov     rdi, 24
+    call    alloc
+    mov     qword [rax], 0x20004    ; two fields, type 4
+    mov     rdi, [rbp-16]
+    mov     [rax+8], rdi            ; store `first` on the heap
+    mov     rsi, [rbp-24]
+    mov     [rax+16], rsi           ; store `rest` on the heap
+    mov     rsp, rbp
+    pop     rbp
     ret
 
 ; IsEmpty
@@ -30,7 +31,7 @@ cons:
 ;   Returns 1 in rax if the list is empty, 0 if not
 isempty:
     mov     rax, 1      ; return true by default
-    cmp     rdi, 0
+    cmp     rdi, 5
     je      end_isempty
     mov     rax, 0
 end_isempty:
@@ -40,15 +41,42 @@ end_isempty:
 ;   Arguments: list in rdi
 ;   Returns: first element of the list in rax
 first:
-    mov     rax, [rdi]
+    test    dil, 3
+    jnz     first_bad
+    test    rdi, rdi
+    jz      first_bad
+    cmp     byte [rdi], 4
+    jne     first_bad
+    mov     rax, [rdi+8]
     ret
+first_bad:
+    mov     rsi, first_name
+    jmp     notpair
 
 ; Rest
 ;   Arguments: list in rdi
 ;   Returns: the rest of the list in rax
 rest:
-    mov     rax, [rdi-8]
+    test    dil, 3
+    jnz     rest_bad
+    test    rdi, rdi
+    jz      rest_bad
+    cmp     byte [rdi], 4
+    jne     rest_bad
+    mov     rax, [rdi+16]
     ret
+rest_bad:
+    mov     rsi, rest_name
+    jmp     notpair
+
+; NotPair
+;   Arguments: value in rdi, name of the routine it was given to in rsi
+;   Reports that the routine needed a pair
+notpair:
+    mov     rdx, rdi
+    mov     rdi, rsi
+    mov     rsi, pair_name
+    jmp     contracterror
 
 ; List
 ;   Arguments: rdi contains the number of arguments and the rest of the
@@ -60,6 +88,8 @@ list:
     call    empty
     mov     rsi, rax               ; accumulate list in rsi
     mov     rbx, rdi               ; Store list length in rbx
+    cmp     rbx, 0
+    je      end_list
 loop_list:
     mov     rdi, [rsp + rbx*8 + 8]
     call    cons                   ; Cons element onto list
@@ -74,17 +104,55 @@ end_list:
 ; Append:
 ;   Arguments: list in rdi, list in rsi
 ;   Returns: combined list in rax
+;   The cells of the first list are copied; the second list is shared with the
+;   result.
 append:
+    mov     rax, rdi     ; check that the first list ends in '()
+append_check:
+    cmp     rax, 5
+    je      appendcopy
+    test    al, 3
+    jnz     append_bad
+    test    rax, rax
+    jz      append_bad
+    cmp     byte [rax], 4
+    jne     append_bad
+    mov     rax, [rax+16]
+    jmp     append_check
+append_bad:
+    mov     rdx, rdi
+    mov     rdi, append_name
+    mov     rsi, list_name
+    jmp     contracterror
+
+; AppendCopy
+;   Like append, for a first list that is known to be a list
+appendcopy:
+    cmp     rdi, 5
+    jne     append_copy
+    mov     rax, rsi     ; (append empty l) is just l
+    ret
+append_copy:
+    push    rbp
+    mov     rbp, rsp
+    push    roots1
     push    rdi          ; save list
-append_loop:
-    mov     rbx, rdi     ; save list
-    call    rest         ; put rest in rax
-    mov     rdi, rax     ; put rest in rdi
-    cmp     rax, 0
-    jne     append_loop
-    mov     [rbx-8], rsi ; put list 2 after list 1
-    pop     rdi
-    mov     rax, rdi
+    call    rest
+    mov     rdi, rax
+    call    appendcopy   ; append the rest; rsi is passed through
+    mov     rsi, rax
+    mov     rdi, [rbp-16]
+    call    first
+    mov     rdi, rax
+    call    cons         ; cons the first element back on
+    mov     rsp, rbp
+    pop     rbp
+    ret
 
 section .data
-format: db "%d", 10, 0
+format:         db "%d", 10, 0
+first_name:     db "first", 0
+rest_name:      db "rest", 0
+append_name:    db "append", 0
+pair_name:      db "pair?", 0
+list_name:      db "list?", 0
@@ -0,0 +1,639 @@
+; The standard numeric functions
+;
+; Each takes numbers in rdi (and rsi) and returns one in rax. Exact arguments
+; give exact results where Racket's do, like (sqrt 4) and (exp 0). Otherwise
+; the argument is converted to a double, and the result is a float.
+;
+; The transcendental functions call the C math library, which reduces their
+; arguments exactly, so (sin 1e20) is right too. Its functions are weak, so a
+; program that doesn't call them can be linked without it.
+
+extern exp:weak
+extern log:weak
+extern sin:weak
+extern cos:weak
+extern tan:weak
+extern atan:weak
+extern atan2:weak
+extern pow:weak
+
+section .text
+; MathArg
+;   Arguments: number in rdi, the name of the function it was given to in rsi
+;   Returns it as a double in xmm0 and math_x, and reports an error if it isn't
+;   a number
+;   Only modifies rax, xmm0, xmm2 and xmm3
+matharg:
+    mov     rax, rdi
+    call    double
+    jnc     matharg_bad
+    movsd   xmm0, xmm2
+    movsd   [math_x], xmm0
+    ret
+matharg_bad:
+    mov     rdx, rdi
+    mov     rdi, rsi
+    mov     rsi, number_name
+    jmp     contracterror
+
+; MathCall
+;   Arguments: the C function in rax, its arguments in xmm0 and xmm1
+;   Calls the function, and returns its result as a float
+mathcall:
+    sub     rsp, 8
+    call    rax
+    add     rsp, 8
+    jmp     newfloat
+
+; NotComplex
+;   Arguments: number in rdi, the name of the function it was given to in rsi,
+;   the number as a double in xmm0
+;   Returns if it isn't negative. A negative number would need a complex
+;   result, which there isn't a type for, so reports an error.
+notcomplex:
+    xorpd   xmm1, xmm1
+    ucomisd xmm0, xmm1
+    jb      notcomplex_bad
+    ret
+notcomplex_bad:
+    mov     rdx, rdi
+    mov     rdi, rsi
+    mov     rsi, complex_text
+    jmp     reporterror
+
+; MSqrt
+;   The square root of an exact number is exact if it has one
+msqrt:
+    push    rbp
+    mov     rbp, rsp
+    push    roots2
+    push    rdi             ; the number
+    push    0               ; the root of a ratio's numerator
+    mov     rsi, sqrt_name
+    call    matharg
+    call    notcomplex
+    mov     rax, rdi
+    call    rational
+    jnc     msqrt_float
+    call    integer
+    jc      msqrt_integer
+    ; A ratio's root is exact if its numerator's and denominator's are
+    mov     rdi, [rax+8]
+    call    exactsqrt
+    test    rax, rax
+    jz      msqrt_float
+    mov     [rbp-24], rax
+    mov     rax, [rbp-16]
+    mov     rdi, [rax+16]
+    call    exactsqrt
+    test    rax, rax
+    jz      msqrt_float
+    mov     rdi, [rbp-24]
+    mov     rsi, rax
+    mov     rsp, rbp
+    pop     rbp
+    jmp     mdiv
+msqrt_integer:
+    mov     rdi, rax
+    call    exactsqrt
+    test    rax, rax
+    jnz     msqrt_done
+msqrt_float:
+    movsd   xmm0, [math_x]
+    sqrtsd  xmm0, xmm0
+    mov     rsp, rbp
+    pop     rbp
+    jmp     newfloat
+msqrt_done:
+    mov     rsp, rbp
+    pop     rbp
+    ret
+
+; ExactSqrt
+;   Arguments: integer in rdi, at least 0
+;   Returns its square root in rax if that is an integer, and #f if not
+;   The root is worked out with Newton's method from the root of the nearest
+;   double. After the first step the guess is never too small, and it shrinks
+;   until it is the integer part of the root.
+exactsqrt:
+    push    rbp
+    mov     rbp, rsp
+    push    roots3
+    push    rdi             ; the integer
+    push    0               ; the guess
+    push    0               ; the guess after it
+    cmp     rdi, 2
+    je      exactsqrt_zero
+    mov     rax, rdi
+    call    double
+    sqrtsd  xmm0, xmm2
+    roundsd xmm0, xmm0, 2
+    ucomisd xmm0, [fixnum_limit]
+    jae     exactsqrt_big
+    cvtsd2si rax, xmm0
+    lea     rax, [rax*4+2]
+    jmp     exactsqrt_newton
+exactsqrt_big:
+    call    newfloat
+    mov     rdi, rax
+    call    toexact
+exactsqrt_newton:
+    mov     [rbp-24], rax
+    call    exactsqrt_step
+    mov     [rbp-24], rax
+exactsqrt_shrink:
+    call    exactsqrt_step
+    mov     [rbp-32], rax
+    mov     rdi, rax
+    mov     rsi, [rbp-24]
+    call    msub
+    call    negative
+    test    rax, rax
+    jz      exactsqrt_check
+    mov     rax, [rbp-32]
+    mov     [rbp-24], rax
+    jmp     exactsqrt_shrink
+exactsqrt_check:
+    mov     rdi, [rbp-24]
+    mov     rsi, rdi
+    call    mmul
+    mov     rdi, rax
+    mov     rsi, [rbp-16]
+    call    eq
+    test    rax, rax
+    jz      exactsqrt_done
+exactsqrt_zero:
+    mov     rax, [rbp-24]
+    cmp     qword [rbp-16], 2
+    jne     exactsqrt_done
+    mov     eax, 2
+exactsqrt_done:
+    mov     rsp, rbp
+    pop     rbp
+    ret
+; The guess after the one at rbp-24 for the root of the integer at rbp-16:
+; (x + n/x) / 2
+exactsqrt_step:
+    sub     rsp, 8
+    mov     rdi, [rbp-16]
+    mov     rsi, [rbp-24]
+    call    mquotient
+    mov     rdi, rax
+    mov     rsi, [rbp-24]
+    call    madd
+    mov     rdi, rax
+    mov     rsi, 10         ; 2
+    call    mquotient
+    add     rsp, 8
+    ret
+
+; MExp
+mexp:
+    mov     eax, 6          ; (exp 0) is exactly 1
+    cmp     rdi, 2
+    je      mexp_done
+    mov     rsi, exp_name
+    call    matharg
+    mov     rax, exp
+    jmp     mathcall
+mexp_done:
+    ret
+
+; MLog
+mlog:
+    mov     eax, 2          ; (log 1) is exactly 0
+    cmp     rdi, 6
+    je      mlog_done
+    cmp     rdi, 2
+    je      mlog_zero
+    mov     rsi, log_name
+    call    matharg
+    call    notcomplex
+    mov     rax, log
+    jmp     mathcall
+mlog_zero:
+    mov     rdx, rdi
+    mov     rdi, log_name
+    jmp     undefinederror
+mlog_done:
+    ret
+
+; MSin
+msin:
+    mov     rax, rdi        ; (sin 0) is exactly 0
+    cmp     rdi, 2
+    je      msin_done
+    mov     rsi, sin_name
+    call    matharg
+    mov     rax, sin
+    jmp     mathcall
+msin_done:
+    ret
+
+; MCos
+mcos:
+    mov     eax, 6          ; (cos 0) is exactly 1
+    cmp     rdi, 2
+    je      mcos_done
+    mov     rsi, cos_name
+    call    matharg
+    mov     rax, cos
+    jmp     mathcall
+mcos_done:
+    ret
+
+; MTan
+mtan:
+    mov     rax, rdi        ; (tan 0) is exactly 0
+    cmp     rdi, 2
+    je      mtan_done
+    mov     rsi, tan_name
+    call    matharg
+    mov     rax, tan
+    jmp     mathcall
+mtan_done:
+    ret
+
+; MAtan
+matan:
+    mov     rax, rdi        ; (atan 0) is exactly 0
+    cmp     rdi, 2
+    je      matan_done
+    mov     rsi, atan_name
+    call    matharg
+    mov     rax, atan
+    jmp     mathcall
+matan_done:
+    ret
+
+; MAtan2
+;   Arguments: y in rdi, x in rsi
+;   Returns the angle of the point (x, y) from the x axis, which there isn't
+;   one of for exact 0 and 0
+matan2:
+    cmp     rdi, 2
+    jne     matan2_float
+    test    sil, 2
+    jz      matan2_float
+    cmp     rsi, 2
+    jg      matan2_zero     ; exactly 0 for an exact positive x
+    je      matan2_origin
+matan2_float:
+    push    rsi
+    mov     rsi, atan_name
+    call    matharg
+    pop     rdi
+    movsd   [math_y], xmm0
+    mov     rsi, atan_name
+    call    matharg
+    movsd   xmm1, xmm0
+    movsd   xmm0, [math_y]
+    mov     rax, atan2
+    jmp     mathcall
+matan2_zero:
+    mov     eax, 2
+    ret
+matan2_origin:
+    mov     rdx, rsi
+    mov     rdi, atan_name
+    mov     rsi, origin_text
+    jmp     reporterror
+
+; The rounding functions take a real number in rdi. An integer is returned
+; as it is, a ratio gives an integer and a float gives a float.
+
+; MFloor
+mfloor:
+    mov     rax, rdi
+    call    rational
+    jc      mfloor_exact
+    mov     rsi, floor_name
+    call    realfloat
+    roundsd xmm0, xmm0, 1
+    jmp     newfloat
+mfloor_exact:
+    mov     edx, 1
+    jmp     ratround
+
+; MCeiling
+mceiling:
+    mov     rax, rdi
+    call    rational
+    jc      mceiling_exact
+    mov     rsi, ceiling_name
+    call    realfloat
+    roundsd xmm0, xmm0, 2
+    jmp     newfloat
+mceiling_exact:
+    mov     edx, 2
+    jmp     ratround
+
+; MTruncate
+mtruncate:
+    mov     rax, rdi
+    call    rational
+    jc      mtruncate_exact
+    mov     rsi, truncate_name
+    call    realfloat
+    roundsd xmm0, xmm0, 3
+    jmp     newfloat
+mtruncate_exact:
+    mov     edx, 3
+    jmp     ratround
+
+; MRound
+;   Halfway cases round to the even integer
+mround:
+    mov     rax, rdi
+    call    rational
+    jc      mround_exact
+    mov     rsi, round_name
+    call    realfloat
+    roundsd xmm0, xmm0, 0
+    jmp     newfloat
+mround_exact:
+    xor     edx, edx
+    jmp     ratround
+
+; MExactRound
+;   Like round, but the result is always exact
+mexactround:
+    mov     rax, rdi
+    call    rational
+    jc      mround_exact
+    mov     rsi, exactround_name
+    call    checkfinite
+    movsd   xmm0, [rdi+8]
+    roundsd xmm0, xmm0, 0
+    call    newfloat
+    mov     rdi, rax
+    jmp     toexact
+
+; RealFloat
+;   Arguments: value in rdi, the name of the function it was given to in rsi
+;   Returns the double in xmm0 if it is a float, and reports an error if not
+realfloat:
+    test    dil, 3
+    jnz     realfloat_bad
+    test    rdi, rdi
+    jz      realfloat_bad
+    cmp     byte [rdi], 1
+    jne     realfloat_bad
+    movsd   xmm0, [rdi+8]
+    ret
+realfloat_bad:
+    mov     rdx, rdi
+    mov     rdi, rsi
+    mov     rsi, real_name
+    jmp     contracterror
+
+; RatRound
+;   Arguments: exact number in rdi, and how to round it in rdx like roundsd
+;   does: 0 to the nearest integer, 1 down, 2 up or 3 towards zero
+;   Returns the integer in rax
+ratround:
+    mov     rax, rdi
+    call    integer
+    jc      ratround_integer
+    push    rbp
+    mov     rbp, rsp
+    push    roots2
+    push    qword [rdi+8]   ; the numerator
+    push    qword [rdi+16]  ; the denominator
+    cmp     rdx, 1
+    je      ratround_floor
+    cmp     rdx, 2
+    je      ratround_ceiling
+    cmp     rdx, 3
+    je      ratround_truncate
+    ; The nearest integer to n/d is the floor of (2n + d) / 2d. A tie has a
+    ; denominator of 2, and goes to the even one of the two.
+    mov     rdi, [rbp-16]
+    mov     rsi, rdi
+    call    madd
+    mov     rdi, rax
+    mov     rsi, [rbp-24]
+    call    madd
+    mov     [rbp-16], rax
+    mov     rdi, [rbp-24]
+    mov     rsi, rdi
+    call    madd
+    mov     rdi, [rbp-16]
+    mov     rsi, rax
+    call    floordiv
+    mov     [rbp-16], rax
+    cmp     qword [rbp-24], 10  ; 2
+    jne     ratround_done
+    mov     rdi, rax
+    mov     esi, 10
+    call    mmod
+    cmp     rax, 6          ; 1, so it's odd
+    jne     ratround_done
+    mov     rdi, [rbp-16]
+    mov     esi, 6
+    call    msub
+    mov     [rbp-16], rax
+    jmp     ratround_done
+ratround_floor:
+    mov     rdi, [rbp-16]
+    mov     rsi, [rbp-24]
+    call    floordiv
+    mov     [rbp-16], rax
+    jmp     ratround_done
+ratround_ceiling:
+    ; -floor(-n / d)
+    mov     edi, 2
+    mov     rsi, [rbp-16]
+    call    msub
+    mov     rdi, rax
+    mov     rsi, [rbp-24]
+    call    floordiv
+    mov     edi, 2
+    mov     rsi, rax
+    call    msub
+    mov     [rbp-16], rax
+    jmp     ratround_done
+ratround_truncate:
+    mov     rdi, [rbp-16]
+    mov     rsi, [rbp-24]
+    call    mquotient
+    mov     [rbp-16], rax
+ratround_done:
+    mov     rax, [rbp-16]
+    mov     rsp, rbp
+    pop     rbp
+ratround_integer:
+    ret
+
+; FloorDiv
+;   Arguments: integers in rdi and rsi, the second more than 0
+;   Returns the floor of rdi / rsi in rax: (a - (modulo a b)) / b
+floordiv:
+    push    rbp
+    mov     rbp, rsp
+    push    roots2
+    push    rdi
+    push    rsi
+    call    mmod
+    mov     rdi, [rbp-16]
+    mov     rsi, rax
+    call    msub
+    mov     rdi, rax
+    mov     rsi, [rbp-24]
+    call    mquotient
+    mov     rsp, rbp
+    pop     rbp
+    ret
+
+; MExpt
+;   Arguments: base in rdi, exponent in rsi
+;   An exact base to a fixnum power is exact, and a float base to one is
+;   multiplied out. Anything else goes to the C library's pow.
+mexpt:
+    mov     eax, 6          ; anything to the power of exact 0 is exactly 1
+    cmp     rsi, 2
+    je      mexpt_done
+    test    sil, 2
+    jz      mexpt_real
+    mov     rax, rdi
+    call    rational
+    jc      mexpt_exact
+    mov     rax, rdi
+    call    double
+    jnc     mexpt_real      ; reports the error
+    movsd   xmm0, xmm2
+    mov     rax, rsi
+    sar     rax, 2
+    call    powdouble
+    jmp     newfloat
+mexpt_real:
+    push    rdi
+    push    rsi
+    mov     rdi, rsi
+    mov     rsi, expt_name
+    call    matharg
+    movsd   [math_y], xmm0
+    pop     rsi
+    pop     rdi
+    push    rsi
+    mov     rsi, expt_name
+    call    matharg
+    pop     rsi
+    ; An integer exponent multiplies out, and allows a negative base
+    movsd   xmm1, [math_y]
+    roundsd xmm2, xmm1, 3
+    ucomisd xmm2, xmm1
+    jne     mexpt_pow
+    movq    rax, xmm1
+    btr     rax, 63
+    movq    xmm2, rax
+    ucomisd xmm2, [fixnum_limit]
+    jae     mexpt_pow
+    cvtsd2si rax, xmm1
+    call    powdouble
+    jmp     newfloat
+mexpt_pow:
+    mov     rsi, expt_name
+    call    notcomplex
+    movsd   xmm1, [math_y]
+    mov     rax, pow
+    jmp     mathcall
+mexpt_exact:
+    push    r12
+    push    r13
+    push    rbp
+    mov     rbp, rsp
+    push    roots2
+    push    rdi             ; the base squared as many times as bits are done
+    push    6               ; the result so far
+    mov     r12, rsi        ; the bits of the exponent that are left
+    mov     r13, 0          ; 1 for a negative exponent
+    test    r12, r12
+    jns     mexpt_bit
+    mov     r13, 1
+    mov     rax, 4
+    sub     rax, r12        ; -n
+    mov     r12, rax
+mexpt_bit:
+    test    r12, 4          ; the lowest bit of the exponent
+    jz      mexpt_square
+    mov     rdi, [rbp-24]
+    mov     rsi, [rbp-16]
+    call    mmul
+    mov     [rbp-24], rax
+mexpt_square:
+    sar     r12, 3
+    lea     r12, [r12*4+2]
+    cmp     r12, 2
+    je      mexpt_inverse
+    mov     rdi, [rbp-16]
+    mov     rsi, rdi
+    call    mmul
+    mov     [rbp-16], rax
+    jmp     mexpt_bit
+mexpt_inverse:
+    mov     rax, [rbp-24]
+    test    r13, r13
+    jz      mexpt_exact_done
+    mov     edi, 6
+    mov     rsi, rax
+    call    mdiv
+mexpt_exact_done:
+    mov     rsp, rbp
+    pop     rbp
+    pop     r13
+    pop     r12
+mexpt_done:
+    ret
+
+; PowDouble
+;   Arguments: double in xmm0, integer in rax
+;   Returns the double to the power of the integer in xmm0, multiplied out by
+;   squaring
+;   Only modifies rax, rcx, rdx, xmm0 and xmm1
+powdouble:
+    mov     rdx, rax
+    mov     rcx, rax
+    sar     rdx, 63
+    xor     rcx, rdx
+    sub     rcx, rdx        ; |n|
+    movsd   xmm1, [one]
+powdouble_bit:
+    test    rcx, 1
+    jz      powdouble_square
+    mulsd   xmm1, xmm0
+powdouble_square:
+    mulsd   xmm0, xmm0
+    shr     rcx, 1
+    jnz     powdouble_bit
+    movsd   xmm0, xmm1
+    test    rdx, rdx
+    jz      powdouble_done
+    movsd   xmm0, [one]
+    divsd   xmm0, xmm1
+powdouble_done:
+    ret
+
+section .data
+sqrt_name:          db "sqrt", 0
+expt_name:          db "expt", 0
+exp_name:           db "exp", 0
+log_name:           db "log", 0
+sin_name:           db "sin", 0
+cos_name:           db "cos", 0
+tan_name:           db "tan", 0
+atan_name:          db "atan", 0
+floor_name:         db "floor", 0
+ceiling_name:       db "ceiling", 0
+round_name:         db "round", 0
+truncate_name:      db "truncate", 0
+exactround_name:    db "exact-round", 0
+real_name:          db "real?", 0
+complex_text:       db "complex results aren't supported for ", 0
+origin_text:        db "undefined for 0 and ", 0
+one:                dq 1.0
+fixnum_limit:       dq 2305843009213693952.0
+
+section .bss
+math_x:     resq 1
+math_y:     resq 1
@@ -1,99 +1,256 @@
-; All values are boxed in Racket.
+; Values are 64-bit words. The low two bits say what kind of value it is:
 ;
-;  ------       ------------
-; | reg | ---> | ty | data |
-; ------       ------------
-
-extern malloc
+;   00  pointer to a boxed value (float, closure, symbol, cons cell, cell,
+;       bignum, ratio or string), except that 0 is #f
+;   01  other immediate: 1 is #t, 5 is '() and 9 is #<void>
+;   10  fixnum: a 62-bit integer n stored as n * 4 + 2
+;
+; Boxed values live on the garbage collected heap (see gc.asm). They start
+; with a header word, followed by their fields:
+;
+;  ------       ------------------------------
+; | reg | ---> | header | field | field | ... |
+;  ------       ------------------------------
+;
+; The header holds the type in its low byte, the number of leading fields that
+; aren't values (like the address of a closure's code) in the next byte, and
+; the number of fields in the rest. A count of 255 in the second byte means
+; that none of the fields are values.
 
 section .text
-; NewInt
-;   Arguments: integer in rdi
-;   Returns pointer in rax
-newint:
-    push    rbx
-    mov     rbx, rdi
-    mov     rdi, 9
-    call    malloc
-    mov     [rax+3], byte 0 ; store type
-    mov     [rax-1], rbx    ; store data
-    pop     rbx
+; Alloc
+;   Arguments: number of bytes in rdi, a multiple of 8
+;   Returns the address of that many uninitialized bytes in rax
+;   Only modifies rax. Compiled code does the same inline, only calling
+;   heapfull when the heap is full.
+alloc:
+    mov     rax, [heap_next]
+    add     rax, rdi
+    cmp     rax, [heap_end]
+    jbe     alloc_fits
+    mov     rax, rdi
+    call    heapfull
+alloc_fits:
+    mov     [heap_next], rax
+    sub     rax, rdi
+    ret
+
+; HeapFull
+;   Arguments: number of bytes that didn't fit in rax
+;   Collects garbage to make room for them, then returns in rax where the
+;   allocation ends, as if it had fit the first time
+;   Only modifies rax
+heapfull:
+    call    collect
+    add     rax, [heap_next]
     ret
 
 ; NewFloat
-;   Arguments: float in xmm0
+;   Arguments: double in xmm0
 ;   Returns pointer in rax
 newfloat:
-    push    rbx
-    sub     rsp, 16
-    movss   [rsp], xmm0
-    mov     rbx, rdi
-    mov     rdi, 9
-    call    malloc
-    movss   xmm0, [rsp]
-    add     rsp, 16
-    mov     [rax+3], byte 1 ; store type
-    movss   [rax-1], xmm0   ; store data
-    pop     rbx
+    mov     rdi, 16
+    call    alloc
+    mov     qword [rax], 0x10101    ; one field, not a value, type 1
+    movsd   [rax+8], xmm0
     ret
 
-; NewIP
-;   Arguments: line number in rdi
-;   Returns ip in rax
-newip:
+; NewString
+;   Arguments: address of text in rdi, its length in rsi
+;   Returns a new string with a copy of the text in rax
+;   Only modifies rax, rcx, rsi and rdi
+newstring:
     push    rbx
+    push    r12
+    sub     rsp, 8
     mov     rbx, rdi
-    mov     rdi, 9
-    call    malloc
-    mov     [rax+3], byte 2 ; store type
-    mov     [rax-1], rbx    ; store data
+    mov     r12, rsi
+    lea     rdi, [rsi+24]
+    and     rdi, -8         ; the header, the length, and the text and its null
+    call    alloc
+    lea     rcx, [rdi-8]
+    shl     rcx, 13         ; the number of fields, shifted left by 16
+    or      rcx, 0xff08     ; none of them are values, type 8
+    mov     [rax], rcx
+    mov     [rax+8], r12
+    mov     qword [rax+rdi-8], 0    ; the null and the padding after the text
+    xor     ecx, ecx
+newstring_byte:
+    cmp     rcx, r12
+    je      newstring_done
+    mov     sil, [rbx+rcx]
+    mov     [rax+rcx+16], sil
+    inc     rcx
+    jmp     newstring_byte
+newstring_done:
+    add     rsp, 8
+    pop     r12
     pop     rbx
     ret
 
 ; GetInt
-;   Arguments: boxed int in rdi
+;   Arguments: fixnum in rdi
 ;   Returns: value in rax
 getint:
-    mov     rax, [rdi-1]
+    test    dil, 2
+    jz      getint_bad
+    mov     rax, rdi
+    sar     rax, 2
     ret
+getint_bad:
+    mov     rdx, rdi
+    mov     rdi, getint_name
+    mov     rsi, fixnum_name
+    jmp     contracterror
 
 ; GetFloat
 ;   Arguments: boxed float in rdi
-;   Returns float value in xmm0
+;   Returns double value in xmm0
 getfloat:
-    movss   xmm0, [rdi-1]
+    test    dil, 3
+    jnz     getfloat_bad
+    test    rdi, rdi
+    jz      getfloat_bad
+    cmp     byte [rdi], 1
+    jne     getfloat_bad
+    movsd   xmm0, [rdi+8]
     ret
+getfloat_bad:
+    mov     rdx, rdi
+    mov     rdi, getfloat_name
+    mov     rsi, flonum_name
+    jmp     contracterror
 
-; GetIP
-;   Arguments: boxed float in rdi
-;   Returns float value in xmm0
-getip:
-    mov     rax, [rdi-1]
+; CheckProcedure
+;   Arguments: value in rax
+;   Returns if the value is a closure, and reports an error if not
+;   Doesn't modify any registers
+checkprocedure:
+    test    al, 3
+    jnz     notprocedure
+    test    rax, rax
+    jz      notprocedure
+    cmp     byte [rax], 2
+    jne     notprocedure
     ret
 
 ; Eq
-;   Arguments: boxed values in rdi and rsi
+;   Arguments: values in rdi and rsi
 ;   Returns 1 if the values are equal, 0 if not
+;   Immediates are equal if they are the same word. Floats are equal if they
+;   have the same value, so a NaN isn't equal even to itself, and a float is
+;   equal to an exact number with its value. Symbols are equal if they have
+;   the same name, bignums if they have the same sign and limbs, ratios if
+;   they have equal numerators and denominators, strings if they have the same
+;   text, and other boxed values only to themselves.
 eq:
-    mov     al, byte [rdi+3]
-    cmp     al, 0
-    je      eqint1
-    mov     al, byte [rsi+3]
-    cmp     al, 0
-    je      neq
-    movss   xmm0, [rdi-1]
-    movss   xmm1, [rsi-1]
-    ucomiss xmm0, xmm1
+    cmp     rdi, rsi
+    jne     eq_different
+    ; A word is equal to itself, unless it is a float box holding a NaN
+    test    dil, 3
+    jnz     yeq
+    test    rdi, rdi
+    jz      yeq
+    cmp     byte [rdi], 1
+    je      eqfloat
+    jmp     yeq
+eq_different:
+    mov     eax, edi
+    or      eax, esi
+    test    al, 1
+    jnz     neq             ; one of them is an immediate other than a fixnum
+    test    rdi, rdi
+    jz      neq
+    test    rsi, rsi
+    jz      neq
+    test    al, 2
+    jnz     eqmixed         ; one of them is a fixnum
+    mov     al, [rdi]
+    cmp     al, [rsi]
+    jne     eqmixed
+    cmp     al, 1
+    je      eqfloat
+    cmp     al, 6
+    je      eqbignum
+    cmp     al, 7
+    je      eqratio
+    cmp     al, 8
+    je      eqstring
+    cmp     al, 3
+    jne     neq
+    mov     rax, [rdi+8]    ; symbols point to their name
+    cmp     rax, [rsi+8]
     je      yeq
     jmp     neq
-eqint1:
-    mov     al, byte [rsi+3]
-    cmp     al, 0
+eqmixed:
+    ; Numbers of different kinds. Exact ones are never equal, since bignums
+    ; and ratios are always normalized, so one of them has to be a float.
+    mov     rax, rdi
+    call    double
+    jnc     neq
+    movsd   xmm0, xmm2
+    mov     rax, rsi
+    call    double
+    jnc     neq
+    movsd   xmm1, xmm2
+    test    dil, 2
+    jnz     eqmixed_right
+    cmp     byte [rdi], 1
+    je      eqdoubles
+eqmixed_right:
+    test    sil, 2
+    jnz     neq
+    cmp     byte [rsi], 1
     jne     neq
-    mov     rax, [rdi-1]
-    cmp     rax, [rsi-1]
+    jmp     eqdoubles
+eqfloat:
+    movsd   xmm0, [rdi+8]
+    movsd   xmm1, [rsi+8]
+eqdoubles:
+    ucomisd xmm0, xmm1
+    jp      neq             ; NaN isn't equal to anything
     je      yeq
     jmp     neq
+eqbignum:
+    mov     rcx, [rdi+8]
+    cmp     rcx, [rsi+8]
+    jne     neq
+    shr     rcx, 1
+eqbignum_limb:
+    test    rcx, rcx
+    jz      yeq
+    mov     rax, [rdi+rcx*8+8]
+    cmp     rax, [rsi+rcx*8+8]
+    jne     neq
+    dec     rcx
+    jmp     eqbignum_limb
+eqstring:
+    ; Strings with the same text have the same header and words, since the
+    ; padding after the text is zero
+    mov     rcx, [rdi]
+    cmp     rcx, [rsi]
+    jne     neq
+    shr     rcx, 16
+eqstring_word:
+    mov     rax, [rdi+rcx*8]
+    cmp     rax, [rsi+rcx*8]
+    jne     neq
+    dec     rcx
+    jnz     eqstring_word
+    jmp     yeq
+eqratio:
+    push    rdi
+    push    rsi
+    mov     rdi, [rdi+8]
+    mov     rsi, [rsi+8]
+    call    eq
+    pop     rsi
+    pop     rdi
+    test    rax, rax
+    jz      neq
+    mov     rdi, [rdi+16]
+    mov     rsi, [rsi+16]
+    jmp     eq
 neq:
     mov     rax, 0
     ret
@@ -101,196 +258,364 @@ yeq:
     mov     rax, 1
     ret
 
+; Double
+;   Arguments: value in rax
+;   If it is a number, returns it as a double in xmm2 and sets the carry flag.
+;   Otherwise, clears the carry flag.
+;   Only modifies xmm2 and xmm3
+double:
+    test        al, 2
+    jz          double_boxed
+    push        rax
+    sar         rax, 2
+    cvtsi2sd    xmm2, rax
+    pop         rax
+    stc
+    ret
+double_boxed:
+    test        al, 1
+    jnz         double_no
+    test        rax, rax
+    jz          double_no
+    cmp         byte [rax], 1
+    je          double_float
+    cmp         byte [rax], 6
+    je          double_bignum
+    cmp         byte [rax], 7
+    je          double_ratio
+double_no:
+    clc
+    ret
+double_float:
+    movsd       xmm2, [rax+8]
+    stc
+    ret
+double_bignum:
+    call        bigdouble
+    stc
+    ret
+double_ratio:
+    ; The numerator divided by the denominator
+    push        rax
+    sub         rsp, 8
+    mov         rax, [rax+16]
+    call        double
+    movsd       [rsp], xmm2
+    mov         rax, [rsp+8]
+    mov         rax, [rax+8]
+    call        double
+    divsd       xmm2, [rsp]
+    add         rsp, 8
+    pop         rax
+    stc
+    ret
+
+; ToFloats
+;   Arguments: numbers in rdi and rsi, the name of the function they were
+;   given to in rdx, and what it expects in rcx
+;   Returns them as doubles in xmm0 and xmm1, and reports an error if either
+;   isn't a number
+;   Only modifies rax and xmm0 to xmm3
+tofloats:
+    mov         rax, rdi
+    call        double
+    jnc         tofloats_bad1
+    movsd       xmm0, xmm2
+    mov         rax, rsi
+    call        double
+    jnc         tofloats_bad2
+    movsd       xmm1, xmm2
+    ret
+tofloats_bad1:
+    mov         rsi, rdi
+tofloats_bad2:
+    mov         rdi, rdx
+    mov         rdx, rsi
+    mov         rsi, rcx
+    jmp         contracterror
+
+; The arithmetic functions below take two numbers in rdi and rsi and return
+; one in rax. If both are fixnums, they work on them without allocating, unless
+; the result doesn't fit in a fixnum. If both are integers, the result is one
+; too, and a bignum if it needs to be (see bignum.asm). If both are exact, the
+; result is an exact rational (see rational.asm). Otherwise, they convert them
+; both to floats and return a boxed float, or report an error if one isn't a
+; number.
+;
+; Fixnums both have bit 1 set, and nothing else does, so the fast path is
+; taken if the bitwise and of the two values has it set. Because fixnums are
+; stored shifted left by two bits, the result overflows a fixnum exactly when
+; the 64-bit operation on the stored words overflows.
+
 ; MAdd
-;   Arguments: two boxed values in rdi and rsi
-;   Returns boxed value in rax
-;   If one of the boxed values is a float, it casts them both to floats and
-;   adds as float. Otherwise, they're both ints and it adds as signed ints.
 madd:
-    mov         al, byte [rdi+3]
-    cmp         al, 0
-    je          int1
-    mov         al, byte [rsi+3]
-    cmp         al, 0
-    je          addfloatint
-    movss       xmm0, [rdi-1]
-    movss       xmm1, [rsi-1]
-    jmp         addfloatfloat
-int1:
-    mov         al, byte [rsi+3]
-    cmp         al, 0
-    je          addintint
-    jmp         addintfloat
-addfloatint:
-    movss       xmm0, [rdi-1]
-    cvtsi2ss    xmm1, [rsi-1]
-    jmp         addfloatfloat
-addintfloat:
-    cvtsi2ss    xmm0, [rdi-1]
-    movss       xmm1, [rsi-1]
-addfloatfloat:
-    addss       xmm0, xmm1
-    call        newfloat
-    ret
-addintint:
-    mov         rdi, [rdi-1]
-    mov         rsi, [rsi-1]
-    add         rdi, rsi
-    call        newint
+    mov         eax, edi
+    and         eax, esi
+    test        al, 2
+    jz          madd_boxed
+    mov         rax, rdi
+    sub         rax, 2
+    add         rax, rsi
+    jo          madd_big
     ret
+madd_big:
+    xor         edx, edx
+    jmp         bigadd
+madd_boxed:
+    xor         edx, edx
+    call        integers
+    jc          bigadd
+    call        rationals
+    jc          ratadd
+madd_float:
+    mov         rdx, add_name
+    mov         rcx, number_name
+    call        tofloats
+    addsd       xmm0, xmm1
+    jmp         newfloat
 
 ; MSub
-;   Arguments: two boxed values in rdi and rsi
-;   Returns boxed value in rax
 msub:
-    mov         al, byte [rdi+3]
-    cmp         al, 0
-    je          subint1
-    mov         al, byte [rsi+3]
-    cmp         al, 0
-    je          subfloatint
-    movss       xmm0, [rdi-1]
-    movss       xmm1, [rsi-1]
-    jmp         subfloatfloat
-subint1:
-    mov         al, byte [rsi+3]
-    cmp         al, 0
-    je          subintint
-    jmp         subintfloat
-subfloatint:
-    movss       xmm0, [rdi-1]
-    cvtsi2ss    xmm1, [rsi-1]
-    jmp         subfloatfloat
-subintfloat:
-    cvtsi2ss    xmm0, [rdi-1]
-    movss       xmm1, [rsi-1]
-subfloatfloat:
-    subss       xmm0, xmm1
-    call        newfloat
-    ret
-subintint:
-    mov         rdi, [rdi-1]
-    mov         rsi, [rsi-1]
-    sub         rdi, rsi
-    call        newint
+    mov         eax, edi
+    and         eax, esi
+    test        al, 2
+    jz          msub_boxed
+    mov         rax, rdi
+    sub         rax, rsi
+    jo          msub_big
+    add         rax, 2
     ret
+msub_big:
+    mov         edx, 1
+    jmp         bigadd
+msub_boxed:
+    mov         edx, 1
+    call        integers
+    jc          bigadd
+    call        rationals
+    jc          ratadd
+msub_float:
+    mov         rdx, sub_name
+    mov         rcx, number_name
+    call        tofloats
+    subsd       xmm0, xmm1
+    jmp         newfloat
 
 ; MMul
-;   Arguments: two boxed values in rdi and rsi
-;   Returns boxed value in rax
 mmul:
-    mov         al, byte [rdi+3]
-    cmp         al, 0
-    je          mulint1
-    mov         al, byte [rsi+3]
-    cmp         al, 0
-    je          mulfloatint
-    movss       xmm0, [rdi-1]
-    movss       xmm1, [rsi-1]
-    jmp         mulfloatfloat
-mulint1:
-    mov         al, byte [rsi+3]
-    cmp         al, 0
-    je          mulintint
-    jmp         mulintfloat
-mulfloatint:
-    movss       xmm0, [rdi-1]
-    cvtsi2ss    xmm1, [rsi-1]
-    jmp         mulfloatfloat
-mulintfloat:
-    cvtsi2ss    xmm0, [rdi-1]
-    movss       xmm1, [rsi-1]
-mulfloatfloat:
-    mulss       xmm0, xmm1
-    call        newfloat
-    ret
-mulintint:
-    mov         rdi, [rdi-1]
-    mov         rsi, [rsi-1]
-    imul        rdi, rsi
-    call        newint
+    mov         eax, edi
+    and         eax, esi
+    test        al, 2
+    jz          mmul_boxed
+    mov         rax, rdi
+    sar         rax, 2
+    lea         rcx, [rsi-2]
+    imul        rax, rcx
+    jo          bigmul
+    add         rax, 2
     ret
+mmul_boxed:
+    call        integers
+    jc          bigmul
+    xor         edx, edx
+    call        rationals
+    jc          ratmul
+    mov         rdx, mul_name
+    mov         rcx, number_name
+    call        tofloats
+    mulsd       xmm0, xmm1
+    jmp         newfloat
 
-; Mdiv
-;   Arguments: two boxed values in rdi and rsi
-;   Returns boxed value in rax
+; MDiv
+;   Integers that don't divide exactly make a ratio. Dividing by an exact 0 is
+;   an error, even for a float dividend.
 mdiv:
-    mov         al, byte [rdi+3]
-    cmp         al, 0
-    je          divint1
-    mov         al, byte [rsi+3]
-    cmp         al, 0
-    je          divfloatint
-    movss       xmm0, [rdi-1]
-    movss       xmm1, [rsi-1]
-    jmp         divfloatfloat
-divint1:
-    mov         al, byte [rsi+3]
-    cmp         al, 0
-    je          divintint
-    jmp         divintfloat
-divfloatint:
-    movss       xmm0, [rdi-1]
-    cvtsi2ss    xmm1, [rsi-1]
-    jmp         divfloatfloat
-divintfloat:
-    cvtsi2ss    xmm0, [rdi-1]
-    movss       xmm1, [rsi-1]
-divfloatfloat:
-    divss       xmm0, xmm1
-    call        newfloat
+    cmp         rsi, 2
+    je          mdiv_zero
+    mov         eax, edi
+    and         eax, esi
+    test        al, 2
+    jz          mdiv_boxed
+    cmp         rsi, -2
+    je          bigdivide       ; dividing by -1 can overflow
+    mov         rax, rdi
+    sar         rax, 2
+    mov         rcx, rsi
+    sar         rcx, 2
+    cqo
+    idiv        rcx
+    test        rdx, rdx
+    jnz         makeratio
+    lea         rax, [rax*4+2]
     ret
-divintint:
-    push        rbx
-    push        rdx
-    xor         rdx, rdx
-    mov         rax, [rdi-1]
-    mov         rbx, [rsi-1]
-    idiv        rbx
-    mov         rdi, rax
-    call        newint
-    pop         rdx
-    pop         rbx
+mdiv_boxed:
+    call        integers
+    jc          makeratio
+    mov         edx, 1
+    call        rationals
+    jc          ratmul
+mdiv_float:
+    mov         rdx, div_name
+    mov         rcx, number_name
+    call        tofloats
+    divsd       xmm0, xmm1
+    jmp         newfloat
+mdiv_zero:
+    mov         rdi, division_by_zero_text
+    jmp         runtimeerror
+
+; The integer division functions below take two integers in rdi and rsi, each
+; a fixnum, a bignum or a float with an integer value, and return one in rax. They
+; report an error if the divisor is zero.
+
+; Divide
+;   Arguments: integers in rdi and rsi, and the name of the function they
+;   were given to in rdx
+;   If both are fixnums or bignums, returns their quotient rounded towards
+;   zero in rax and the remainder in rdx, and clears the carry flag.
+;   Otherwise, returns them as doubles in xmm0 and xmm1, the quotient in xmm2
+;   and the remainder in xmm3, and sets the carry flag.
+divide:
+    mov         eax, edi
+    and         eax, esi
+    test        al, 2
+    jz          divide_boxed
+    cmp         rsi, 2
+    je          divide_zero
+    cmp         rsi, -2
+    je          divide_big      ; dividing by -1 can overflow
+    mov         rax, rdi
+    sar         rax, 2
+    mov         rcx, rsi
+    sar         rcx, 2
+    cqo
+    idiv        rcx
+    lea         rax, [rax*4+2]
+    lea         rdx, [rdx*4+2]
+    clc
     ret
+divide_boxed:
+    call        integers
+    jnc         divide_float
+    cmp         rsi, 2
+    je          divide_zero
+divide_big:
+    call        bigdivide
+    clc
+    ret
+divide_float:
+    mov         rcx, integer_name
+    call        tofloats
+    roundsd     xmm2, xmm0, 3   ; rounded towards zero
+    ucomisd     xmm2, xmm0
+    jne         divide_bad1
+    roundsd     xmm2, xmm1, 3
+    ucomisd     xmm2, xmm1
+    jne         divide_bad2
+    xorpd       xmm2, xmm2
+    ucomisd     xmm1, xmm2
+    je          divide_zero
+    movapd      xmm2, xmm0
+    divsd       xmm2, xmm1
+    roundsd     xmm2, xmm2, 3
+    movapd      xmm4, xmm2
+    mulsd       xmm4, xmm1
+    movapd      xmm3, xmm0
+    subsd       xmm3, xmm4
+    stc
+    ret
+divide_bad1:
+    mov         rsi, rdi
+divide_bad2:
+    mov         rdi, rdx
+    mov         rdx, rsi
+    mov         rsi, integer_name
+    jmp         contracterror
+divide_zero:
+    mov         rdi, rdx
+    mov         rdx, rsi
+    jmp         undefinederror
+
+; MQuotient
+mquotient:
+    mov         rdx, quotient_name
+    call        divide
+    jc          mquotient_float
+    ret
+mquotient_float:
+    movapd      xmm0, xmm2
+    jmp         newfloat
+
+; MRemainder
+;   The result has the sign of the dividend
+mremainder:
+    mov         rdx, remainder_name
+    call        divide
+    jc          mremainder_float
+    mov         rax, rdx
+    ret
+mremainder_float:
+    movapd      xmm0, xmm3
+    jmp         newfloat
 
 ; MMod
-;   Arguments: two boxed values in rdi and rsi
-;   Returns boxed value in rax
+;   The result has the sign of the divisor, so it is the remainder plus the
+;   divisor when their signs differ
 mmod:
-    mov         al, byte [rdi+3]
-    cmp         al, 0
-    je          modint1
-    mov         al, byte [rsi+3]
-    cmp         al, 0
-    je          modfloatint
-    movss       xmm0, [rdi-1]
-    movss       xmm1, [rsi-1]
-    jmp         modfloatfloat
-modint1:
-    mov         al, byte [rsi+3]
-    cmp         al, 0
-    je          modintint
-    jmp         modintfloat
-modfloatint:
-    movss       xmm0, [rdi-1]
-    cvtsi2ss    xmm1, [rsi-1]
-    jmp         modfloatfloat
-modintfloat:
-    cvtsi2ss    xmm0, [rdi-1]
-    movss       xmm1, [rsi-1]
-modfloatfloat:
-    divss       xmm0, xmm1
-    call        newfloat
-    ret
-modintint:
-    push        rbx
-    push        rdx
-    xor         rdx, rdx
-    mov         rax, [rdi-1]
-    mov         rbx, [rsi-1]
-    idiv        rbx
+    push        rbp             ; a frame that keeps the divisor
+    mov         rbp, rsp
+    push        roots1
+    push        rsi
+    mov         rdx, modulo_name
+    call        divide
+    mov         rsi, [rbp-16]
+    mov         rsp, rbp
+    pop         rbp
+    jc          mmod_float
+    cmp         rdx, 2
+    je          mmod_done       ; 0
+    mov         rax, rdx
+    call        negative
+    mov         rcx, rax
+    mov         rax, rsi
+    call        negative
+    cmp         rax, rcx
+    je          mmod_done
     mov         rdi, rdx
-    call        newint
-    pop         rdx
-    pop         rbx
+    jmp         madd
+mmod_done:
+    mov         rax, rdx
     ret
+mmod_float:
+    movapd      xmm0, xmm3
+    movq        rax, xmm0
+    shl         rax, 1
+    jz          newfloat        ; 0.0 or -0.0
+    movq        rax, xmm0
+    movq        rcx, xmm1
+    xor         rax, rcx
+    jns         newfloat
+    addsd       xmm0, xmm1
+    jmp         newfloat
+
+section .data
+add_name:       db "+", 0
+sub_name:       db "-", 0
+mul_name:       db "*", 0
+div_name:       db "/", 0
+quotient_name:  db "quotient", 0
+remainder_name: db "remainder", 0
+modulo_name:    db "modulo", 0
+getint_name:    db "_getint", 0
+getfloat_name:  db "_getfloat", 0
+number_name:    db "number?", 0
+integer_name:   db "integer?", 0
+fixnum_name:    db "fixnum?", 0
+flonum_name:    db "flonum?", 0
+division_by_zero_text: db "/: division by zero", 0
+
+section .bss
+; The part of the heap values are allocated in, and the next free byte in it
+heap_start: resq 1
+heap_next:  resq 1
+heap_end:   resq 1
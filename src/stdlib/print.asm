@@ -0,0 +1,661 @@
+; Writing values as text, the way Racket does
+
+extern fprintf
+extern fputc
+extern fputs
+extern snprintf
+extern strtod
+extern strpbrk
+extern strcat
+extern stdout
+extern open_memstream
+extern fclose
+
+section .text
+; The procedures that print a value to stdout take it in rdi, and return
+; #<void>.
+
+; Display
+display:
+    mov     rsi, rdi
+    mov     rdi, [stdout]
+    sub     rsp, 8
+    call    fdisplayvalue
+    add     rsp, 8
+    mov     eax, 9
+    ret
+
+; WriteValue
+writevalue:
+    mov     rsi, rdi
+    mov     rdi, [stdout]
+    sub     rsp, 8
+    call    fwritevalue
+    add     rsp, 8
+    mov     eax, 9
+    ret
+
+; PrintValue
+printvalue:
+    mov     rsi, rdi
+    mov     rdi, [stdout]
+    sub     rsp, 8
+    call    fprintvalue
+    add     rsp, 8
+    mov     eax, 9
+    ret
+
+; Newline
+newline:
+    mov     edi, 10
+    mov     rsi, [stdout]
+    sub     rsp, 8
+    call    fputc
+    add     rsp, 8
+    mov     eax, 9
+    ret
+
+; PrintFormatted
+;   Arguments: the number of values in rdi, and the values on the stack: a
+;   format string, then the values for its directives
+;   Writes the string to stdout with the directives replaced, like Racket's
+;   `printf`, and returns #<void>
+printformatted:
+    sub     rsp, 8
+    mov     rsi, rdi
+    lea     rdx, [rsp+16]
+    mov     rdi, [stdout]
+    mov     rcx, printf_name
+    call    fformat
+    add     rsp, 8
+    mov     eax, 9
+    ret
+
+; FormatString
+;   Like printformatted, but returns what would be written as a new string,
+;   like Racket's `format`
+formatstring:
+    push    rbx
+    mov     rbx, rdi
+    mov     rdi, memstream_text
+    mov     rsi, memstream_size
+    call    open_memstream
+    mov     rdi, rax
+    mov     rsi, rbx
+    mov     rbx, rax
+    lea     rdx, [rsp+16]
+    mov     rcx, format_name
+    call    fformat
+    mov     rdi, rbx
+    call    fclose
+    mov     rdi, [memstream_text]
+    mov     rsi, [memstream_size]
+    call    newstring
+    mov     rbx, rax
+    mov     rdi, [memstream_text]
+    call    free
+    mov     rax, rbx
+    pop     rbx
+    ret
+
+; FFormat
+;   Arguments: stream in rdi, the number of values in rsi, the address of the
+;   first in rdx, and the name of the procedure they were given to in rcx
+;   The first value is a format string, which is written to the stream with
+;   its directives replaced: ~a displays the next value, ~s writes it, ~v
+;   prints it, ~n and ~% write a newline and ~~ writes a tilde. The number of
+;   values has to match the directives.
+fformat:
+    push    rbx
+    push    r12
+    push    r13
+    push    r14
+    push    r15
+    mov     r12, rdi
+    mov     r15, rcx
+    mov     rdi, [rdx]      ; the format string
+    lea     r13, [rdx+8]    ; the next value
+    lea     r14, [rsi-1]    ; how many values are left
+    test    dil, 3
+    jnz     fformat_bad
+    test    rdi, rdi
+    jz      fformat_bad
+    cmp     byte [rdi], 8
+    jne     fformat_bad
+    lea     rbx, [rdi+16]   ; the next character
+    ; The directives are counted first, so nothing is written if they don't
+    ; match the values
+    mov     rcx, rbx
+    xor     edx, edx
+fformat_count:
+    movzx   eax, byte [rcx]
+    inc     rcx
+    test    eax, eax
+    jz      fformat_counted
+    cmp     eax, 126        ; ~
+    jne     fformat_count
+    movzx   eax, byte [rcx]
+    inc     rcx
+    call    lowercase
+    cmp     eax, 97         ; a
+    je      fformat_value
+    cmp     eax, 115        ; s
+    je      fformat_value
+    cmp     eax, 118        ; v
+    je      fformat_value
+    cmp     eax, 110        ; n
+    je      fformat_count
+    cmp     eax, 37         ; %
+    je      fformat_count
+    cmp     eax, 126
+    je      fformat_count
+    jmp     fformat_illformed
+fformat_value:
+    inc     rdx
+    jmp     fformat_count
+fformat_counted:
+    cmp     rdx, r14
+    jne     fformat_arity
+fformat_char:
+    movzx   edi, byte [rbx]
+    inc     rbx
+    test    edi, edi
+    jz      fformat_done
+    cmp     edi, 126
+    je      fformat_directive
+fformat_put:
+    mov     rsi, r12
+    call    fputc
+    jmp     fformat_char
+fformat_directive:
+    movzx   edi, byte [rbx]
+    inc     rbx
+    mov     eax, edi
+    call    lowercase
+    cmp     eax, 110
+    je      fformat_newline
+    cmp     eax, 37
+    je      fformat_newline
+    cmp     eax, 126
+    je      fformat_put
+    mov     rdi, r12
+    mov     rsi, [r13]
+    add     r13, 8
+    cmp     eax, 97
+    je      fformat_display
+    cmp     eax, 115
+    je      fformat_write
+    call    fprintvalue
+    jmp     fformat_char
+fformat_display:
+    call    fdisplayvalue
+    jmp     fformat_char
+fformat_write:
+    call    fwritevalue
+    jmp     fformat_char
+fformat_newline:
+    mov     edi, 10
+    jmp     fformat_put
+fformat_done:
+    pop     r15
+    pop     r14
+    pop     r13
+    pop     r12
+    pop     rbx
+    ret
+; The letter in eax in lower case, so directives can be either
+lowercase:
+    lea     esi, [eax-65]   ; A
+    cmp     esi, 25
+    ja      lowercase_done
+    or      eax, 32
+lowercase_done:
+    ret
+fformat_bad:
+    mov     rdx, rdi
+    mov     rdi, r15
+    mov     rsi, string_name
+    jmp     contracterror
+fformat_illformed:
+    mov     rdx, rdi
+    mov     rdi, r15
+    mov     rsi, illformed_text
+    jmp     reporterror
+fformat_arity:
+    and     rsp, -16
+    mov     rcx, rdx
+    mov     r8, r14
+    mov     rdx, r15
+    mov     rdi, [stderr]
+    mov     rsi, arity_format
+    xor     eax, eax
+    call    fprintf
+    jmp     errorend
+
+; FPrintValue
+;   Arguments: stream in rdi, value in rsi
+;   Writes the value to the stream like Racket's `print`: like `write`, but
+;   symbols and lists are quoted so that they read back as the same value
+fprintvalue:
+    push    rbx
+    push    r12
+    sub     rsp, 8
+    mov     rbx, rdi
+    mov     r12, rsi
+    cmp     rsi, 5
+    je      fprintvalue_quote
+    test    sil, 3
+    jnz     fprintvalue_write
+    test    rsi, rsi
+    jz      fprintvalue_write
+    cmp     byte [rsi], 3
+    je      fprintvalue_quote
+    cmp     byte [rsi], 4
+    jne     fprintvalue_write
+fprintvalue_quote:
+    mov     edi, 39         ; '
+    mov     rsi, rbx
+    call    fputc
+fprintvalue_write:
+    mov     rdi, rbx
+    mov     rsi, r12
+    add     rsp, 8
+    pop     r12
+    pop     rbx
+    jmp     fwritevalue
+
+; FWriteValue
+;   Arguments: stream in rdi, value in rsi
+;   Writes the value to the stream like Racket's `write`
+fwritevalue:
+    xor     edx, edx
+    jmp     fshowvalue
+
+; FDisplayValue
+;   Arguments: stream in rdi, value in rsi
+;   Writes the value to the stream like Racket's `display`: like `write`, but
+;   strings are written as their text, without quotes or escapes
+fdisplayvalue:
+    mov     edx, 1
+    jmp     fshowvalue
+
+; FShowValue
+;   Arguments: stream in rdi, value in rsi, 1 in rdx to display the value or 0
+;   to write it
+fshowvalue:
+    push    rbx
+    push    r12
+    push    r13
+    mov     rbx, rdi
+    mov     r12, rsi
+    mov     r13, rdx
+    test    sil, 2
+    jnz     fwrite_fixnum
+    test    r12, r12
+    jz      fwrite_false
+    cmp     r12, 1
+    je      fwrite_true
+    cmp     r12, 5
+    je      fwrite_empty
+    cmp     r12, 9
+    je      fwrite_void
+    test    sil, 1
+    jnz     fwrite_unknown
+    movzx   eax, byte [r12]
+    cmp     eax, 1
+    je      fwrite_float
+    cmp     eax, 2
+    je      fwrite_procedure
+    cmp     eax, 3
+    je      fwrite_symbol
+    cmp     eax, 4
+    je      fwrite_list
+    cmp     eax, 6
+    je      fwrite_bignum
+    cmp     eax, 7
+    je      fwrite_ratio
+    cmp     eax, 8
+    je      fwrite_string
+fwrite_unknown:
+    mov     rdi, unknown_text
+    jmp     fwrite_text
+fwrite_fixnum:
+    mov     rdi, rbx
+    mov     rsi, fixnum_format
+    mov     rdx, r12
+    sar     rdx, 2
+    xor     eax, eax
+    call    fprintf
+    jmp     fwrite_done
+fwrite_false:
+    mov     rdi, false_text
+    jmp     fwrite_text
+fwrite_true:
+    mov     rdi, true_text
+    jmp     fwrite_text
+fwrite_empty:
+    mov     rdi, empty_text
+    jmp     fwrite_text
+fwrite_void:
+    mov     rdi, void_text
+    jmp     fwrite_text
+fwrite_procedure:
+    mov     rdi, procedure_text
+    jmp     fwrite_text
+fwrite_string:
+    lea     rdi, [r12+16]
+    test    r13, r13
+    jnz     fwrite_text
+    mov     rdi, rbx
+    mov     rsi, r12
+    call    fwritestring
+    jmp     fwrite_done
+fwrite_symbol:
+    mov     rdi, [r12+8]
+    jmp     fwrite_text
+fwrite_float:
+    movsd   xmm0, [r12+8]
+    call    formatfloat
+    mov     rdi, rax
+    jmp     fwrite_text
+fwrite_bignum:
+    mov     rdi, rbx
+    mov     rsi, r12
+    call    fwritebignum
+    jmp     fwrite_done
+fwrite_ratio:
+    mov     rdi, rbx
+    mov     rsi, [r12+8]
+    call    fwritevalue
+    mov     edi, 47         ; /
+    mov     rsi, rbx
+    call    fputc
+    mov     rdi, rbx
+    mov     rsi, [r12+16]
+    call    fwritevalue
+    jmp     fwrite_done
+fwrite_list:
+    mov     edi, 40         ; (
+    mov     rsi, rbx
+    call    fputc
+fwrite_item:
+    mov     rdi, rbx
+    mov     rsi, [r12+8]
+    mov     rdx, r13
+    call    fshowvalue
+    mov     r12, [r12+16]
+    cmp     r12, 5
+    je      fwrite_close
+    mov     edi, 32         ; space
+    mov     rsi, rbx
+    call    fputc
+    ; The list goes on while the rest is a pair. Anything else ends it after
+    ; a dot.
+    test    r12b, 3
+    jnz     fwrite_dotted
+    test    r12, r12
+    jz      fwrite_dotted
+    cmp     byte [r12], 4
+    je      fwrite_item
+fwrite_dotted:
+    mov     rdi, dot_text
+    mov     rsi, rbx
+    call    fputs
+    mov     rdi, rbx
+    mov     rsi, r12
+    mov     rdx, r13
+    call    fshowvalue
+fwrite_close:
+    mov     edi, 41         ; )
+    mov     rsi, rbx
+    call    fputc
+    jmp     fwrite_done
+fwrite_text:
+    mov     rsi, rbx
+    call    fputs
+fwrite_done:
+    pop     r13
+    pop     r12
+    pop     rbx
+    ret
+
+; FWriteString
+;   Arguments: stream in rdi, string in rsi
+;   Writes the string in quotes, with escapes for the characters that need
+;   them, so that it reads back as the same string
+fwritestring:
+    push    rbx
+    push    r12
+    push    r13
+    mov     rbx, rdi
+    lea     r12, [rsi+16]   ; the next character
+    mov     edi, 34         ; double quote
+    mov     rsi, rbx
+    call    fputc
+fwritestring_char:
+    movzx   r13d, byte [r12]
+    test    r13d, r13d
+    jz      fwritestring_done
+    inc     r12
+    cmp     r13d, 10
+    je      fwritestring_newline
+    cmp     r13d, 9
+    je      fwritestring_tab
+    cmp     r13d, 34
+    je      fwritestring_escape
+    cmp     r13d, 92        ; backslash
+    je      fwritestring_escape
+fwritestring_put:
+    mov     edi, r13d
+    mov     rsi, rbx
+    call    fputc
+    jmp     fwritestring_char
+fwritestring_newline:
+    mov     r13d, 110       ; n
+    jmp     fwritestring_escape
+fwritestring_tab:
+    mov     r13d, 116       ; t
+fwritestring_escape:
+    mov     edi, 92
+    mov     rsi, rbx
+    call    fputc
+    jmp     fwritestring_put
+fwritestring_done:
+    mov     edi, 34
+    mov     rsi, rbx
+    call    fputc
+    pop     r13
+    pop     r12
+    pop     rbx
+    ret
+
+; FWriteBignum
+;   Arguments: stream in rdi, bignum in rsi
+;   Writes the bignum to the stream in decimal. A copy of its limbs is divided
+;   by 10^18 until nothing is left, and the remainders are written from the
+;   last one, each but the first with 18 digits.
+fwritebignum:
+    push    rbx
+    push    r12
+    push    r13
+    push    r14
+    push    r15
+    mov     rbx, rdi
+    mov     r12, rsi
+    test    byte [r12+8], 1
+    jz      fwritebignum_copy
+    mov     edi, 45         ; -
+    mov     rsi, rbx
+    call    fputc
+fwritebignum_copy:
+    mov     r13, [r12+8]
+    shr     r13, 1          ; limbs
+    lea     rdi, [r13*8]
+    call    malloc
+    mov     r14, rax
+    xor     ecx, ecx
+fwritebignum_limb:
+    mov     rax, [r12+rcx*8+16]
+    mov     [r14+rcx*8], rax
+    inc     rcx
+    cmp     rcx, r13
+    jb      fwritebignum_limb
+    ; Each limb makes less than 2 groups of 18 digits
+    lea     rdi, [r13*8]
+    shl     rdi, 1
+    call    malloc
+    mov     r15, rax
+    xor     r12d, r12d      ; groups
+fwritebignum_group:
+    mov     rcx, r13
+    xor     edx, edx
+    mov     r8, 1000000000000000000
+fwritebignum_divide:
+    dec     rcx
+    mov     rax, [r14+rcx*8]
+    div     r8
+    mov     [r14+rcx*8], rax
+    test    rcx, rcx
+    jnz     fwritebignum_divide
+    mov     [r15+r12*8], rdx
+    inc     r12
+fwritebignum_trim:
+    cmp     qword [r14+r13*8-8], 0
+    jne     fwritebignum_group
+    dec     r13
+    jnz     fwritebignum_trim
+    dec     r12
+    mov     rdi, rbx
+    mov     rsi, fixnum_format
+    mov     rdx, [r15+r12*8]
+    xor     eax, eax
+    call    fprintf
+fwritebignum_rest:
+    test    r12, r12
+    jz      fwritebignum_done
+    dec     r12
+    mov     rdi, rbx
+    mov     rsi, group_format
+    mov     rdx, [r15+r12*8]
+    xor     eax, eax
+    call    fprintf
+    jmp     fwritebignum_rest
+fwritebignum_done:
+    mov     rdi, r14
+    call    free
+    mov     rdi, r15
+    call    free
+    pop     r15
+    pop     r14
+    pop     r13
+    pop     r12
+    pop     rbx
+    ret
+
+; FormatFloat
+;   Arguments: double in xmm0
+;   Returns in rax the address of the shortest text that reads back as the
+;   same double, written like Racket does: with a decimal point unless it has
+;   an exponent (2.0, not 2), and +inf.0, -inf.0 and +nan.0 for the special
+;   values. The text is overwritten by the next call.
+formatfloat:
+    push    rbx
+    push    r12
+    sub     rsp, 8
+    movsd   [float_value], xmm0
+    movq    rax, xmm0
+    mov     rcx, rax
+    shr     rcx, 52
+    and     ecx, 0x7ff
+    cmp     ecx, 0x7ff
+    jne     formatfloat_finite
+    mov     rcx, rax
+    shl     rcx, 12
+    jnz     formatfloat_nan
+    test    rax, rax
+    mov     rax, positive_infinity_text
+    jns     formatfloat_done
+    mov     rax, negative_infinity_text
+    jmp     formatfloat_done
+formatfloat_nan:
+    mov     rax, nan_text
+    jmp     formatfloat_done
+formatfloat_finite:
+    ; Very small and very large magnitudes get an exponent
+    mov     r12, fixed_format
+    btr     rax, 63
+    test    rax, rax
+    jz      formatfloat_digits
+    movq    xmm1, rax
+    ucomisd xmm1, [smallest_fixed]
+    jb      formatfloat_exponent
+    ucomisd xmm1, [largest_fixed]
+    jb      formatfloat_digits
+formatfloat_exponent:
+    mov     r12, exponent_format
+formatfloat_digits:
+    xor     ebx, ebx        ; digits after the decimal point
+formatfloat_try:
+    mov     rdi, float_text
+    mov     esi, 48
+    mov     rdx, r12
+    mov     ecx, ebx
+    movsd   xmm0, [float_value]
+    mov     eax, 1
+    call    snprintf
+    mov     rdi, float_text
+    xor     esi, esi
+    call    strtod
+    ucomisd xmm0, [float_value]
+    je      formatfloat_found
+    inc     ebx
+    cmp     ebx, 24
+    jb      formatfloat_try
+formatfloat_found:
+    mov     rdi, float_text
+    mov     rsi, float_marks
+    call    strpbrk
+    test    rax, rax
+    jnz     formatfloat_text
+    mov     rdi, float_text
+    mov     rsi, point_zero_text
+    call    strcat
+formatfloat_text:
+    mov     rax, float_text
+formatfloat_done:
+    add     rsp, 8
+    pop     r12
+    pop     rbx
+    ret
+
+section .data
+fixnum_format:          db "%ld", 0
+group_format:           db "%018ld", 0
+fixed_format:           db "%.*f", 0
+exponent_format:        db "%.*e", 0
+float_marks:            db ".e", 0
+point_zero_text:        db ".0", 0
+positive_infinity_text: db "+inf.0", 0
+negative_infinity_text: db "-inf.0", 0
+nan_text:               db "+nan.0", 0
+false_text:             db "#f", 0
+true_text:              db "#t", 0
+empty_text:             db "()", 0
+void_text:              db "#<void>", 0
+procedure_text:         db "#<procedure>", 0
+unknown_text:           db "#<unknown>", 0
+dot_text:               db ". ", 0
+smallest_fixed:         dq 0.0001
+largest_fixed:          dq 1.0e21
+
+printf_name:            db "printf", 0
+format_name:            db "format", 0
+string_name:            db "string?", 0
+illformed_text:         db "ill-formed pattern string ", 0
+arity_format:           db "%s: format string requires %ld arguments, given %ld", 0
+
+section .bss
+float_value:    resq 1
+memstream_text: resq 1
+memstream_size: resq 1
+float_text:     resb 48
@@ -0,0 +1,400 @@
+; Exact rational numbers that aren't integers
+;
+; A ratio is a box of type 7 with two fields, the numerator and the
+; denominator, which are integers. The denominator is more than 1 and has no
+; factor in common with the numerator, so every rational number has one
+; representation, and one that would have a denominator of 1 is an integer.
+;
+; The integer arithmetic may allocate, so the routines below keep the values
+; they need after calling it in frames the garbage collector looks at.
+
+section .text
+; Rationals
+;   Arguments: values in rdi and rsi
+;   Sets the carry flag if both are exact numbers, integers or ratios, and
+;   clears it if not
+;   Only modifies rax
+rationals:
+    mov     rax, rdi
+    call    rational
+    jnc     rationals_done
+    mov     rax, rsi
+    jmp     rational
+rationals_done:
+    ret
+
+; Rational
+;   Arguments: value in rax
+;   Sets the carry flag if it is an integer or a ratio, and clears it if not
+;   Doesn't modify any registers
+rational:
+    call    integer
+    jc      rational_done
+    test    al, 3
+    jnz     rational_done
+    test    rax, rax
+    jz      rational_done
+    cmp     byte [rax], 7
+    je      rational_yes
+    clc
+    ret
+rational_yes:
+    stc
+rational_done:
+    ret
+
+; Parts
+;   Arguments: exact number in rax
+;   Returns its numerator in rax and its denominator in rdx
+parts:
+    call    integer
+    jc      parts_integer
+    mov     rdx, [rax+16]
+    mov     rax, [rax+8]
+    ret
+parts_integer:
+    mov     edx, 6          ; 1
+    ret
+
+; Gcd
+;   Arguments: integers in rdi and rsi, not both 0
+;   Returns their greatest common divisor in rax
+gcd:
+    push    rbp
+    mov     rbp, rsp
+    push    roots2
+    push    rdi
+    push    rsi
+gcd_step:
+    mov     rsi, [rbp-24]
+    cmp     rsi, 2
+    je      gcd_done
+    mov     rdi, [rbp-16]
+    mov     [rbp-16], rsi
+    call    mremainder
+    mov     [rbp-24], rax
+    jmp     gcd_step
+gcd_done:
+    mov     rax, [rbp-16]
+    call    negative
+    test    rax, rax
+    mov     rax, [rbp-16]
+    jz      gcd_positive
+    mov     edi, 2
+    mov     rsi, rax
+    call    msub
+gcd_positive:
+    mov     rsp, rbp
+    pop     rbp
+    ret
+
+; MakeRatio
+;   Arguments: integers in rdi and rsi, the second not 0
+;   Returns the rational number rdi/rsi in lowest terms in rax
+makeratio:
+    push    rbp
+    mov     rbp, rsp
+    push    roots3
+    push    rdi             ; the numerator
+    push    rsi             ; the denominator
+    push    0               ; their greatest common divisor
+    mov     rax, rsi
+    call    negative
+    test    rax, rax
+    jz      makeratio_reduce
+    mov     edi, 2
+    mov     rsi, [rbp-16]
+    call    msub
+    mov     [rbp-16], rax
+    mov     edi, 2
+    mov     rsi, [rbp-24]
+    call    msub
+    mov     [rbp-24], rax
+makeratio_reduce:
+    mov     rdi, [rbp-16]
+    mov     rsi, [rbp-24]
+    call    gcd
+    mov     [rbp-32], rax
+    mov     rdi, [rbp-16]
+    mov     rsi, rax
+    call    mquotient
+    mov     [rbp-16], rax
+    mov     rdi, [rbp-24]
+    mov     rsi, [rbp-32]
+    call    mquotient
+    mov     [rbp-24], rax
+    cmp     rax, 6
+    mov     rax, [rbp-16]
+    je      makeratio_done  ; a denominator of 1 makes an integer
+    mov     rdi, 24
+    call    alloc
+    mov     qword [rax], 0x20007    ; two fields, type 7
+    mov     rcx, [rbp-16]
+    mov     [rax+8], rcx
+    mov     rcx, [rbp-24]
+    mov     [rax+16], rcx
+makeratio_done:
+    mov     rsp, rbp
+    pop     rbp
+    ret
+
+; RatAdd
+;   Arguments: exact numbers in rdi and rsi, and 1 in rdx to subtract the
+;   second instead of adding it, or 0
+;   Returns the result in rax: a/b + c/d = (ad + cb) / bd
+ratadd:
+    push    r15
+    push    rbp
+    mov     rbp, rsp
+    push    roots4
+    mov     r15, rdx
+    mov     rax, rdi
+    call    parts
+    push    rax             ; a
+    push    rdx             ; b
+    mov     rax, rsi
+    call    parts
+    push    rax             ; c
+    push    rdx             ; d
+    mov     rdi, [rbp-16]
+    mov     rsi, rdx
+    call    mmul
+    mov     [rbp-16], rax   ; ad
+    mov     rdi, [rbp-32]
+    mov     rsi, [rbp-24]
+    call    mmul
+    mov     [rbp-32], rax   ; cb
+    mov     rdi, [rbp-24]
+    mov     rsi, [rbp-40]
+    call    mmul
+    mov     [rbp-24], rax   ; bd
+    mov     rdi, [rbp-16]
+    mov     rsi, [rbp-32]
+    test    r15, r15
+    jnz     ratadd_subtract
+    call    madd
+    jmp     ratadd_done
+ratadd_subtract:
+    call    msub
+ratadd_done:
+    mov     rdi, rax
+    mov     rsi, [rbp-24]
+    mov     rsp, rbp
+    pop     rbp
+    pop     r15
+    jmp     makeratio
+
+; RatMul
+;   Arguments: exact numbers in rdi and rsi, and 1 in rdx to divide by the
+;   second instead of multiplying, or 0. The second can't be 0 to divide by it.
+;   Returns the result in rax: a/b * c/d = ac / bd
+ratmul:
+    push    rbp
+    mov     rbp, rsp
+    push    roots2
+    mov     r8, rdx
+    mov     rax, rdi
+    call    parts
+    push    rax             ; a
+    push    rdx             ; b
+    mov     rax, rsi
+    call    parts
+    test    r8, r8
+    jz      ratmul_parts
+    xchg    rax, rdx        ; dividing by c/d multiplies by d/c
+ratmul_parts:
+    mov     rdi, [rbp-24]
+    mov     rsi, rdx
+    mov     [rbp-24], rax
+    call    mmul            ; bd
+    mov     rdi, [rbp-16]
+    mov     rsi, [rbp-24]
+    mov     [rbp-24], rax
+    call    mmul            ; ac
+    mov     rdi, rax
+    mov     rsi, [rbp-24]
+    mov     rsp, rbp
+    pop     rbp
+    jmp     makeratio
+
+; Numerator
+;   Arguments: rational number in rdi
+;   Returns its numerator in rax. A float's is a float too.
+numerator:
+    mov     rax, rdi
+    call    rational
+    jnc     numerator_float
+    call    parts
+    ret
+numerator_float:
+    mov     rsi, numerator_name
+    call    checkfinite
+    call    toexact
+    call    parts
+    mov     rdi, rax
+    jmp     toinexact
+
+; Denominator
+;   Arguments: rational number in rdi
+;   Returns its denominator in rax. A float's is a float too.
+denominator:
+    mov     rax, rdi
+    call    rational
+    jnc     denominator_float
+    call    parts
+    mov     rax, rdx
+    ret
+denominator_float:
+    mov     rsi, denominator_name
+    call    checkfinite
+    call    toexact
+    call    parts
+    mov     rdi, rdx
+    jmp     toinexact
+
+; CheckFinite
+;   Arguments: value in rdi, the name of the function it was given to in rsi
+;   Returns if it is a finite float, and reports an error if not
+;   Only modifies rax and rcx
+checkfinite:
+    test    dil, 3
+    jnz     checkfinite_bad
+    test    rdi, rdi
+    jz      checkfinite_bad
+    cmp     byte [rdi], 1
+    jne     checkfinite_bad
+    mov     rax, [rdi+8]
+    shr     rax, 52
+    and     eax, 0x7ff
+    cmp     eax, 0x7ff
+    je      checkfinite_bad
+    ret
+checkfinite_bad:
+    mov     rdx, rdi
+    mov     rdi, rsi
+    mov     rsi, rational_name
+    jmp     contracterror
+
+; ToInexact
+;   Arguments: number in rdi
+;   Returns the float nearest to it
+toinexact:
+    mov     rax, rdi
+    call    double
+    jnc     toinexact_bad
+    movsd   xmm0, xmm2
+    jmp     newfloat
+toinexact_bad:
+    mov     rdx, rdi
+    mov     rdi, toinexact_name
+    mov     rsi, number_name
+    jmp     contracterror
+
+; ToExact
+;   Arguments: number in rdi
+;   Returns the exact number with the same value. A finite double is m * 2^e
+;   for integers m and e, which is an integer or a ratio with a power of 2 for
+;   the denominator.
+toexact:
+    mov     rax, rdi
+    call    rational
+    jnc     toexact_float
+    ret
+toexact_float:
+    test    dil, 3
+    jnz     toexact_bad
+    test    rdi, rdi
+    jz      toexact_bad
+    cmp     byte [rdi], 1
+    jne     toexact_bad
+    push    rbx
+    push    r12
+    push    r13
+    mov     rax, [rdi+8]
+    mov     rcx, rax
+    shr     rcx, 52
+    and     ecx, 0x7ff      ; biased exponent
+    cmp     ecx, 0x7ff
+    je      toexact_infinite
+    mov     rbx, 0xfffffffffffff
+    and     rbx, rax        ; m
+    mov     r12, -1074      ; e
+    test    ecx, ecx
+    jz      toexact_signed  ; subnormal
+    bts     rbx, 52
+    lea     r12, [rcx-1075]
+toexact_signed:
+    test    rax, rax
+    jns     toexact_tagged
+    neg     rbx
+toexact_tagged:
+    lea     rbx, [rbx*4+2]
+    test    r12, r12
+    js      toexact_fraction
+    mov     rdi, r12
+    call    powtwo
+    mov     rdi, rbx
+    mov     rsi, rax
+    call    mmul
+    jmp     toexact_done
+toexact_fraction:
+    mov     rdi, r12
+    neg     rdi
+    call    powtwo
+    mov     rdi, rbx
+    mov     rsi, rax
+    call    makeratio
+toexact_done:
+    pop     r13
+    pop     r12
+    pop     rbx
+    ret
+toexact_infinite:
+    mov     rdx, rdi
+    mov     rdi, toexact_name
+    mov     rsi, no_exact_text
+    jmp     reporterror
+toexact_bad:
+    mov     rdx, rdi
+    mov     rdi, toexact_name
+    mov     rsi, number_name
+    jmp     contracterror
+
+; PowTwo
+;   Arguments: number in rdi, at least 0
+;   Returns 2 to that power in rax, an integer
+powtwo:
+    push    rbx
+    push    rbp
+    mov     rbp, rsp
+    push    roots1
+    push    6               ; 1
+    mov     rbx, rdi
+powtwo_step:
+    cmp     rbx, 60
+    jb      powtwo_last
+    mov     rdi, [rbp-16]
+    mov     rsi, 0x4000000000000002 ; 2^60
+    call    mmul
+    mov     [rbp-16], rax
+    sub     rbx, 60
+    jmp     powtwo_step
+powtwo_last:
+    mov     ecx, ebx
+    mov     eax, 1
+    shl     rax, cl
+    lea     rsi, [rax*4+2]
+    mov     rdi, [rbp-16]
+    call    mmul
+    mov     rsp, rbp
+    pop     rbp
+    pop     rbx
+    ret
+
+section .data
+numerator_name:     db "numerator", 0
+denominator_name:   db "denominator", 0
+toinexact_name:     db "exact->inexact", 0
+toexact_name:       db "inexact->exact", 0
+rational_name:      db "rational?", 0
+no_exact_text:      db "no exact representation for ", 0
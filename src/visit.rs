@@ -0,0 +1,172 @@
+use crate::parser::{
+    AppExpr, CondExpr, Datum, DefineExpr, Expr, LambdaExpr, LetExpr, LetKind, Node, Template,
+};
+
+/// Walks a `Node` tree without changing it
+///
+/// Every method defaults to walking the children of what it visits, so an implementation only
+/// overrides the parts it cares about. An override can call the matching `walk_*` function to
+/// keep walking.
+pub trait Visitor {
+    fn visit_node(&mut self, node: &Node) {
+        walk_node(self, node)
+    }
+
+    fn visit_expr(&mut self, e: &Expr) {
+        walk_expr(self, e)
+    }
+
+    fn visit_app_expr(&mut self, e: &AppExpr) {
+        walk_app_expr(self, e)
+    }
+
+    fn visit_let_expr(&mut self, e: &LetExpr) {
+        walk_let_expr(self, e)
+    }
+
+    fn visit_lambda_expr(&mut self, e: &LambdaExpr) {
+        walk_lambda_expr(self, e)
+    }
+
+    fn visit_cond_expr(&mut self, e: &CondExpr) {
+        walk_cond_expr(self, e)
+    }
+
+    fn visit_define_expr(&mut self, e: &DefineExpr) {
+        walk_define_expr(self, e)
+    }
+
+    fn visit_template(&mut self, t: &Template) {
+        walk_template(self, t)
+    }
+
+    /// Called for identifiers used as expressions, including `#t` and `#f`, and for the operator
+    /// of every `Expr`, including keywords like `if`
+    fn visit_ident(&mut self, _name: &str) {}
+
+    /// Called for identifiers being bound by `let`, `lambda` or `define`
+    fn visit_binder(&mut self, _name: &str) {}
+
+    fn visit_integer(&mut self, _i: i64) {}
+
+    fn visit_float(&mut self, _f: f64) {}
+
+    /// Called for quoted data and the constant parts of quasiquoted templates
+    fn visit_datum(&mut self, _d: &Datum) {}
+}
+
+pub fn walk_node<V: Visitor + ?Sized>(v: &mut V, node: &Node) {
+    match node {
+        Node::Expr(e) => v.visit_expr(e),
+        Node::AppExpr(e) => v.visit_app_expr(e),
+        Node::String(s) => v.visit_ident(s),
+        Node::Float(f) => v.visit_float(*f),
+        Node::Integer(i) => v.visit_integer(*i),
+        Node::LetExpr(e) => v.visit_let_expr(e),
+        Node::LambdaExpr(e) => v.visit_lambda_expr(e),
+        Node::CondExpr(e) => v.visit_cond_expr(e),
+        Node::DefineExpr(e) => v.visit_define_expr(e),
+        Node::Begin(body) => {
+            for node in body {
+                v.visit_node(node);
+            }
+        }
+        Node::Quote(d) => v.visit_datum(d),
+        Node::Quasiquote(t) => v.visit_template(t),
+    }
+}
+
+pub fn walk_expr<V: Visitor + ?Sized>(v: &mut V, e: &Expr) {
+    v.visit_ident(&e.op);
+    for param in &e.params {
+        v.visit_node(param);
+    }
+}
+
+pub fn walk_app_expr<V: Visitor + ?Sized>(v: &mut V, e: &AppExpr) {
+    v.visit_node(&e.op);
+    for param in &e.params {
+        v.visit_node(param);
+    }
+}
+
+pub fn walk_let_expr<V: Visitor + ?Sized>(v: &mut V, e: &LetExpr) {
+    if let LetKind::Named(name) = &e.kind {
+        v.visit_binder(name);
+    }
+    for (name, val) in &e.bindings {
+        v.visit_binder(name);
+        v.visit_node(val);
+    }
+    v.visit_node(&e.body);
+}
+
+pub fn walk_lambda_expr<V: Visitor + ?Sized>(v: &mut V, e: &LambdaExpr) {
+    for param in &e.params {
+        v.visit_binder(param);
+    }
+    v.visit_node(&e.body);
+}
+
+pub fn walk_cond_expr<V: Visitor + ?Sized>(v: &mut V, e: &CondExpr) {
+    for (test, body) in &e.clauses {
+        v.visit_node(test);
+        v.visit_node(body);
+    }
+    if let Some(body) = &e.else_body {
+        v.visit_node(body);
+    }
+}
+
+pub fn walk_define_expr<V: Visitor + ?Sized>(v: &mut V, e: &DefineExpr) {
+    v.visit_binder(&e.name);
+    for param in e.params.iter().flatten() {
+        v.visit_binder(param);
+    }
+    v.visit_node(&e.body);
+}
+
+pub fn walk_template<V: Visitor + ?Sized>(v: &mut V, t: &Template) {
+    match t {
+        Template::Datum(d) => v.visit_datum(d),
+        Template::Unquote(e) | Template::UnquoteSplicing(e) => v.visit_node(e),
+        Template::List(items) => {
+            for item in items {
+                v.visit_template(item);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{lexer::Lexer, parser::Parser};
+
+    use super::*;
+
+    /// Counts references to each identifier
+    #[derive(Default)]
+    struct Uses(HashMap<String, usize>);
+
+    impl Visitor for Uses {
+        fn visit_ident(&mut self, name: &str) {
+            *self.0.entry(name.into()).or_default() += 1;
+        }
+    }
+
+    #[test]
+    fn visits_every_identifier() {
+        let node = Parser::parse(Lexer::lex(
+            "(let ([x 1]) (cond [(= x y) `(,x ,@y)] [else (lambda (z) (+ x z))]))".into(),
+        ));
+        let mut uses = Uses::default();
+        uses.visit_node(&node);
+        let expected = [("x", 3), ("y", 2), ("z", 1), ("=", 1), ("+", 1)];
+        assert_eq!(
+            uses.0,
+            expected.map(|(name, n)| (name.to_string(), n)).into()
+        );
+    }
+}
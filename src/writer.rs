@@ -18,35 +18,16 @@ section .text
         )
         .unwrap();
 
-        for line in self.fns.iter() {
+        file.write_all(b"main:\n").unwrap();
+        for line in self.lines.iter().chain(&self.fns) {
             file.write_all(line.as_bytes()).unwrap();
             file.write_all(b"\n").unwrap();
         }
-
-        file.write_all(
-            br#"main:
-call currip
-mov rbx, rax
-"#,
-        )
-        .unwrap();
-        for line in &self.lines {
-            file.write_all(line.as_bytes()).unwrap();
-            file.write_all(b"\n").unwrap();
-        }
-        file.write_all(
-            br#"ret
-currip:
-    mov eax, [rsp]
-    ret
-section .data
-"#,
-        )
-        .unwrap();
+        file.write_all(b"section .data\n").unwrap();
         for (name, val) in &self.consts {
-            if !val.is_nan() {
-                file.write_all(format!("{name}: dd {val:?}\n").as_bytes())
-                    .unwrap();
+            if let Some(decl) = val.to_asm(name) {
+                file.write_all(decl.as_bytes()).unwrap();
+                file.write_all(b"\n").unwrap();
             }
         }
 
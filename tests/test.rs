@@ -16,9 +16,12 @@ fn arithmetic() {
             ("(_getint (- 2 1))", 1),
             ("(_getint (- 1 2))", -1),
             ("(_getint (* 2 21))", 42),
-            ("(_getint (/ 5 2))", 2),
-            // weird error: expected -1, got -1
-            // ("(_getint (+ 1 (* 2 (- 3 4))))", -1),
+            ("(_getint (/ 6 2))", 3),
+            // Dividing integers that don't divide evenly makes a ratio
+            ("(= (/ 5 2) (/ 10 4))", 1),
+            ("(_getint (numerator (/ 5 2)))", 5),
+            ("(_getint (denominator (/ 5 2)))", 2),
+            ("(_getint (+ 1 (* 2 (- 3 4))))", -1),
             ("(_getint (mod 5 2))", 1),
         ],
     );
@@ -34,8 +37,7 @@ fn lists() {
             ("(_getint (first (list 1 2 3 4 5)))", 1),
             ("(_getint (first (rest (list 1 2))))", 2),
             ("(_getint (first (cons 1 (empty))))", 1),
-            // runs out of memory methinks
-            // ("(first (rest (append (list 1) (list 2 3))))", 2),
+            ("(_getint (first (rest (append (list 1) (list 2 3)))))", 2),
         ],
     );
 }
@@ -52,6 +54,39 @@ fn local_variables() {
     );
 }
 
+#[test]
+fn many_variables() {
+    let names: Vec<_> = (0..20).map(|i| format!("x{i}")).collect();
+    let bindings: Vec<_> = names
+        .iter()
+        .enumerate()
+        .map(|(i, x)| format!("[{x} (+ n {i})]"))
+        .collect();
+    let sum = names
+        .iter()
+        .rev()
+        .fold("0".to_string(), |acc, x| format!("(+ {x} {acc})"));
+    let nested = (1..=30)
+        .rev()
+        .fold("n".to_string(), |acc, i| format!("(+ {i} {acc})"));
+    let run =
+        |body: String| at_runtime(&format!("(_getint (let* ({}) {body}))", bindings.join(" ")));
+    run_tests(
+        "many vars",
+        &[
+            (run(sum.clone()), 210),
+            (run(nested), 466),
+            (
+                format!(
+                    "(define (double x) (* x 2)) {}",
+                    run(format!("(+ {sum} (double x19))"))
+                ),
+                250,
+            ),
+        ],
+    );
+}
+
 #[test]
 fn floats() {
     run_tests(
@@ -65,6 +100,449 @@ fn floats() {
     );
 }
 
+#[test]
+fn doubles() {
+    run_tests(
+        "doubles",
+        &[
+            (
+                at_runtime(&format!("(= (+ 0.1 (* n 0.2)) {:?})", 0.1 + 0.2)),
+                1,
+            ),
+            (at_runtime("(= (+ 0.1 (* n 0.2)) 0.3)"), 0),
+            (at_runtime(&format!("(= (/ n 3.0) {:?})", 1.0 / 3.0)), 1),
+            (at_runtime(&format!("(= (- n 0.7) {:?})", 1.0 - 0.7)), 1),
+            (at_runtime("(= (+ n 16777216.0) 16777217.0)"), 1),
+            (at_runtime("(= (* n 1.5) (* 1.5 n))"), 1),
+            (at_runtime("(= (+ n 0.5) 2.5)"), 0),
+            // 0.0 / 0.0 is a NaN, which isn't even equal to itself
+            (at_runtime("(let ([x (/ (- n 1.0) 0.0)]) (= x x))"), 0),
+            (at_runtime("(let ([x (+ n 0.5)]) (= x x))"), 1),
+        ],
+    );
+}
+
+#[test]
+fn fixnums() {
+    run_tests(
+        "fixnums",
+        &[
+            (at_runtime("(_getint (+ n 2))"), 3),
+            (at_runtime("(_getint (- n 5))"), -4),
+            (at_runtime("(_getint (* (- n 5) 3))"), -12),
+            (at_runtime("(_getint (/ (+ n 7) 2))"), 4),
+            (at_runtime("(_getint (mod (+ n 6) 4))"), 3),
+            (at_runtime("(_getint (* n 1000000000000))"), 1000000000000),
+            (at_runtime("(= (+ n 0.5) 1.5)"), 1),
+            (at_runtime("(= (* 0.5 n) 0.5)"), 1),
+            (at_runtime("(= n 1.0)"), 1),
+            (at_runtime("(= 1.5 n)"), 0),
+            (at_runtime("(= n 1)"), 1),
+            (at_runtime("(= n 2)"), 0),
+            (at_runtime("(= (/ n 2) 0.5)"), 1),
+            (
+                at_runtime("(= (* n 4611686018427387904) 4611686018427387904.0)"),
+                1,
+            ),
+            (
+                at_runtime("(= (* n 4611686018427387904) 4611686018427387904)"),
+                1,
+            ),
+            (at_runtime("(empty? (rest (list n)))"), 1),
+            (at_runtime("(_getint (if (rest (list n)) 1 2))"), 1),
+        ],
+    );
+}
+
+#[test]
+fn division() {
+    run_tests(
+        "division",
+        &[
+            (at_runtime("(_getint (/ (- n 9) 2))"), -4),
+            (at_runtime("(_getint (quotient (- n 8) 2))"), -3),
+            (at_runtime("(_getint (quotient 7 (- n 3)))"), -3),
+            (at_runtime("(_getint (remainder (- n 8) 2))"), -1),
+            (at_runtime("(_getint (remainder (+ n 6) -2))"), 1),
+            (at_runtime("(_getint (modulo (- n 8) 2))"), 1),
+            (at_runtime("(_getint (modulo (+ n 6) -2))"), -1),
+            (at_runtime("(_getint (modulo (- n 8) -2))"), -1),
+            (at_runtime("(_getint (modulo (- n 7) 3))"), 0),
+            (at_runtime("(= (quotient (- n 8.0) 2) -3.0)"), 1),
+            (at_runtime("(= (remainder (- n 8.0) 2) -1.0)"), 1),
+            (at_runtime("(= (modulo (- n 8.0) 2) 1.0)"), 1),
+            (at_runtime("(= (modulo (+ n 6) -2.0) -1.0)"), 1),
+            (at_runtime("(= (modulo (- n 7.0) 3) 0.0)"), 1),
+            (at_runtime("(= (/ n 0.0) (/ 2 0.0))"), 1),
+        ],
+    );
+}
+
+#[test]
+fn bignums() {
+    let fact = "(define (fact n) (if (= n 0) 1 (* n (fact (- n 1)))))";
+    let run = |body: &str| format!("{fact} {body}");
+    run_tests(
+        "bignums",
+        &[
+            (run("(_getint (quotient (fact 40) (fact 38)))"), 1560),
+            (run("(_getint (remainder (fact 40) 1000000007))"), 799434881),
+            (run("(_getint (modulo (- 0 (fact 40)) 1000000007))"), 200565126),
+            (run("(_getint (- (+ (fact 25) 7) (fact 25)))"), 7),
+            (run("(= (fact 100) (* (fact 99) 100))"), 1),
+            (run("(= (fact 100) (* (fact 99) 99))"), 0),
+            (run("(= (- (fact 30) (fact 30)) 0)"), 1),
+            (run("(= (quotient (fact 30) (- 0 (fact 29))) -30)"), 1),
+            (
+                run("(let ([d (+ (fact 22) 12345)]) \
+                     (= (modulo (fact 40) (- 0 d)) (- (remainder (fact 40) d) d)))"),
+                1,
+            ),
+            (run("(= (+ 2305843009213693951 1) 2305843009213693952)"), 1),
+            (run("(= (- (+ 2305843009213693951 1) 1) 2305843009213693951)"), 1),
+            (run("(_getint (- (* 3037000499 3037000499) 9223372030926249000))"), 1),
+            (run("(= (quotient -2305843009213693952 -1) 2305843009213693952)"), 1),
+            (run("(= (+ (fact 25) 0.5) (* (fact 25) 1.0))"), 1),
+            (
+                run("(let ([l (list (fact 30) (fact 40))]) (and (_checkheap) (= (first l) (fact 30))))"),
+                1,
+            ),
+        ],
+    );
+}
+
+#[test]
+fn rationals() {
+    run_tests(
+        "rationals",
+        &[
+            (at_runtime("(= (/ n 3) (/ 2 6))"), 1),
+            (at_runtime("(= (/ n 3) (/ 1 4))"), 0),
+            (at_runtime("(_getint (numerator (/ (+ n 5) -4)))"), -3),
+            (at_runtime("(_getint (denominator (/ (+ n 5) -4)))"), 2),
+            (at_runtime("(_getint (+ (/ n 3) (/ 2 3)))"), 1),
+            (at_runtime("(= (- (/ n 2) (/ n 3)) (/ 1 6))"), 1),
+            (at_runtime("(= (* (/ n 2) (/ 2 3)) (/ 1 3))"), 1),
+            (at_runtime("(= (/ (/ n 2) (/ -3 4)) (/ -2 3))"), 1),
+            (at_runtime("(_getint (* (/ n 3) 3))"), 1),
+            (at_runtime("(= (+ (/ n 4) 0.5) 0.75)"), 1),
+            (at_runtime("(= (exact->inexact (/ n 4)) 0.25)"), 1),
+            (at_runtime("(= (inexact->exact (* n 0.375)) (/ 3 8))"), 1),
+            (at_runtime("(_getint (inexact->exact (* n 1024.0)))"), 1024),
+            (at_runtime("(= (denominator (* n 0.75)) 4.0)"), 1),
+            (
+                at_runtime("(= (/ (* 3000000000 3000000000) (* n 4000000000000000000)) (/ 9 4))"),
+                1,
+            ),
+            (at_runtime("(_checkheap)"), 1),
+        ],
+    );
+}
+
+#[test]
+fn math() {
+    // The results are checked against Racket's
+    run_tests(
+        "math",
+        &[
+            (
+                at_runtime(
+                    "(_getint (+ (+ (sin (- n 1)) (cos (- n 1))) (+ (exp (- n 1)) (log n))))",
+                ),
+                2,
+            ),
+            (at_runtime("(_getint (sqrt (* n 16)))"), 4),
+            (at_runtime("(= (sqrt (+ n 1)) 1.4142135623730951)"), 1),
+            (at_runtime("(= (sqrt (/ 9 (* n 4))) (/ 3 2))"), 1),
+            (at_runtime("(= (sqrt (expt (* n 10) 40)) (expt 10 20))"), 1),
+            (
+                at_runtime("(= (expt 2 (* n 100)) (* 1125899906842624 1125899906842624))"),
+                1,
+            ),
+            (at_runtime("(= (expt (/ 2 (* n 3)) -5) (/ 243 32))"), 1),
+            (at_runtime("(_getint (expt (* n -3) 3))"), -27),
+            (at_runtime("(_getint (expt (* n 0.5) 0))"), 1),
+            (at_runtime("(= (expt (* n -2.0) -3) -0.125)"), 1),
+            (at_runtime("(= (expt (* n 0.0) -1) (/ 1.0 0.0))"), 1),
+            (at_runtime("(_getint (floor (/ -5 (* n 2))))"), -3),
+            (at_runtime("(_getint (ceiling (/ -5 (* n 2))))"), -2),
+            (at_runtime("(_getint (truncate (/ -7 (* n 2))))"), -3),
+            (at_runtime("(_getint (round (/ 5 (* n 2))))"), 2),
+            (at_runtime("(_getint (round (/ -7 (* n 2))))"), -4),
+            (at_runtime("(_getint (round (/ 5 (* n 3))))"), 2),
+            (at_runtime("(= (round (* n 2.5)) 2.0)"), 1),
+            (at_runtime("(= (floor (* n -2.5)) -3.0)"), 1),
+            (at_runtime("(= (ceiling (* n 2.1)) 3.0)"), 1),
+            (at_runtime("(= (truncate (* n -2.7)) -2.0)"), 1),
+            (at_runtime("(_getint (exact-round (* n 2.6)))"), 3),
+            (at_runtime("(_getint (floor (* n 7)))"), 7),
+            (at_runtime("(_checkheap)"), 1),
+        ],
+    );
+}
+
+#[test]
+fn transcendental() {
+    // These call the C math library, which only programs compiled on their own are linked with.
+    // The results are checked against Racket's.
+    let programs = [
+        ("(exp n)", "2.718281828459045"),
+        ("(exp (* n 10))", "22026.465794806718"),
+        ("(exp (* n 1000))", "+inf.0"),
+        ("(log (* n 10))", "2.302585092994046"),
+        ("(log (* n 0.0))", "-inf.0"),
+        ("(sin n)", "0.8414709848078965"),
+        ("(cos n)", "0.5403023058681398"),
+        ("(tan n)", "1.5574077246549023"),
+        ("(atan n)", "0.7853981633974483"),
+        ("(atan n -1)", "2.356194490192345"),
+        ("(expt (* n 10) 0.3)", "1.9952623149688795"),
+        ("(sin (* n 100000000000000000000.0))", "-0.6452512852657808"),
+        ("(sin (* n 3.141592653589793))", "1.2246467991473532e-16"),
+        ("(cos (* n 3.141592653589793))", "-1.0"),
+        ("(atan (* n 0.0) 0.0)", "0.0"),
+    ];
+    for (i, (body, expected)) in programs.iter().enumerate() {
+        let rkt = at_runtime(&format!("(display {body})"));
+        let (_, stdout, stderr) = run_program(&format!("transcendental{i}"), &rkt);
+        assert_eq!(stdout, *expected, "{body}");
+        assert_eq!(stderr, "", "{body}");
+    }
+}
+
+#[test]
+fn garbage_collection() {
+    // Each of these allocates more than the first space of the heap holds, while keeping some
+    // values alive in registers and stack frames
+    run_tests(
+        "gc",
+        &[
+            (
+                "(define (build n acc) (if (= n 0) acc (build (- n 1) (cons n acc)))) \
+                 (define (sum l) (if (empty? l) 0 (+ (first l) (sum (rest l))))) \
+                 (_getint (sum (build 60000 '())))",
+                1800030000,
+            ),
+            (
+                "(define (halves n acc) (if (= n 0) acc (halves (- n 1) (+ acc 0.5)))) \
+                 (= (halves 70000 0) 35000.0)",
+                1,
+            ),
+            (
+                "(define (make-counter) (let ([n 0]) (lambda () (set! n (+ n 1)) n))) \
+                 (define (spin c k) (if (= k 0) (c) (let ([junk (list k k k)]) (c) (spin c (- k 1))))) \
+                 (_getint (spin (make-counter) 30000))",
+                30001,
+            ),
+            (
+                "(define (churn n keep) (if (= n 0) keep (churn (- n 1) (list (first keep) 'x 2.5)))) \
+                 (define (same? l) (if (eq? (first (rest l)) 'x) (= (first (rest (rest l))) 2.5) #f)) \
+                 (same? (churn 50000 (list 7 'x 2.5)))",
+                1,
+            ),
+        ],
+    );
+}
+
+#[test]
+fn heap_layout() {
+    // `_checkheap` walks the heap and checks that every box and the values in it are well formed
+    run_tests(
+        "heap",
+        &[
+            ("(_checkheap)", 1),
+            (
+                "(let ([l (list 1 2.5 'a (empty))]) (if (_checkheap) (_getint (first l)) 0))",
+                1,
+            ),
+            (
+                "(let* ([l (list 1 2.5 'a)] [c (cons l (append l l))]) \
+                   (if (_checkheap) (_getint (first (first c))) 0))",
+                1,
+            ),
+            (
+                "(define (make-counter) (let ([n 0]) (lambda () (set! n (+ n 1)) n))) \
+                 (let ([c (make-counter)]) (c) (c) (if (_checkheap) (_getint (c)) 0))",
+                3,
+            ),
+            (
+                "(define (build n acc) (if (= n 0) acc (build (- n 1) (cons (* n 0.5) acc)))) \
+                 (let ([l (build 60000 '())]) (if (_checkheap) (= (first l) 0.5) #f))",
+                1,
+            ),
+        ],
+    );
+}
+
+#[test]
+fn contract_violations() {
+    let errors = [
+        (
+            "(first 5)",
+            "first: contract violation\n  expected: pair?\n  given: 5\n",
+        ),
+        (
+            "(rest '())",
+            "rest: contract violation\n  expected: pair?\n  given: '()\n",
+        ),
+        (
+            "(+ 1 'a)",
+            "+: contract violation\n  expected: number?\n  given: 'a\n",
+        ),
+        (
+            "(* (list 1 2.5 'b #t) 2)",
+            "*: contract violation\n  expected: number?\n  given: '(1 2.5 b #t)\n",
+        ),
+        (
+            "(- (cons 1 (cons 2 3)) 1)",
+            "-: contract violation\n  expected: number?\n  given: '(1 2 . 3)\n",
+        ),
+        (
+            "(append 5 '())",
+            "append: contract violation\n  expected: list?\n  given: 5\n",
+        ),
+        (
+            "(first (+ 0.1 0.2))",
+            "first: contract violation\n  expected: pair?\n  given: 0.30000000000000004\n",
+        ),
+        (
+            "(rest (* 2 50.0))",
+            "rest: contract violation\n  expected: pair?\n  given: 100.0\n",
+        ),
+        (
+            "(first (/ 1.0 100000))",
+            "first: contract violation\n  expected: pair?\n  given: 1e-05\n",
+        ),
+        (
+            "(first (lambda (x) x))",
+            "first: contract violation\n  expected: pair?\n  given: #<procedure>\n",
+        ),
+        (
+            "(first (* 3037000499 3037000499))",
+            "first: contract violation\n  expected: pair?\n  given: 9223372030926249001\n",
+        ),
+        (
+            "(rest (* -99999999999 (* 99999999999 99999999999)))",
+            "rest: contract violation\n  expected: pair?\n  given: -999999999970000000000299999999999\n",
+        ),
+        (
+            "(first (/ 6 -4))",
+            "first: contract violation\n  expected: pair?\n  given: -3/2\n",
+        ),
+        (
+            "(rest (inexact->exact 0.1))",
+            "rest: contract violation\n  expected: pair?\n  given: 3602879701896397/36028797018963968\n",
+        ),
+        (
+            "(inexact->exact (/ -1.0 0.0))",
+            "inexact->exact: no exact representation for -inf.0\n",
+        ),
+        (
+            "(numerator 'a)",
+            "numerator: contract violation\n  expected: rational?\n  given: 'a\n",
+        ),
+        (
+            "(quotient (/ 1 2) 1)",
+            "quotient: contract violation\n  expected: integer?\n  given: 1/2\n",
+        ),
+        ("(/ 1 0)", "/: division by zero\n"),
+        ("(/ 1.5 0)", "/: division by zero\n"),
+        ("(quotient 1 0)", "quotient: undefined for 0\n"),
+        ("(remainder 1.0 0.0)", "remainder: undefined for 0.0\n"),
+        ("(modulo 5 0)", "modulo: undefined for 0\n"),
+        (
+            "(quotient 1.5 1)",
+            "quotient: contract violation\n  expected: integer?\n  given: 1.5\n",
+        ),
+        (
+            "(modulo 4 'a)",
+            "modulo: contract violation\n  expected: integer?\n  given: 'a\n",
+        ),
+        (
+            "(sqrt 'a)",
+            "sqrt: contract violation\n  expected: number?\n  given: 'a\n",
+        ),
+        (
+            "(floor '(1))",
+            "floor: contract violation\n  expected: real?\n  given: '(1)\n",
+        ),
+        (
+            "(exact-round (/ 1.0 0.0))",
+            "exact-round: contract violation\n  expected: rational?\n  given: +inf.0\n",
+        ),
+        ("(log 0)", "log: undefined for 0\n"),
+        ("(sqrt -4)", "sqrt: complex results aren't supported for -4\n"),
+        ("(expt -8 0.5)", "expt: complex results aren't supported for -8\n"),
+        ("(atan 0 0)", "atan: undefined for 0 and 0\n"),
+        (
+            "(printf 5)",
+            "printf: contract violation\n  expected: string?\n  given: 5\n",
+        ),
+        (
+            r#"(printf "~a ~s" 1)"#,
+            "printf: format string requires 2 arguments, given 1\n",
+        ),
+        (
+            r#"(format "~x" 1)"#,
+            "format: ill-formed pattern string \"~x\"\n",
+        ),
+        (
+            "(let ([f 5]) (f 1))",
+            "application: not a procedure;\n expected a procedure that can be applied to \
+             arguments\n  given: 5\n",
+        ),
+    ];
+    for (i, (rkt, message)) in errors.iter().enumerate() {
+        let (status, _, stderr) = run_program(&format!("contract{i}"), rkt);
+        assert_eq!(stderr, *message, "{rkt}");
+        assert_eq!(status, 1, "{rkt}");
+    }
+}
+
+#[test]
+fn printing() {
+    let programs = [
+        (r#"(display "hi") (newline)"#, "hi\n"),
+        (r#"(write "a \"q\"\n\\")"#, r#""a \"q\"\n\\""#),
+        (r#"(display '(1 "two" three 4.5))"#, "(1 two three 4.5)"),
+        (r#"(write '(1 "two" three 4.5))"#, r#"(1 "two" three 4.5)"#),
+        (r#"(print '(1 "two" three))"#, r#"'(1 "two" three)"#),
+        ("(print 'x)", "'x"),
+        (
+            "(display (list #t #f (/ 1 3) (expt 2 70)))",
+            "(#t #f 1/3 1180591620717411303424)",
+        ),
+        ("(display (cons 1 2))", "(1 . 2)"),
+        ("(display (newline))", "\n#<void>"),
+        (
+            "(display (list (when #f 1) (unless #t 1) (cond [#f 1])))",
+            "(#<void> #<void> #<void>)",
+        ),
+        ("(let ([x 0]) (display (set! x 1)) (display x))", "#<void>1"),
+        (
+            r#"(printf "~a + ~s = ~v~n" "one" "two" '(3))"#,
+            "one + \"two\" = '(3)\n",
+        ),
+        (r#"(printf "~~100%~%")"#, "~100%\n"),
+        (r#"(display (format "~A/~S" 1 "b"))"#, "1/\"b\""),
+        (r#"(display (= (format "x~a" 1) "x1"))"#, "#t"),
+        (
+            // Strings made at runtime have to survive garbage collection
+            r#"(define (strings n acc)
+                 (if (= n 0) acc (strings (- n 1) (cons (format "~a: ~a" n "some text") acc))))
+               (define l (strings 100000 '()))
+               (display (first (rest l)))
+               (display (_checkheap))"#,
+            "2: some text#t",
+        ),
+    ];
+    for (i, (rkt, expected)) in programs.iter().enumerate() {
+        let (_, stdout, stderr) = run_program(&format!("printing{i}"), rkt);
+        assert_eq!(stdout, *expected, "{rkt}");
+        assert_eq!(stderr, "", "{rkt}");
+    }
+}
+
 #[test]
 fn conditionals() {
     run_tests(
@@ -78,6 +556,139 @@ fn conditionals() {
     );
 }
 
+#[test]
+fn quote() {
+    run_tests(
+        "quote",
+        &[
+            ("(_getint (first (rest '(1 2 3))))", 2),
+            ("(_getint (first (first (rest '((1) (2 3))))))", 2),
+            ("(empty? '())", 1),
+            ("(eq? 'a (first '(a b)))", 1),
+            ("(eq? 'a (first (rest '(a b))))", 0),
+            ("(_getint (first (quote (4))))", 4),
+        ],
+    );
+}
+
+#[test]
+fn quasiquote() {
+    run_tests(
+        "quasiquote",
+        &[
+            ("(_getint (first (rest `(1 ,(+ 1 1) 3))))", 2),
+            ("(_getint (let* [(x 5)] (first `(,x))))", 5),
+            ("(_getint (first (rest (rest `(1 ,@(list 2 3) 4)))))", 3),
+            ("(_getint (first (rest (rest `(1 ,@(list 2 3))))))", 3),
+            ("(empty? `(,@(empty)))", 1),
+            (
+                "(eq? 'quasiquote (first (first (rest `(1 `,(+ 1 ,2))))))",
+                1,
+            ),
+            ("(_getint (first (rest (append (list 1) (list 2 3)))))", 2),
+        ],
+    );
+}
+
+#[test]
+fn derived_forms() {
+    run_tests(
+        "derived forms",
+        &[
+            ("(_getint (let ([x 1] [y 2]) (+ x y)))", 3),
+            ("(_getint (let ([x 1]) (let ([x 2] [y x]) y)))", 1),
+            ("(_getint (let* ([x 1]) (+ (let* ([x 2]) x) x)))", 3),
+            ("(_getint (letrec ([x 1] [y x]) y))", 1),
+            ("(_getint (cond [(= 1 2) 1] [(= 1 1) 2] [else 3]))", 2),
+            ("(_getint (cond [(= 1 2) 1] [else 3]))", 3),
+            ("(and (= 1 1) (= 2 2))", 1),
+            ("(and (= 1 1) (= 2 3))", 0),
+            ("(or #f (= 1 1))", 1),
+            ("(_getint (or #f 5))", 5),
+            ("(_getint (when (= 1 1) 4 5))", 5),
+            // The exit status is the low byte of #<void>
+            ("(unless (= 1 1) 5)", 9),
+            ("(_getint (let ([x 1]) (set! x 5) x))", 5),
+            ("(_getint (begin 1 2))", 2),
+            ("(define x 3) (define y (+ x 1)) (_getint (+ x y))", 7),
+        ],
+    );
+}
+
+#[test]
+fn lambdas() {
+    run_tests(
+        "lambdas",
+        &[
+            ("(let ([f (lambda (x) (+ x 1))]) (_getint (f 5)))", 6),
+            (
+                "(let ([y 10]) (let ([f (lambda (x) (+ x y))]) (_getint (f 5))))",
+                15,
+            ),
+            (
+                "(define (fact n) (if (= n 0) 1 (* n (fact (- n 1))))) (_getint (fact 5))",
+                120,
+            ),
+            (
+                "(let ([n 0]) (let ([inc (lambda () (set! n (+ n 1)))]) (inc) (inc) (_getint n)))",
+                2,
+            ),
+            (
+                "(_getint (let loop ([i 0] [acc 0]) (if (= i 5) acc (loop (+ i 1) (+ acc i)))))",
+                10,
+            ),
+            (
+                "(define (adder n) (lambda (x) (+ x n))) (let ([add3 (adder 3)]) (_getint (add3 4)))",
+                7,
+            ),
+            ("(_getint ((lambda (x) (+ x 1)) 5))", 6),
+            (
+                "(let ([f (lambda (g n) (if (= n 0) 7 (g g (- n 1))))]) (_getint (f f 3)))",
+                7,
+            ),
+            ("(define (adder n) (lambda (x) (+ x n))) (_getint ((adder 3) 4))", 7),
+        ],
+    );
+}
+
+#[test]
+fn macros() {
+    let my_or = "(define-syntax my-or (syntax-rules () \
+                   [(_) #f] \
+                   [(_ e) e] \
+                   [(_ e r ...) (let* ([t e]) (if t t (my-or r ...)))]))";
+    let my_if = "(define-syntax my-if (syntax-rules (then else) \
+                   [(_ c then t else e) (if c t e)]))";
+    let sum_pairs = "(define-syntax sum-pairs (syntax-rules () \
+                       [(_ (a b) ...) (list (+ a b) ...)]))";
+    let while_ = "(define-syntax while (syntax-rules () \
+                    [(_ c body ...) (let lp () (when c body ... (lp)))]))";
+    run_tests(
+        "macros",
+        &[
+            (format!("{my_or} (my-or #f #f #t)"), 1),
+            (format!("{my_or} (my-or)"), 0),
+            (format!("{my_or} (let* ([t #t]) (my-or #f t))"), 1),
+            (format!("{my_if} (_getint (my-if #f then 1 else 2))"), 2),
+            (
+                format!("{sum_pairs} (_getint (first (rest (sum-pairs (1 2) (3 4)))))"),
+                7,
+            ),
+            (
+                format!("{while_} (let ([i 0]) (while (if (= i 3) #f #t) (set! i (+ i 1))) (_getint i))"),
+                3,
+            ),
+        ],
+    );
+}
+
+/// Wraps `body` in a function that is called with `n` bound to 1
+///
+/// The optimizer can't know what `n` is, so whatever depends on it is computed at runtime.
+fn at_runtime(body: &str) -> String {
+    format!("(define (f n) (if (= n 0) (f 1) {body})) (f 0)")
+}
+
 /// Do not touch this function it is awful
 fn run_tests(name: &str, tests: &[(impl ToString, i64)]) {
     fs::create_dir_all("target/tests").unwrap();
@@ -129,10 +740,9 @@ fn run_tests(name: &str, tests: &[(impl ToString, i64)]) {
 
     asmfile.write_all(b"section .data\n").unwrap();
     for (name, val) in all_consts {
-        if !val.is_nan() {
-            asmfile
-                .write_all(format!("{name}: dd {val:?}\n").as_bytes())
-                .unwrap();
+        if let Some(decl) = val.to_asm(&name) {
+            asmfile.write_all(decl.as_bytes()).unwrap();
+            asmfile.write_all(b"\n").unwrap();
         }
     }
 
@@ -171,3 +781,37 @@ fn run_tests(name: &str, tests: &[(impl ToString, i64)]) {
     io::stderr().write_all(&output.stderr).unwrap();
     assert_eq!(output.status.code().unwrap(), 0);
 }
+
+/// Compiles a program on its own and runs it, returning its exit status and what it printed to
+/// stdout and stderr
+fn run_program(name: &str, rkt: &str) -> (i32, String, String) {
+    fs::create_dir_all("target/tests").unwrap();
+    let path = format!("target/tests/{name}");
+    let mut file = File::create(format!("{path}.asm")).unwrap();
+    Compiler::default().compile_to_file(Parser::parse(Lexer::lex(rkt.into())), &mut file);
+    Command::new("nasm")
+        .args([
+            "-f",
+            "elf64",
+            &format!("{path}.asm"),
+            "-o",
+            &format!("{path}.o"),
+        ])
+        .output()
+        .unwrap();
+    Command::new("gcc")
+        .args([
+            "-no-pie",
+            &format!("{path}.o"),
+            "-Wl,--no-as-needed",
+            "-lm",
+            "-o",
+            &format!("{path}.out"),
+        ])
+        .output()
+        .unwrap();
+    let output = Command::new(format!("./{path}.out")).output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    (output.status.code().unwrap(), stdout, stderr)
+}